@@ -0,0 +1,24 @@
+use std::{env, process::Command};
+
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8(output.stdout).ok().map(|s| s.trim().to_string())
+}
+
+fn main() {
+    let commit = env::var("MONEROD_EXPORTER_COMMIT").ok()
+        .or_else(|| command_output("git", &["rev-parse", "--short", "HEAD"]))
+        .unwrap_or_else(|| "unknown".into());
+
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".into());
+    let rustc_version = command_output(&rustc, &["--version"])
+        .unwrap_or_else(|| "unknown".into());
+
+    println!("cargo:rustc-env=MONEROD_EXPORTER_COMMIT={}", commit);
+    println!("cargo:rustc-env=MONEROD_EXPORTER_RUSTC={}", rustc_version);
+    println!("cargo:rerun-if-env-changed=MONEROD_EXPORTER_COMMIT");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+}
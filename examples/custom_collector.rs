@@ -0,0 +1,60 @@
+// exports the metrics of the local daemon once, along with the number of its peer connections
+use monerod_exporter::{
+    client::{Client, RetryPolicy},
+    config::{Config, MonerodConfig},
+    metrics::{CollectFuture, Collector, CollectorRegistry, Exporter, MetricNaming},
+    prometheus::{Format, Metric, render_metrics},
+};
+use serde_json::{Value, json};
+
+struct ConnectionsCollector;
+
+impl Collector for ConnectionsCollector {
+    fn name(&self) -> &str {
+        "connections"
+    }
+
+    fn collect<'a>(&'a self, client: &'a Client) -> CollectFuture<'a> {
+        Box::pin(async move {
+            let response: Value = client.call_json_rpc("get_connections", json!({})).await?;
+            let connections = response["connections"].as_array().map(Vec::len).unwrap_or(0);
+            Ok(vec![Metric::new_gauge("monero_node_connections", connections as f64)])
+        })
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let config = Config::default();
+    let monerod_config = MonerodConfig::default();
+
+    let mut registry = CollectorRegistry::default();
+    registry.register(ConnectionsCollector);
+
+    let client = Client::new(
+        reqwest::Client::new(),
+        monerod_config.base_url,
+        None,
+        monerod_config.max_concurrent_calls,
+        monerod_config.latency_buckets,
+        RetryPolicy::default(),
+    );
+    let exporter = Exporter::new(
+        client,
+        monerod_config.name,
+        false,
+        MetricNaming::new(&config),
+        config.block_spans.clone(),
+        config.empty_spans,
+        Vec::new(),
+    ).with_custom_collectors(&registry);
+
+    match exporter.export().await {
+        Ok(metrics) => {
+            let mut rendered = String::new();
+            render_metrics(metrics.iter(), &mut rendered, Format::Text).unwrap();
+            print!("{}", rendered);
+        },
+        Err(e) => eprintln!("failed to export metrics: {}", e),
+    }
+}
@@ -0,0 +1,168 @@
+use serde::Deserialize;
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+};
+use warp::{Filter, Rejection, Reply, http::StatusCode, reject::Reject};
+
+use crate::server::remote_addr;
+
+#[derive(Debug)]
+pub struct Forbidden;
+
+impl Reject for Forbidden {}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+#[derive(Clone, Debug)]
+pub struct CidrParseError;
+
+impl fmt::Display for CidrParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid CIDR")
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = CidrParseError;
+
+    // a bare address is a network of just that address
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            None => (s, None),
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+        };
+        let addr = addr.parse::<IpAddr>().map_err(|_| CidrParseError)?;
+        let max_prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            None => max_prefix_len,
+            Some(prefix_len) => prefix_len.parse::<u8>().ok()
+                .filter(|prefix_len| *prefix_len <= max_prefix_len)
+                .ok_or(CidrParseError)?,
+        };
+        Ok(Cidr { addr, prefix_len })
+    }
+}
+
+// IPv4 clients of a dual-stack socket show up as IPv4-mapped IPv6 addresses
+fn unmap(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, hi, lo] => {
+                IpAddr::V4(Ipv4Addr::new((hi >> 8) as u8, hi as u8, (lo >> 8) as u8, lo as u8))
+            },
+            _ => IpAddr::V6(v6),
+        },
+        addr => addr,
+    }
+}
+
+fn prefix_eq(a: u128, b: u128, prefix_len: u8, bits: u8) -> bool {
+    let shift = bits - prefix_len;
+    // a shift by the full width would overflow
+    shift == bits || (a >> shift) == (b >> shift)
+}
+
+impl Cidr {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, unmap(addr)) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                prefix_eq(u32::from(net) as u128, u32::from(addr) as u128, self.prefix_len, 32)
+            },
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                prefix_eq(u128::from(net), u128::from(addr), self.prefix_len, 128)
+            },
+            _ => false,
+        }
+    }
+}
+
+// passes every request when the list is empty, and none without a remote address otherwise
+pub fn allowed(cidrs: Arc<Vec<Cidr>>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    remote_addr()
+        .and_then(move |remote_addr: Option<SocketAddr>| {
+            let allowed = cidrs.is_empty() || remote_addr
+                .map(|addr| cidrs.iter().any(|cidr| cidr.contains(addr.ip())))
+                .unwrap_or(false);
+            async move {
+                if allowed {
+                    Ok(())
+                } else {
+                    Err(warp::reject::custom(Forbidden))
+                }
+            }
+        })
+        .untuple_one()
+}
+
+pub async fn handle_rejection(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    if rejection.find::<Forbidden>().is_some() {
+        return Ok(warp::reply::with_status("forbidden", StatusCode::FORBIDDEN));
+    }
+    Err(rejection)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn addr(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_networks() {
+        assert_eq!(cidr("10.0.0.0/8"), Cidr { addr: addr("10.0.0.0"), prefix_len: 8 });
+        assert_eq!(cidr("192.0.2.1"), Cidr { addr: addr("192.0.2.1"), prefix_len: 32 });
+        assert_eq!(cidr("2001:db8::1"), Cidr { addr: addr("2001:db8::1"), prefix_len: 128 });
+        assert_eq!(cidr("::/0"), Cidr { addr: addr("::"), prefix_len: 0 });
+        // prefixes longer than the address, and malformed ones
+        let invalid = ["10.0.0.0/33", "2001:db8::/129", "10.0.0.0/", "10.0.0.0/-1", "10.0.0/8", "a/8"];
+        for invalid in invalid.iter() {
+            assert!(invalid.parse::<Cidr>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn contains_addresses_of_the_prefix() {
+        assert!(cidr("0.0.0.0/0").contains(addr("203.0.113.7")));
+        assert!(!cidr("0.0.0.0/0").contains(addr("2001:db8::1")));
+        assert!(cidr("::/0").contains(addr("2001:db8::1")));
+        assert!(cidr("10.0.0.0/8").contains(addr("10.255.255.255")));
+        assert!(!cidr("10.0.0.0/8").contains(addr("11.0.0.0")));
+        assert!(cidr("192.0.2.1/32").contains(addr("192.0.2.1")));
+        assert!(!cidr("192.0.2.1/32").contains(addr("192.0.2.2")));
+        assert!(cidr("2001:db8::/32").contains(addr("2001:db8:ffff::1")));
+        assert!(!cidr("2001:db8::/32").contains(addr("2001:db9::1")));
+        assert!(cidr("2001:db8::1/128").contains(addr("2001:db8::1")));
+        assert!(!cidr("2001:db8::1/128").contains(addr("2001:db8::2")));
+    }
+
+    #[test]
+    fn unmaps_ipv4_clients_of_dual_stack_sockets() {
+        assert_eq!(unmap(addr("::ffff:192.0.2.1")), addr("192.0.2.1"));
+        assert_eq!(unmap(addr("::1")), addr("::1"));
+        assert!(cidr("192.0.2.0/24").contains(addr("::ffff:192.0.2.1")));
+        assert!(!cidr("192.0.2.0/24").contains(addr("::ffff:198.51.100.1")));
+    }
+
+    #[test]
+    fn compares_prefixes() {
+        assert!(prefix_eq(0, u128::MAX, 0, 128));
+        assert!(prefix_eq(0, u32::MAX as u128, 0, 32));
+        assert!(prefix_eq(0xff00, 0xff01, 120, 128));
+        assert!(!prefix_eq(0xff00, 0xff01, 128, 128));
+        assert!(prefix_eq(0x8000_0000, 0xffff_ffff, 1, 32));
+        assert!(!prefix_eq(0x8000_0000, 0x7fff_ffff, 1, 32));
+    }
+}
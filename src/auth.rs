@@ -0,0 +1,124 @@
+use std::sync::{Arc, Mutex};
+use warp::{
+    Filter, Rejection, Reply,
+    http::{StatusCode, header::WWW_AUTHENTICATE},
+    reject::Reject,
+};
+
+use crate::config::BasicAuthConfig;
+
+// carries the WWW-Authenticate challenge
+#[derive(Debug)]
+pub struct Unauthorized(&'static str);
+
+impl Reject for Unauthorized {}
+
+#[derive(Debug)]
+pub struct BasicAuth {
+    username: String,
+    password_hash: String,
+    // bcrypt is slow by design, so the last accepted credentials aren't verified again
+    last_accepted: Mutex<Option<String>>,
+}
+
+impl BasicAuth {
+    pub fn new(config: &BasicAuthConfig) -> BasicAuth {
+        BasicAuth {
+            username: config.username.clone(),
+            password_hash: config.password_hash.clone(),
+            last_accepted: Mutex::new(None),
+        }
+    }
+
+    async fn check(&self, credentials: &str) -> bool {
+        let accepted_before = match self.last_accepted.lock().unwrap().as_deref() {
+            Some(last_accepted) => constant_time_eq(last_accepted.as_bytes(), credentials.as_bytes()),
+            None => false,
+        };
+        if accepted_before {
+            return true;
+        }
+
+        let decoded = base64::decode(credentials).ok().and_then(|d| String::from_utf8(d).ok());
+        let accepted = match decoded.as_deref().and_then(|d| d.split_once(':')) {
+            Some((username, password)) if username == self.username => {
+                // so that verifying doesn't hold up the runtime
+                let password = password.to_string();
+                let hash = self.password_hash.clone();
+                let verify = tokio::task::spawn_blocking(move || bcrypt::verify(password, &hash));
+                matches!(verify.await, Ok(Ok(true)))
+            },
+            _ => false,
+        };
+
+        if accepted {
+            *self.last_accepted.lock().unwrap() = Some(credentials.to_string());
+        }
+        accepted
+    }
+}
+
+// compares in time depending only on the length, so that the token can't be guessed bytewise
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+// a request is let through if any of the configured schemes accepts it
+#[derive(Debug, Default)]
+pub struct Auth {
+    pub basic: Option<BasicAuth>,
+    pub bearer_token: Option<String>,
+}
+
+impl Auth {
+    pub fn is_enabled(&self) -> bool {
+        self.basic.is_some() || self.bearer_token.is_some()
+    }
+
+    async fn check(&self, authorization: Option<&str>) -> bool {
+        let (scheme, credentials) = match authorization.and_then(|value| value.split_once(' ')) {
+            None => return false,
+            Some((scheme, credentials)) => (scheme, credentials.trim()),
+        };
+
+        // auth schemes are case-insensitive
+        match (scheme.to_ascii_lowercase().as_str(), &self.basic, &self.bearer_token) {
+            ("basic", Some(basic), _) => basic.check(credentials).await,
+            ("bearer", _, Some(token)) => constant_time_eq(credentials.as_bytes(), token.as_bytes()),
+            _ => false,
+        }
+    }
+
+    fn challenge(&self) -> &'static str {
+        if self.basic.is_some() {
+            "Basic realm=\"monerod-exporter\""
+        } else {
+            "Bearer realm=\"monerod-exporter\""
+        }
+    }
+}
+
+// passes every request when auth is disabled
+pub fn authorization(auth: Arc<Auth>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |authorization: Option<String>| {
+            let auth = auth.clone();
+            async move {
+                if auth.is_enabled() && !auth.check(authorization.as_deref()).await {
+                    Err(warp::reject::custom(Unauthorized(auth.challenge())))
+                } else {
+                    Ok(())
+                }
+            }
+        })
+        .untuple_one()
+}
+
+// asks for credentials on auth failures and leaves other rejections to warp
+pub async fn handle_rejection(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    if let Some(Unauthorized(challenge)) = rejection.find::<Unauthorized>() {
+        let reply = warp::reply::with_status("unauthorized", StatusCode::UNAUTHORIZED);
+        return Ok(warp::reply::with_header(reply, WWW_AUTHENTICATE, *challenge));
+    }
+    Err(rejection)
+}
@@ -1,6 +1,31 @@
+use arc_swap::ArcSwap;
+use futures_util::future::try_join_all;
+use reqwest::{
+    StatusCode, Url,
+    header::{ACCEPT_ENCODING, AUTHORIZATION, CONTENT_ENCODING, CONTENT_TYPE, HeaderMap, HeaderValue},
+};
 use serde_json::json;
-use serde::{Deserialize, Serialize, de::DeserializeOwned};
-use std::fmt;
+use serde::{Deserialize, Deserializer, Serialize, de::{self, DeserializeOwned}};
+use std::{
+    collections::BTreeMap,
+    error,
+    fmt,
+    io,
+    sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}},
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::{sync::Semaphore, time::sleep};
+use tracing::{debug, warn};
+
+use crate::{
+    compression::{Encoding, decompress},
+    config::RpcLoginConfig,
+    digest::Authentication,
+    epee::{self, EpeeError},
+    hex,
+    prometheus::{Histogram, Metric},
+    transport::{Response, Transport},
+};
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct InfoResponse {
@@ -15,18 +40,35 @@ pub struct InfoResponse {
     pub grey_peerlist_size: u64,
     pub height: u64,
     pub incoming_connections_count: u64,
+    #[serde(default)]
+    pub nettype: String,
     pub offline: bool,
     pub outgoing_connections_count: u64,
     pub rpc_connections_count: u64,
     pub synchronized: bool,
     pub target: u64,
     pub target_height: u64,
+    #[serde(default)]
+    pub top_block_hash: String,
     pub tx_count: u64,
     pub tx_pool_size: u64,
     pub untrusted: bool,
+    #[serde(default)]
+    pub version: String,
     pub white_peerlist_size: u64,
 }
 
+#[derive(Clone, Debug, Serialize)]
+pub struct PruneBlockchainRequest {
+    pub check: bool,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PruneBlockchainResponse {
+    pub pruned: bool,
+    pub untrusted: bool,
+}
+
 #[derive(Clone, Debug, Serialize)]
 pub struct BlockHeadersRangeRequest {
     pub start_height: u64,
@@ -36,9 +78,14 @@ pub struct BlockHeadersRangeRequest {
 #[derive(Clone, Debug, Deserialize)]
 pub struct BlockHeader {
     pub block_size: u64,
+    pub difficulty: u64,
+    pub hash: String,
+    pub height: u64,
     pub num_txes: u64,
     pub orphan_status: bool,
     pub reward: u64,
+    // set by the miner, within the limits of consensus
+    pub timestamp: u64,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -67,18 +114,208 @@ pub struct TransactionPoolStatsResponse {
     pub untrusted: bool,
 }
 
+#[derive(Clone, Debug, Deserialize)]
+pub struct TransactionPoolHashesResponse {
+    // missing when the pool is empty
+    #[serde(default, deserialize_with = "deserialize_hashes")]
+    pub tx_hashes: Vec<String>,
+    pub untrusted: bool,
+}
+
+// the .bin endpoints send hashes concatenated in a single blob, which is only decoded as text
+// rather than hex in the unlikely case that all of its bytes are printable
+fn deserialize_hashes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    let blob = String::deserialize(deserializer)?;
+    let hex = match blob.len() % 64 == 0 && blob.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        true => blob,
+        false => hex::encode(blob.as_bytes()),
+    };
+    match hex.len() % 64 {
+        0 => Ok((0..hex.len()).step_by(64).map(|start| hex[start..start + 64].to_owned()).collect()),
+        _ => Err(de::Error::custom("hashes blob isn't a multiple of 32 bytes")),
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TransactionsRequest {
+    pub txs_hashes: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TransactionEntry {
+    pub tx_hash: String,
+    pub in_pool: bool,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TransactionsResponse {
+    // of the transactions which are in the pool or the chain
+    #[serde(default)]
+    pub txs: Vec<TransactionEntry>,
+    // of those which are in neither
+    #[serde(default)]
+    pub missed_tx: Vec<String>,
+    pub untrusted: bool,
+}
+
+// monerod doesn't return more block headers in one response
+const MAX_BLOCK_HEADERS_PER_CALL: u64 = 1000;
+
+// nor more transactions from a restricted RPC
+const MAX_TRANSACTIONS_PER_CALL: usize = 100;
+
+// far larger than the largest responses, those of 1000 block headers
+pub const DEFAULT_MAX_RESPONSE_SIZE: usize = 16 * 1024 * 1024;
+
+// of the body of an error response kept in the error, enough for the message of a proxy
+const BODY_SNIPPET_LEN: usize = 200;
+
+// how the requests and responses of an endpoint are encoded
+#[derive(Clone, Copy, Debug)]
+enum RpcFormat {
+    // the result of a response to the JSON-RPC request with the id
+    JsonRpc(u64),
+    Json,
+    // the portable storage of epee, which the .bin endpoints use
+    Binary,
+}
+
+#[derive(Serialize)]
+struct JsonRpcRequest<'a, B> {
+    jsonrpc: &'static str,
+    id: u64,
+    method: &'a str,
+    params: B,
+}
+
+// error responses to malformed requests may not have the id of the request
+#[derive(Deserialize)]
+struct JsonRpcEnvelope<T> {
+    #[serde(default)]
+    id: Option<serde_json::Value>,
+    error: Option<JsonRpcError>,
+    result: Option<T>,
+}
+
+#[derive(Deserialize)]
+struct JsonRpcError {
+    #[serde(default)]
+    code: i64,
+    #[serde(default)]
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct StatusEnvelope {
+    status: Option<String>,
+}
+
+// a result along with its status, which a typed result leaves over and a Value keeps as well
+#[derive(Deserialize)]
+struct StatusResult<R> {
+    // none if it doesn't fit, e.g. when a busy node leaves out everything but the status
+    #[serde(flatten)]
+    result: Option<R>,
+    #[serde(flatten)]
+    status: StatusEnvelope,
+}
+
+// the backoff doesn't grow any further after this many retries
+const MAX_BACKOFF_EXPONENT: i32 = 16;
+
+// how calls failing for a reason that's likely gone soon are retried
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    // doubled with every retry
+    pub delay: Duration,
+    // up to this fraction of the delay is randomly taken off, so that targets aren't retried in lockstep
+    pub jitter: f64,
+}
+
+impl RetryPolicy {
+    fn backoff(&self, retry: u32) -> Duration {
+        let exponent = (retry as i32 - 1).min(MAX_BACKOFF_EXPONENT);
+        let mut random = [0; 4];
+        let random = match getrandom::getrandom(&mut random) {
+            Ok(()) => u32::from_le_bytes(random) as f64 / u32::MAX as f64,
+            Err(_) => 0.0,
+        };
+        self.delay.mul_f64(2f64.powi(exponent) * (1.0 - self.jitter * random))
+    }
+}
+
+// outcome of the latest call of an RPC method
+#[derive(Clone, Debug)]
+pub struct CallState {
+    pub at: SystemTime,
+    pub error: Option<String>,
+}
+
+// rebuilds the transport now and then, so that the host of the daemon is resolved again
+pub struct TransportRefresh {
+    interval: Duration,
+    rebuild: Box<dyn Fn() -> Result<Transport, Box<dyn error::Error>> + Send + Sync>,
+    refreshed_at: Mutex<Instant>,
+}
+
+impl fmt::Debug for TransportRefresh {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TransportRefresh").field("interval", &self.interval).finish()
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Client {
-    http_client: reqwest::Client,
+    // swapped for a new one when refreshed, calls in flight keep the one they started with
+    transport: Arc<ArcSwap<Transport>>,
+    transport_refresh: Option<Arc<TransportRefresh>>,
     base_url: String,
+    rpc_login: Option<RpcLoginConfig>,
+    // the scheme the daemon asked for, along with its challenge, once it asked for a login
+    authentication: Arc<Mutex<Option<Authentication>>>,
+    // bounds the calls in flight when collectors fan out, none if unlimited
+    call_slots: Option<Arc<Semaphore>>,
+    latency_buckets: Vec<f64>,
+    retry_policy: RetryPolicy,
+    // in place of the timeout of the HTTP client
+    method_timeouts: BTreeMap<String, Duration>,
+    // so that a remote node can't exhaust the memory of the exporter, none if unlimited
+    max_response_size: Option<usize>,
+    // whether gzip and deflate are advertised, compressed responses are decompressed either way
+    accept_compression: bool,
+    // sent with every request, along with those of the RPC itself which take precedence
+    headers: HeaderMap,
+    // ids of JSON-RPC requests, matched against those of the responses
+    next_request_id: Arc<AtomicU64>,
+    latencies: Arc<Mutex<BTreeMap<String, Histogram>>>,
+    call_states: Arc<Mutex<BTreeMap<String, CallState>>>,
 }
 
 #[derive(Debug)]
 pub enum ClientError {
     HttpClient(reqwest::Error),
+    UnixSocket(hyper::Error),
+    Timeout,
+    InvalidRequest,
     ResponseDeserialization(serde_json::Error),
+    BinaryResponseDeserialization(EpeeError),
+    // the limit in bytes
+    ResponseTooLarge(usize),
+    UnsupportedContentEncoding,
+    Decompression(io::Error),
     NoResult,
-    UnexpectedStatus,
+    // the error object of a JSON-RPC response
+    Rpc { code: i64, message: String },
+    MismatchedId,
+    // the status of the RPC response itself, if it had one
+    UnexpectedStatus(Option<String>),
+    // the daemon is syncing or otherwise too busy to respond
+    Busy,
+    // whether a login was sent, which the daemon rejected then
+    Unauthorized { rpc_login: bool },
+    // any other error status, along with the start of the body
+    HttpStatus { status: StatusCode, body: String },
 }
 
 impl fmt::Display for ClientError {
@@ -87,70 +324,471 @@ impl fmt::Display for ClientError {
             ClientError::HttpClient(e) => {
                 write!(f, "HTTP client error: {}", e)
             },
+            ClientError::UnixSocket(e) => write!(f, "Unix socket error: {}", e),
+            ClientError::Timeout => f.write_str("request timed out"),
+            ClientError::InvalidRequest => f.write_str("invalid request"),
             ClientError::ResponseDeserialization(e) => {
                 write!(f, "response deserialization error: {}", e)
             },
+            ClientError::BinaryResponseDeserialization(e) => {
+                write!(f, "binary response deserialization error: {}", e)
+            },
+            ClientError::ResponseTooLarge(limit) => write!(f, "response larger than {} bytes", limit),
+            ClientError::UnsupportedContentEncoding => f.write_str("unsupported content encoding"),
+            ClientError::Decompression(e) => write!(f, "response decompression error: {}", e),
             ClientError::NoResult => f.write_str("result not found in the response"),
-            ClientError::UnexpectedStatus => f.write_str("unexpected or missing status"),
+            ClientError::Rpc { code, message } => write!(f, "RPC error {}: {}", code, message),
+            ClientError::MismatchedId => f.write_str("response to another request"),
+            ClientError::UnexpectedStatus(Some(status)) => write!(f, "unexpected status {:?}", status),
+            ClientError::UnexpectedStatus(None) => f.write_str("missing status"),
+            ClientError::Busy => f.write_str("daemon is busy"),
+            ClientError::Unauthorized { rpc_login: false } => {
+                f.write_str("daemon requires an RPC login, which is set with rpc_login")
+            },
+            ClientError::Unauthorized { rpc_login: true } => {
+                f.write_str("RPC login rejected by the daemon")
+            },
+            ClientError::HttpStatus { status, body } => {
+                write!(f, "daemon responded with {}", status)?;
+                match *status {
+                    StatusCode::FORBIDDEN => {
+                        f.write_str(", the call may not be allowed by a restricted RPC")?;
+                    },
+                    StatusCode::NOT_FOUND => {
+                        f.write_str(", the base URL may not be the one of the daemon RPC")?;
+                    },
+                    _ => {},
+                }
+                match body.is_empty() {
+                    true => Ok(()),
+                    false => write!(f, ": {}", body),
+                }
+            },
+        }
+    }
+}
+
+impl ClientError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ClientError::HttpClient(e) if e.is_timeout() => "timeout",
+            ClientError::HttpClient(e) if e.is_connect() => "connect",
+            ClientError::HttpClient(e) if e.is_decode() => "deserialization",
+            ClientError::HttpClient(_) => "http",
+            ClientError::UnixSocket(e) if e.is_connect() => "connect",
+            ClientError::UnixSocket(_) => "http",
+            ClientError::Timeout => "timeout",
+            ClientError::InvalidRequest => "invalid_request",
+            ClientError::ResponseDeserialization(_) => "deserialization",
+            ClientError::BinaryResponseDeserialization(_) => "deserialization",
+            ClientError::ResponseTooLarge(_) => "response_too_large",
+            ClientError::UnsupportedContentEncoding => "unsupported_encoding",
+            ClientError::Decompression(_) => "decompression",
+            ClientError::NoResult => "no_result",
+            ClientError::Rpc { .. } => "rpc",
+            ClientError::MismatchedId => "mismatched_id",
+            ClientError::UnexpectedStatus(_) => "unexpected_status",
+            ClientError::Busy => "busy",
+            ClientError::Unauthorized { rpc_login: false } => "rpc_login_required",
+            ClientError::Unauthorized { rpc_login: true } => "rpc_login_rejected",
+            ClientError::HttpStatus { status, .. } => match *status {
+                StatusCode::FORBIDDEN => "restricted",
+                StatusCode::NOT_FOUND => "not_found",
+                status if status.is_server_error() => "server_error",
+                _ => "http_status",
+            },
+        }
+    }
+
+    // errors which a retry shortly after may not run into
+    pub fn is_transient(&self) -> bool {
+        match self {
+            ClientError::HttpClient(e) => e.is_connect(),
+            ClientError::UnixSocket(e) => e.is_connect(),
+            ClientError::Busy => true,
+            ClientError::HttpStatus { status, .. } => status.is_server_error(),
+            _ => false,
+        }
+    }
+
+    // the message without credentials of the request URL
+    pub fn redacted(&self) -> String {
+        let message = self.to_string();
+        match self {
+            ClientError::HttpClient(e) => match e.url() {
+                Some(url) if !url.username().is_empty() || url.password().is_some() => {
+                    let mut redacted = url.clone();
+                    let _ = redacted.set_username("");
+                    let _ = redacted.set_password(None);
+                    message.replace(url.as_str(), redacted.as_str())
+                },
+                _ => message,
+            },
+            _ => message,
         }
     }
 }
 
 impl Client {
-    async fn call<S, B, R>(
+    async fn call<B, R>(
         &self,
-        result_selector: S,
+        method: &str,
         path: &str,
+        format: RpcFormat,
         body: B,
     ) -> Result<R, ClientError>
     where
-        S: FnOnce(serde_json::Value) -> Option<serde_json::Value>,
         B: Serialize,
         R: DeserializeOwned,
     {
-        let url = format!("{}{}", self.base_url.clone(), path);
-        let response = self.http_client
-            .post(url).json(&body).send().await.map_err(ClientError::HttpClient)?
-            .json::<serde_json::Value>().await.map_err(ClientError::HttpClient)?;
+        self.refresh_transport();
+        let url = self.transport.load().url(&self.base_url, path);
+
+        let mut retries = 0;
+        let (called_at, result) = loop {
+            // the latency doesn't include the wait for a free slot, which isn't held during the backoff
+            let slot = match &self.call_slots {
+                Some(call_slots) => Some(call_slots.acquire().await),
+                None => None,
+            };
+            let called_at = SystemTime::now();
+            let started_at = Instant::now();
+            let response = self.send(&url, &body, format, self.method_timeouts.get(method)).await;
+            self.observe_latency(method, started_at.elapsed().as_secs_f64());
+            drop(slot);
+
+            let result = response.and_then(|body| Self::deserialize(format, &body));
+
+            match result {
+                Err(e) if e.is_transient() && retries < self.retry_policy.retries => {
+                    retries += 1;
+                    let backoff = self.retry_policy.backoff(retries);
+                    debug!("retrying {} in {:?} after error: {}", method, backoff, e.redacted());
+                    sleep(backoff).await;
+                },
+                result => break (called_at, result),
+            }
+        };
+
+        self.call_states.lock().unwrap().insert(method.to_string(), CallState {
+            at: called_at,
+            error: result.as_ref().err().map(ClientError::redacted),
+        });
+        result
+    }
+
+    // the login is only sent once the daemon asks for it, and a stale challenge is renewed once
+    async fn send<B: Serialize>(
+        &self,
+        url: &str,
+        body: &B,
+        format: RpcFormat,
+        timeout: Option<&Duration>,
+    ) -> Result<Vec<u8>, ClientError> {
+        let body = match format {
+            RpcFormat::JsonRpc(_) | RpcFormat::Json => serde_json::to_vec(body).ok(),
+            RpcFormat::Binary => serde_json::to_value(body).ok()
+                .and_then(|body| epee::to_vec(&body).ok()),
+        };
+        let body = body.ok_or(ClientError::InvalidRequest)?;
+        let mut response = self.post(url, &body, format, timeout).await?;
+        if response.status() == StatusCode::UNAUTHORIZED && self.rpc_login.is_some() {
+            *self.authentication.lock().unwrap() = Authentication::from_headers(response.headers());
+            response = self.post(url, &body, format, timeout).await?;
+        }
+        if response.status() == StatusCode::UNAUTHORIZED {
+            return Err(ClientError::Unauthorized { rpc_login: self.rpc_login.is_some() });
+        }
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = Self::body_snippet(&mut response).await;
+            return Err(ClientError::HttpStatus { status, body });
+        }
+
+        let encoding = response.headers().get(CONTENT_ENCODING)
+            .map(|encoding| encoding.to_str().ok().and_then(Encoding::from_content_encoding))
+            .unwrap_or(Some(Encoding::Identity))
+            .ok_or(ClientError::UnsupportedContentEncoding)?;
+
+        // a known length is checked upfront, and the body as it's read since the length may be missing
+        let mut body = Vec::new();
+        if let (Some(length), Some(limit)) = (response.content_length(), self.max_response_size) {
+            if length > limit as u64 {
+                return Err(ClientError::ResponseTooLarge(limit));
+            }
+            body.reserve(length as usize);
+        }
+        while let Some(chunk) = response.chunk().await? {
+            match self.max_response_size {
+                Some(limit) if body.len() + chunk.len() > limit => {
+                    return Err(ClientError::ResponseTooLarge(limit));
+                },
+                _ => body.extend_from_slice(&chunk),
+            }
+        }
+        // the limit applies to the decompressed body too, which may be far larger
+        if encoding != Encoding::Identity {
+            let max_len = self.max_response_size.map(|limit| limit as u64 + 1).unwrap_or(u64::MAX);
+            body = decompress(&body, encoding, max_len).map_err(ClientError::Decompression)?;
+            match self.max_response_size {
+                Some(limit) if body.len() > limit => return Err(ClientError::ResponseTooLarge(limit)),
+                _ => {},
+            }
+        }
+        Ok(body)
+    }
+
+    fn from_slice<T: DeserializeOwned>(body: &[u8]) -> Result<T, ClientError> {
+        serde_json::from_slice(body).map_err(ClientError::ResponseDeserialization)
+    }
+
+    fn check_status(status: Option<&str>) -> Result<(), ClientError> {
+        match status {
+            Some("OK") => Ok(()),
+            Some("BUSY") => Err(ClientError::Busy),
+            status => Err(ClientError::UnexpectedStatus(status.map(str::to_string))),
+        }
+    }
+
+    // JSON is read once along with the status, and only read again for the error of a result
+    // which doesn't fit despite an OK status
+    fn deserialize<R: DeserializeOwned>(format: RpcFormat, body: &[u8]) -> Result<R, ClientError> {
+        match format {
+            RpcFormat::JsonRpc(id) => {
+                let envelope: JsonRpcEnvelope<StatusResult<R>> = Self::from_slice(body)?;
+                if let Some(error) = envelope.error {
+                    return Err(ClientError::Rpc { code: error.code, message: error.message });
+                }
+                if envelope.id.as_ref().and_then(|id| id.as_u64()) != Some(id) {
+                    return Err(ClientError::MismatchedId);
+                }
+                let result = envelope.result.ok_or(ClientError::NoResult)?;
+                Self::check_status(result.status.status.as_deref())?;
+                match result.result {
+                    Some(result) => Ok(result),
+                    None => Self::from_slice::<JsonRpcEnvelope<R>>(body)?
+                        .result
+                        .ok_or(ClientError::NoResult),
+                }
+            },
+            RpcFormat::Json => {
+                let envelope: StatusResult<R> = Self::from_slice(body)?;
+                Self::check_status(envelope.status.status.as_deref())?;
+                match envelope.result {
+                    Some(result) => Ok(result),
+                    None => Self::from_slice(body),
+                }
+            },
+            // the storage is decoded into a Value either way
+            RpcFormat::Binary => {
+                let value = epee::from_slice(body).map_err(ClientError::BinaryResponseDeserialization)?;
+                Self::check_status(value.get("status").and_then(|status| status.as_str()))?;
+                serde_json::from_value(value).map_err(ClientError::ResponseDeserialization)
+            },
+        }
+    }
 
-        let result = result_selector(response).ok_or(ClientError::NoResult)?;
+    // the start of the body on a single line, none if it's compressed or can't be read
+    async fn body_snippet(response: &mut Response) -> String {
+        let encoding = response.headers().get(CONTENT_ENCODING)
+            .map(|encoding| encoding.to_str().ok().and_then(Encoding::from_content_encoding));
+        if !matches!(encoding, None | Some(Some(Encoding::Identity))) {
+            return String::new();
+        }
 
-        let status = result.get("status").and_then(|v| v.as_str());
-        if status != Some("OK") {
-            return Err(ClientError::UnexpectedStatus);
+        let mut body = Vec::new();
+        while body.len() < BODY_SNIPPET_LEN {
+            match response.chunk().await {
+                Ok(Some(chunk)) => body.extend_from_slice(&chunk),
+                _ => break,
+            }
         }
+        let body = String::from_utf8_lossy(&body[..body.len().min(BODY_SNIPPET_LEN)]).into_owned();
+        body.split_whitespace().collect::<Vec<_>>().join(" ")
+    }
 
-        serde_json::from_value(result.clone()).map_err(ClientError::ResponseDeserialization)
+    async fn post(
+        &self,
+        url: &str,
+        body: &[u8],
+        format: RpcFormat,
+        timeout: Option<&Duration>,
+    ) -> Result<Response, ClientError> {
+        let mut headers = self.headers.clone();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static(match format {
+            RpcFormat::JsonRpc(_) | RpcFormat::Json => "application/json",
+            RpcFormat::Binary => "application/octet-stream",
+        }));
+        if self.accept_compression {
+            headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip, deflate"));
+        }
+        if let Some(authorization) = self.authorization(url) {
+            let authorization = HeaderValue::from_str(&authorization)
+                .map_err(|_| ClientError::InvalidRequest)?;
+            headers.insert(AUTHORIZATION, authorization);
+        }
+        let transport = self.transport.load_full();
+        transport.post(url, body.to_vec(), headers, timeout.copied()).await
     }
 
-    fn get_json_rpc_result(value: serde_json::Value) -> Option<serde_json::Value> {
-        value.get("result").cloned()
+    fn authorization(&self, url: &str) -> Option<String> {
+        let rpc_login = self.rpc_login.as_ref()?;
+
+        // the digest covers the path of the request
+        let url = Url::parse(url).ok()?;
+        let uri = match url.query() {
+            None => url.path().to_string(),
+            Some(query) => format!("{}?{}", url.path(), query),
+        };
+        self.authentication.lock().unwrap().as_mut()
+            .and_then(|authentication| authentication.authorization(rpc_login, "POST", &uri))
     }
 
-    async fn call_json_rpc<B, R>(&self, method: &str, body: B) -> Result<R, ClientError>
+    fn observe_latency(&self, method: &str, seconds: f64) {
+        let mut latencies = self.latencies.lock().unwrap();
+        latencies.entry(method.to_string())
+            .or_insert_with(|| Histogram::new(&self.latency_buckets))
+            .observe(seconds);
+    }
+
+    pub fn latency_metric(&self) -> Metric {
+        let latencies = self.latencies.lock().unwrap();
+        Metric::new_histogram_with_label_values(
+            "monerod_exporter_rpc_duration_seconds",
+            "method",
+            latencies.iter().map(|(method, histogram)| (method.clone(), histogram)),
+        )
+    }
+
+    // for methods without a wrapper of their own, e.g. in custom collectors
+    pub async fn call_json_rpc<B, R>(&self, method: &str, body: B) -> Result<R, ClientError>
     where
         B: Serialize,
         R: DeserializeOwned,
     {
-        let body = json!({
-            "method": method,
-            "params": body,
-        });
-        self.call(Self::get_json_rpc_result, "/json_rpc", body).await
+        let id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+        let body = JsonRpcRequest { jsonrpc: "2.0", id, method, params: body };
+        self.call(method, "/json_rpc", RpcFormat::JsonRpc(id), body).await
     }
 
-    async fn call_rpc<B, R>(&self, path: &str, body: B) -> Result<R, ClientError>
+    pub async fn call_rpc<B, R>(&self, path: &str, body: B) -> Result<R, ClientError>
     where
         B: Serialize,
         R: DeserializeOwned,
     {
-        self.call(Some, path, body).await
+        self.call(path.trim_start_matches('/'), path, RpcFormat::Json, body).await
     }
 
-    pub fn new(http_client: reqwest::Client, base_url: String) -> Client {
+    // for the .bin endpoints, e.g. /get_transaction_pool_hashes.bin, which are far cheaper
+    // for bulk data than their JSON counterparts, if they have any
+    pub async fn call_binary_rpc<B, R>(&self, path: &str, body: B) -> Result<R, ClientError>
+    where
+        B: Serialize,
+        R: DeserializeOwned,
+    {
+        self.call(path.trim_start_matches('/'), path, RpcFormat::Binary, body).await
+    }
+
+    pub fn new(
+        transport: impl Into<Transport>,
+        base_url: String,
+        rpc_login: Option<RpcLoginConfig>,
+        max_concurrent_calls: u32,
+        latency_buckets: Vec<f64>,
+        retry_policy: RetryPolicy,
+    ) -> Client {
+        let call_slots = Some(max_concurrent_calls)
+            .filter(|max| *max > 0)
+            .map(|max| Arc::new(Semaphore::new(max as usize)));
+
         Client {
-            http_client,
+            transport: Arc::new(ArcSwap::from_pointee(transport.into())),
+            transport_refresh: None,
             base_url,
+            rpc_login,
+            authentication: Arc::new(Mutex::new(None)),
+            call_slots,
+            latency_buckets,
+            retry_policy,
+            method_timeouts: BTreeMap::new(),
+            max_response_size: Some(DEFAULT_MAX_RESPONSE_SIZE),
+            accept_compression: true,
+            headers: HeaderMap::new(),
+            next_request_id: Arc::new(AtomicU64::new(0)),
+            latencies: Arc::new(Mutex::new(BTreeMap::new())),
+            call_states: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    // by the method label of the latency histogram, e.g. get_block_headers_range
+    pub fn with_method_timeouts(mut self, method_timeouts: BTreeMap<String, Duration>) -> Client {
+        self.method_timeouts = method_timeouts;
+        self
+    }
+
+    // 0 for no limit
+    pub fn with_max_response_size(mut self, max_response_size: usize) -> Client {
+        self.max_response_size = Some(max_response_size).filter(|max| *max > 0);
+        self
+    }
+
+    pub fn with_compression(mut self, accept_compression: bool) -> Client {
+        self.accept_compression = accept_compression;
+        self
+    }
+
+    // e.g. the User-Agent
+    pub fn with_headers(mut self, headers: HeaderMap) -> Client {
+        self.headers = headers;
+        self
+    }
+
+    pub fn with_transport_refresh<F>(mut self, interval: Duration, rebuild: F) -> Client
+    where
+        F: Fn() -> Result<Transport, Box<dyn error::Error>> + Send + Sync + 'static,
+    {
+        self.transport_refresh = Some(Arc::new(TransportRefresh {
+            interval,
+            rebuild: Box::new(rebuild),
+            refreshed_at: Mutex::new(Instant::now()),
+        }));
+        self
+    }
+
+    // the old transport is kept if the new one can't be built
+    fn refresh_transport(&self) {
+        let refresh = match &self.transport_refresh {
+            Some(refresh) => refresh,
+            None => return,
+        };
+        {
+            let mut refreshed_at = refresh.refreshed_at.lock().unwrap();
+            if refreshed_at.elapsed() < refresh.interval {
+                return;
+            }
+            *refreshed_at = Instant::now();
+        }
+        match (refresh.rebuild)() {
+            Ok(transport) => self.transport.store(Arc::new(transport)),
+            Err(e) => warn!("failed to refresh RPC client of {}: {}", self.sanitized_base_url(), e),
+        }
+    }
+
+    pub fn call_states(&self) -> BTreeMap<String, CallState> {
+        self.call_states.lock().unwrap().clone()
+    }
+
+    // base URL without credentials, query and fragment
+    pub fn sanitized_base_url(&self) -> String {
+        match reqwest::Url::parse(&self.base_url) {
+            Ok(mut url) => {
+                let _ = url.set_username("");
+                let _ = url.set_password(None);
+                url.set_query(None);
+                url.set_fragment(None);
+                url.to_string()
+            },
+            Err(_) => String::new(),
         }
     }
 
@@ -158,11 +796,31 @@ impl Client {
         self.call_json_rpc("get_info", json!({})).await
     }
 
+    pub async fn prune_blockchain(
+        &self,
+        req: PruneBlockchainRequest,
+    ) -> Result<PruneBlockchainResponse, ClientError> {
+        self.call_json_rpc("prune_blockchain", req).await
+    }
+
+    // larger ranges are requested in chunks at once and stitched back together
     pub async fn get_block_headers_range(
         &self,
         req: BlockHeadersRangeRequest,
     ) -> Result<BlockHeadersRangeResponse, ClientError> {
-        self.call_json_rpc("get_block_headers_range", req).await
+        let chunks = (req.start_height..=req.end_height)
+            .step_by(MAX_BLOCK_HEADERS_PER_CALL as usize)
+            .map(|start_height| BlockHeadersRangeRequest {
+                start_height,
+                end_height: req.end_height.min(start_height + MAX_BLOCK_HEADERS_PER_CALL - 1),
+            })
+            .map(|chunk| self.call_json_rpc("get_block_headers_range", chunk));
+        let responses: Vec<BlockHeadersRangeResponse> = try_join_all(chunks).await?;
+
+        Ok(BlockHeadersRangeResponse {
+            untrusted: responses.iter().any(|response| response.untrusted),
+            headers: responses.into_iter().flat_map(|response| response.headers).collect(),
+        })
     }
 
     pub async fn get_transaction_pool_stats(
@@ -170,4 +828,98 @@ impl Client {
     ) -> Result<TransactionPoolStatsResponse, ClientError> {
         self.call_rpc("/get_transaction_pool_stats", json!({})).await
     }
+
+    pub async fn get_transaction_pool_hashes(
+        &self
+    ) -> Result<TransactionPoolHashesResponse, ClientError> {
+        self.call_binary_rpc("/get_transaction_pool_hashes.bin", json!({})).await
+    }
+
+    // like the block headers, more transactions are requested in chunks at once
+    pub async fn get_transactions(
+        &self,
+        req: TransactionsRequest,
+    ) -> Result<TransactionsResponse, ClientError> {
+        let chunks = req.txs_hashes.chunks(MAX_TRANSACTIONS_PER_CALL)
+            .map(|chunk| TransactionsRequest { txs_hashes: chunk.to_vec() })
+            .map(|chunk| self.call_rpc("/get_transactions", chunk));
+        let responses: Vec<TransactionsResponse> = try_join_all(chunks).await?;
+
+        let mut stitched = TransactionsResponse {
+            txs: Vec::new(),
+            missed_tx: Vec::new(),
+            untrusted: false,
+        };
+        for response in responses {
+            stitched.txs.extend(response.txs);
+            stitched.missed_tx.extend(response.missed_tx);
+            stitched.untrusted |= response.untrusted;
+        }
+        Ok(stitched)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epee::Field;
+
+    fn pool_hashes(blob: Option<Vec<u8>>) -> Result<TransactionPoolHashesResponse, serde_json::Error> {
+        let mut fields = vec![("status", Field::Blob(b"OK".to_vec()))];
+        fields.extend(blob.map(|blob| ("tx_hashes", Field::Blob(blob))));
+        let mut value = epee::from_slice(&epee::fields_to_vec(&fields).unwrap()).unwrap();
+        // fields are never booleans
+        value["untrusted"] = false.into();
+        serde_json::from_value(value)
+    }
+
+    #[test]
+    fn splits_the_pool_hashes() {
+        let mut blob = vec![0xab; 32];
+        blob.extend_from_slice(&[0x01; 32]);
+        assert_eq!(pool_hashes(Some(blob)).unwrap().tx_hashes, vec!["ab".repeat(32), "01".repeat(32)]);
+        // decoded as text rather than hex
+        assert_eq!(pool_hashes(Some(vec![b'a'; 32])).unwrap().tx_hashes, vec!["61".repeat(32)]);
+        assert!(pool_hashes(None).unwrap().tx_hashes.is_empty());
+        assert!(pool_hashes(Some(vec![0xab; 31])).is_err());
+    }
+
+    #[test]
+    fn deserializes_json_rpc_results() {
+        let deserialize = |body: &str| {
+            Client::deserialize::<InfoResponse>(RpcFormat::JsonRpc(1), body.as_bytes())
+        };
+        let info = r#"{"id": 1, "result": {"status": "OK", "height": 3, "untrusted": false}}"#;
+        assert!(matches!(deserialize(info), Err(ClientError::ResponseDeserialization(_))));
+        let busy = r#"{"id": 1, "result": {"status": "BUSY", "untrusted": false}}"#;
+        assert!(matches!(deserialize(busy), Err(ClientError::Busy)));
+        let error = r#"{"id": 1, "error": {"code": -32601, "message": "Method not found"}}"#;
+        assert!(matches!(deserialize(error), Err(ClientError::Rpc { code: -32601, .. })));
+        let other = r#"{"id": 2, "result": {"status": "OK"}}"#;
+        assert!(matches!(deserialize(other), Err(ClientError::MismatchedId)));
+        assert!(matches!(deserialize(r#"{"id": 1}"#), Err(ClientError::NoResult)));
+
+        let body = r#"{"id": 1, "result": {"status": "OK", "height": 3}}"#;
+        // the status is kept along with the rest
+        let value: serde_json::Value = Client::deserialize(RpcFormat::JsonRpc(1), body.as_bytes())
+            .unwrap();
+        assert_eq!(value, json!({"status": "OK", "height": 3}));
+    }
+
+    #[test]
+    fn deserializes_json_results() {
+        let deserialize = |body: &str| {
+            Client::deserialize::<TransactionPoolHashesResponse>(RpcFormat::Json, body.as_bytes())
+        };
+        let empty = deserialize(r#"{"status": "OK", "untrusted": true}"#).unwrap();
+        assert!(empty.tx_hashes.is_empty() && empty.untrusted);
+        assert!(matches!(deserialize(r#"{"status": "BUSY"}"#), Err(ClientError::Busy)));
+        let missing = deserialize(r#"{"status": "OK"}"#);
+        assert!(matches!(missing, Err(ClientError::ResponseDeserialization(_))));
+        assert!(matches!(
+            deserialize(r#"{"status": "Failed", "untrusted": false}"#),
+            Err(ClientError::UnexpectedStatus(Some(_))),
+        ));
+        assert!(matches!(deserialize("<html>"), Err(ClientError::ResponseDeserialization(_))));
+    }
 }
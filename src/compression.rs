@@ -0,0 +1,83 @@
+use flate2::{
+    Compression,
+    read::{GzDecoder, ZlibDecoder},
+    write::{GzEncoder, ZlibEncoder},
+};
+use std::io::{self, Read, Write};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    // "deflate" in HTTP is the zlib format
+    Deflate,
+}
+
+impl Encoding {
+    // picks the encoding with the highest q-value in the Accept-Encoding header, preferring gzip
+    pub fn negotiate(accept_encoding: Option<&str>) -> Encoding {
+        let mut best = (Encoding::Identity, 0.0);
+        for item in accept_encoding.unwrap_or("").split(',') {
+            let mut parts = item.split(';').map(str::trim);
+            let encoding = match parts.next().map(str::to_ascii_lowercase).as_deref() {
+                Some("gzip") | Some("x-gzip") | Some("*") => Encoding::Gzip,
+                Some("deflate") => Encoding::Deflate,
+                _ => continue,
+            };
+            let q = parts
+                .find_map(|param| param.strip_prefix("q="))
+                .and_then(|q| q.parse::<f64>().ok())
+                .unwrap_or(1.0);
+            let is_better = q > best.1 || (q == best.1 && q > 0.0 && encoding == Encoding::Gzip);
+            if q > 0.0 && is_better {
+                best = (encoding, q);
+            }
+        }
+        best.0
+    }
+
+    // none if the encoding isn't supported
+    pub fn from_content_encoding(content_encoding: &str) -> Option<Encoding> {
+        match content_encoding.trim().to_ascii_lowercase().as_str() {
+            "identity" => Some(Encoding::Identity),
+            "gzip" | "x-gzip" => Some(Encoding::Gzip),
+            "deflate" => Some(Encoding::Deflate),
+            _ => None,
+        }
+    }
+
+    pub fn content_encoding(self) -> Option<&'static str> {
+        match self {
+            Encoding::Identity => None,
+            Encoding::Gzip => Some("gzip"),
+            Encoding::Deflate => Some("deflate"),
+        }
+    }
+}
+
+pub fn compress(data: &[u8], encoding: Encoding) -> io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Identity => Ok(data.to_vec()),
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(data)?;
+            encoder.finish()
+        },
+        Encoding::Deflate => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(data)?;
+            encoder.finish()
+        },
+    }
+}
+
+// at most max_len bytes of the decompressed data, which otherwise may have no bounds
+pub fn decompress(data: &[u8], encoding: Encoding, max_len: u64) -> io::Result<Vec<u8>> {
+    let mut decompressed = Vec::new();
+    match encoding {
+        Encoding::Identity => return Ok(data.to_vec()),
+        Encoding::Gzip => GzDecoder::new(data).take(max_len).read_to_end(&mut decompressed)?,
+        Encoding::Deflate => ZlibDecoder::new(data).take(max_len).read_to_end(&mut decompressed)?,
+    };
+    Ok(decompressed)
+}
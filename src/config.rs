@@ -1,7 +1,53 @@
 use config::{Environment, File, FileFormat};
 use humantime::parse_duration;
+use regex::Regex;
+use reqwest::header::{HeaderName, HeaderValue};
 use serde::Deserialize;
-use std::{convert::TryInto, fmt, path::PathBuf, time::Duration};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    convert::TryInto,
+    fmt,
+    fs,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
+
+use crate::{
+    allowlist::Cidr,
+    client::DEFAULT_MAX_RESPONSE_SIZE,
+    discovery::{DNS_PORT, is_valid_name},
+    expression::{Expression, ParseError},
+    graphite::GRAPHITE_PORT,
+    logtail::DEFAULT_LOG_PATTERNS,
+    metrics::{COLLECTORS, EmptySpanPolicy, ScrapeMode, StalePolicy},
+    pinning::parse_pin,
+    probe::{P2P_PORT, parse_address},
+    prometheus::{DEFAULT_BUCKETS, RewriteRule},
+    server::{TlsVersion, cipher_suite, is_cipher_suite_usable},
+    socks::SocksProxy,
+    systemd::ReadyOn,
+    zmq,
+};
+
+// the host and port of a daemon URL, identifying the daemon unless named otherwise
+pub fn url_name(url: &str) -> String {
+    match reqwest::Url::parse(url) {
+        Ok(parsed) => match (parsed.host_str(), parsed.port_or_known_default()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => url.to_string(),
+        },
+        Err(_) => url.to_string(),
+    }
+}
+
+// the port may be left out
+fn parse_nameserver(nameserver: &str) -> Option<SocketAddr> {
+    nameserver.parse::<SocketAddr>()
+        .or_else(|_| nameserver.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, DNS_PORT)))
+        .ok()
+}
 
 fn parse_path<E: Clone>(
     default: Option<PathBuf>,
@@ -20,19 +66,88 @@ fn parse_path<E: Clone>(
     })
 }
 
+// [a-zA-Z_:][a-zA-Z0-9_:]*
+fn is_valid_metric_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BasicAuthConfig {
+    pub username: String,
+    // bcrypt hash of the password
+    pub password_hash: String,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct RpcLoginConfig {
+    pub username: String,
+    pub password: String,
+}
+
+// the config is logged, the password must not be
+impl fmt::Debug for RpcLoginConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RpcLoginConfig")
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+// served regardless of the metrics path
+const RESERVED_PATHS: &[&str] = &["/", "/health", "/ready", "/status", "/check"];
+
+// the smallest read buffer hyper accepts
+const MIN_MAX_HEADER_SIZE: usize = 8192;
+
+// server hosts with this prefix are paths to a Unix socket
+pub const UNIX_SOCKET_PREFIX: &str = "unix:";
+
 #[derive(Debug, Deserialize)]
 pub struct ServerConfig {
     pub host: String,
+    pub metrics_path: String,
     pub tls_key_path: Option<PathBuf>,
     pub tls_cert_path: Option<PathBuf>,
+    // clients have to present a certificate signed by this CA when set
+    pub tls_client_ca_path: Option<PathBuf>,
+    pub tls_min_version: TlsVersion,
+    // IANA names of the allowed cipher suites, all of the supported ones if empty
+    pub tls_ciphers: Vec<String>,
+    pub basic_auth: Option<BasicAuthConfig>,
+    pub bearer_token_path: Option<PathBuf>,
+    pub access_log: bool,
+    pub max_concurrent_scrapes: u32,
+    pub rate_limit_per_minute: u32,
+    pub request_timeout: Option<Duration>,
+    pub max_header_size: usize,
+    // only these networks may scrape the metrics if not empty
+    pub allowed_cidrs: Vec<Cidr>,
+    // whether other exporters may have this one check ports for them
+    pub port_checks: bool,
 }
 
 impl Default for ServerConfig {
     fn default() -> Self {
         ServerConfig {
             host: "[::]:8080".into(),
+            metrics_path: "/metrics".into(),
             tls_key_path: None,
             tls_cert_path: None,
+            tls_client_ca_path: None,
+            tls_min_version: TlsVersion::Tls12,
+            tls_ciphers: Vec::new(),
+            basic_auth: None,
+            bearer_token_path: None,
+            access_log: false,
+            max_concurrent_scrapes: 0,
+            rate_limit_per_minute: 0,
+            request_timeout: Some(Duration::from_secs(30)),
+            max_header_size: 16384,
+            allowed_cidrs: Vec::new(),
+            port_checks: false,
         }
     }
 }
@@ -40,14 +155,42 @@ impl Default for ServerConfig {
 #[derive(Debug, Deserialize)]
 pub struct ServerSettings {
     pub host: Option<String>,
+    pub metrics_path: Option<String>,
     pub tls_key_path: Option<String>,
     pub tls_cert_path: Option<String>,
+    pub tls_client_ca_path: Option<String>,
+    pub tls_min_version: Option<String>,
+    pub tls_ciphers: Option<String>,
+    pub basic_auth_username: Option<String>,
+    pub basic_auth_password_hash: Option<String>,
+    pub basic_auth_password_file: Option<String>,
+    pub bearer_token_file: Option<String>,
+    pub access_log: Option<bool>,
+    pub max_concurrent_scrapes: Option<u32>,
+    pub rate_limit_per_minute: Option<u32>,
+    pub request_timeout: Option<String>,
+    pub max_header_size: Option<usize>,
+    pub allowed_cidrs: Option<String>,
+    pub port_checks: Option<bool>,
 }
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Clone, Debug)]
 pub enum ServerSettingsError {
+    InvalidMetricsPath,
     InvalidTlsKeyPath,
     InvalidTlsCertPath,
+    InvalidTlsClientCaPath,
+    ClientCaWithoutTls,
+    InvalidTlsMinVersion,
+    InvalidTlsCiphers,
+    InvalidBasicAuthPasswordFile,
+    InvalidBasicAuth,
+    InvalidBearerTokenFile,
+    InvalidRequestTimeout,
+    InvalidMaxHeaderSize,
+    InvalidAllowedCidrs,
+    AllowedCidrsOnUnixSocket,
 }
 
 impl TryInto<ServerConfig> for ServerSettings {
@@ -58,6 +201,11 @@ impl TryInto<ServerConfig> for ServerSettings {
 
         let host = self.host.unwrap_or(default.host);
 
+        let metrics_path = self.metrics_path.unwrap_or(default.metrics_path);
+        if !metrics_path.starts_with('/') || RESERVED_PATHS.contains(&metrics_path.as_str()) {
+            return Err(ServerSettingsError::InvalidMetricsPath);
+        }
+
         let tls_key_path = parse_path(
             default.tls_key_path,
             ServerSettingsError::InvalidTlsKeyPath,
@@ -70,45 +218,424 @@ impl TryInto<ServerConfig> for ServerSettings {
             self.tls_cert_path,
         )?;
 
+        let tls_client_ca_path = parse_path(
+            default.tls_client_ca_path,
+            ServerSettingsError::InvalidTlsClientCaPath,
+            self.tls_client_ca_path,
+        )?;
+        if tls_client_ca_path.is_some() && (tls_key_path.is_none() || tls_cert_path.is_none()) {
+            return Err(ServerSettingsError::ClientCaWithoutTls);
+        }
+
+        let tls_min_version = match self.tls_min_version.as_deref() {
+            None => default.tls_min_version,
+            Some("1.2") => TlsVersion::Tls12,
+            Some("1.3") => TlsVersion::Tls13,
+            Some(_) => return Err(ServerSettingsError::InvalidTlsMinVersion),
+        };
+
+        let tls_ciphers = match self.tls_ciphers {
+            None => default.tls_ciphers,
+            Some(ciphers) => ciphers.split(',')
+                .map(str::trim)
+                .filter(|cipher| !cipher.is_empty())
+                .map(String::from)
+                .collect(),
+        };
+        let usable = |name: &String| {
+            cipher_suite(name)
+                .map(|suite| is_cipher_suite_usable(suite, tls_min_version))
+                .unwrap_or(false)
+        };
+        if !tls_ciphers.iter().all(usable) {
+            return Err(ServerSettingsError::InvalidTlsCiphers);
+        }
+
+        let password_file = parse_path(
+            None,
+            ServerSettingsError::InvalidBasicAuthPasswordFile,
+            self.basic_auth_password_file,
+        )?;
+
+        let file_password_hash = match password_file {
+            None => None,
+            Some(path) => Some(fs::read_to_string(path)
+                .map_err(|_| ServerSettingsError::InvalidBasicAuthPasswordFile)?),
+        };
+
+        let password_hash = self.basic_auth_password_hash.filter(|hash| !hash.is_empty());
+        let password_hash = match (password_hash, file_password_hash) {
+            (Some(hash), None) | (None, Some(hash)) => Some(hash.trim().to_string()),
+            (None, None) => None,
+            (Some(_), Some(_)) => return Err(ServerSettingsError::InvalidBasicAuth),
+        };
+
+        let basic_auth = match (self.basic_auth_username.filter(|u| !u.is_empty()), password_hash) {
+            (None, None) => default.basic_auth,
+            (Some(username), Some(password_hash)) => {
+                // fails on anything that isn't a bcrypt hash
+                if bcrypt::verify("", &password_hash).is_err() {
+                    return Err(ServerSettingsError::InvalidBasicAuth);
+                }
+                Some(BasicAuthConfig { username, password_hash })
+            },
+            (Some(_), None) | (None, Some(_)) => return Err(ServerSettingsError::InvalidBasicAuth),
+        };
+
+        // the token itself is read when the server is created, so it isn't kept in the config
+        let bearer_token_path = parse_path(
+            default.bearer_token_path,
+            ServerSettingsError::InvalidBearerTokenFile,
+            self.bearer_token_file,
+        )?;
+
+        let access_log = self.access_log.unwrap_or(default.access_log);
+        let max_concurrent_scrapes = self.max_concurrent_scrapes
+            .unwrap_or(default.max_concurrent_scrapes);
+        let rate_limit_per_minute = self.rate_limit_per_minute.unwrap_or(default.rate_limit_per_minute);
+
+        let request_timeout = match self.request_timeout.as_deref() {
+            None => default.request_timeout,
+            Some("") => None,
+            Some(timeout) => Some(parse_duration(timeout)
+                .map_err(|_| ServerSettingsError::InvalidRequestTimeout)?),
+        };
+
+        let max_header_size = self.max_header_size.unwrap_or(default.max_header_size);
+        if max_header_size < MIN_MAX_HEADER_SIZE {
+            return Err(ServerSettingsError::InvalidMaxHeaderSize);
+        }
+
+        let allowed_cidrs = match self.allowed_cidrs {
+            None => default.allowed_cidrs,
+            Some(cidrs) => cidrs.split(',')
+                .map(str::trim)
+                .filter(|cidr| !cidr.is_empty())
+                .map(|cidr| cidr.parse::<Cidr>().map_err(|_| ServerSettingsError::InvalidAllowedCidrs))
+                .collect::<Result<Vec<_>, _>>()?,
+        };
+        if host.starts_with(UNIX_SOCKET_PREFIX) && !allowed_cidrs.is_empty() {
+            return Err(ServerSettingsError::AllowedCidrsOnUnixSocket);
+        }
+
+        let port_checks = self.port_checks.unwrap_or(default.port_checks);
+
         Ok(ServerConfig {
             host,
+            metrics_path,
             tls_key_path,
             tls_cert_path,
+            tls_client_ca_path,
+            tls_min_version,
+            tls_ciphers,
+            basic_auth,
+            bearer_token_path,
+            access_log,
+            max_concurrent_scrapes,
+            rate_limit_per_minute,
+            request_timeout,
+            max_header_size,
+            allowed_cidrs,
+            port_checks,
         })
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize)]
 pub struct MonerodConfig {
+    // value of the target label when there are several targets
+    pub name: String,
     pub base_url: String,
+    pub rpc_login: Option<RpcLoginConfig>,
+    pub proxy: Option<String>,
+    // whether HTTP_PROXY, HTTPS_PROXY and NO_PROXY apply without a proxy of its own
+    pub env_proxy: bool,
     pub tls_cert_path: Option<PathBuf>,
+    // presented to a proxy in front of the daemon which requires mutual TLS
+    pub tls_client_cert_path: Option<PathBuf>,
+    pub tls_client_key_path: Option<PathBuf>,
+    // verified and sent in SNI in place of the host of the URL, e.g. for a daemon on an IP
+    pub tls_server_name: Option<String>,
+    // SHA-256 hashes of public keys, one of which the certificate has to have instead of a valid chain
+    pub tls_pinned_keys: Vec<[u8; 32]>,
     pub skip_tls_verification: bool,
     pub timeout: Duration,
+    pub connect_timeout: Option<Duration>,
+    // resolves the host of the URL instead of the system resolver
+    pub nameserver: Option<SocketAddr>,
+    // after which the connections are made anew, to the addresses the host resolves to by then
+    pub dns_refresh_interval: Option<Duration>,
+    // after which idle connections are closed, none for never
+    pub pool_idle_timeout: Option<Duration>,
+    // none for no limit, 0 for a connection per request
+    pub pool_max_idle_per_host: Option<usize>,
+    // of the TCP connections, so that NATs and Tor circuits don't drop them between refreshes
+    pub tcp_keepalive: Option<Duration>,
+    // negotiated over TLS and sent with prior knowledge otherwise, for proxies in front of the daemon
+    pub http2: bool,
+    // in place of the timeout for the methods they are set for
+    pub method_timeouts: BTreeMap<String, Duration>,
+    // of calls failing with connect errors, 5xx responses or a BUSY status
+    pub retries: u32,
+    pub retry_delay: Duration,
+    pub retry_jitter: f64,
+    // consecutive failed refreshes after which the daemon isn't called for the cooldown, 0 for never
+    pub circuit_breaker_failures: u32,
+    pub circuit_breaker_cooldown: Duration,
+    pub max_concurrent_calls: u32,
+    pub max_response_size: usize,
+    // whether the daemon, or rather a proxy in front of it, may compress responses
+    pub accept_compression: bool,
+    // none for no User-Agent header
+    pub user_agent: Option<String>,
+    // sent with every request, e.g. an API key of a proxy in front of the daemon
+    pub headers: BTreeMap<String, String>,
+    // the tcp:// URL monerod publishes its events at with --zmq-pub
+    pub zmq_url: Option<String>,
+    // host:port of the P2P port connected to on every refresh, to tell whether it accepts connections
+    pub p2p_address: Option<String>,
+    // whether the probe of the P2P port goes on with a handshake, which tells what the node advertises
+    pub p2p_handshake: bool,
+    // peers of the node's handshake which are handshaken with too, every survey interval
+    pub p2p_survey_peers: u32,
+    pub p2p_survey_interval: Duration,
+    // host:port of the onion service of the daemon, connected to through the onion proxy
+    // every check interval to tell whether it's reachable from outside
+    pub onion_address: Option<String>,
+    // a socks5h:// proxy, the proxy of the daemon unless set
+    pub onion_proxy: Option<String>,
+    pub onion_check_interval: Duration,
+    pub onion_timeout: Duration,
+    // base URL of another exporter with port checks, which checks the public ports every interval
+    pub port_checker: Option<String>,
+    // host:port addresses the daemon is reachable at from the internet, with distinct ports
+    pub public_ports: Vec<String>,
+    pub port_check_interval: Duration,
+    // the log of the daemon, whose lines matching the patterns are counted by the names of the patterns
+    pub log_path: Option<PathBuf>,
+    pub log_patterns: BTreeMap<String, String>,
+    pub latency_buckets: Vec<f64>,
+    pub disabled_collectors: Vec<String>,
 }
 
 impl Default for MonerodConfig {
     fn default() -> Self {
         MonerodConfig {
+            name: "localhost:18081".into(),
             base_url: "http://localhost:18081".into(),
+            rpc_login: None,
+            proxy: None,
+            env_proxy: true,
             tls_cert_path: None,
+            tls_client_cert_path: None,
+            tls_client_key_path: None,
+            tls_server_name: None,
+            tls_pinned_keys: Vec::new(),
             skip_tls_verification: false,
             timeout: Duration::from_secs(1),
+            connect_timeout: None,
+            nameserver: None,
+            dns_refresh_interval: None,
+            // those of reqwest
+            pool_idle_timeout: Some(Duration::from_secs(90)),
+            pool_max_idle_per_host: None,
+            tcp_keepalive: None,
+            http2: false,
+            method_timeouts: BTreeMap::new(),
+            retries: 0,
+            retry_delay: Duration::from_millis(100),
+            retry_jitter: 0.5,
+            circuit_breaker_failures: 0,
+            circuit_breaker_cooldown: Duration::from_secs(60),
+            max_concurrent_calls: 4,
+            max_response_size: DEFAULT_MAX_RESPONSE_SIZE,
+            accept_compression: true,
+            user_agent: Some(format!("monerod-exporter/{}", env!("CARGO_PKG_VERSION"))),
+            headers: BTreeMap::new(),
+            zmq_url: None,
+            p2p_address: None,
+            p2p_handshake: false,
+            p2p_survey_peers: 0,
+            p2p_survey_interval: Duration::from_secs(10 * 60),
+            onion_address: None,
+            onion_proxy: None,
+            onion_check_interval: Duration::from_secs(5 * 60),
+            onion_timeout: Duration::from_secs(60),
+            port_checker: None,
+            public_ports: Vec::new(),
+            port_check_interval: Duration::from_secs(60),
+            log_path: None,
+            log_patterns: DEFAULT_LOG_PATTERNS.iter()
+                .map(|(name, pattern)| (name.to_string(), pattern.to_string()))
+                .collect(),
+            latency_buckets: DEFAULT_BUCKETS.to_vec(),
+            disabled_collectors: Vec::new(),
         }
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Default, Deserialize)]
 pub struct MonerodSettings {
+    pub name: Option<String>,
     pub base_url: Option<String>,
+    pub rpc_username: Option<String>,
+    pub rpc_password: Option<String>,
+    pub rpc_login_file: Option<String>,
+    pub proxy: Option<String>,
+    pub env_proxy: Option<bool>,
     pub tls_cert_path: Option<String>,
+    pub tls_client_cert_path: Option<String>,
+    pub tls_client_key_path: Option<String>,
+    pub tls_server_name: Option<String>,
+    pub tls_pinned_keys: Option<String>,
     pub skip_tls_verification: Option<bool>,
     pub timeout: Option<String>,
+    pub connect_timeout: Option<String>,
+    pub nameserver: Option<String>,
+    pub dns_refresh_interval: Option<String>,
+    pub pool_idle_timeout: Option<String>,
+    pub pool_max_idle_per_host: Option<usize>,
+    pub tcp_keepalive: Option<String>,
+    pub http2: Option<bool>,
+    pub method_timeouts: Option<String>,
+    pub retries: Option<u32>,
+    pub retry_delay: Option<String>,
+    pub retry_jitter: Option<f64>,
+    pub circuit_breaker_failures: Option<u32>,
+    pub circuit_breaker_cooldown: Option<String>,
+    pub max_concurrent_calls: Option<u32>,
+    pub max_response_size: Option<usize>,
+    pub accept_compression: Option<bool>,
+    pub user_agent: Option<String>,
+    pub headers: Option<String>,
+    pub zmq_url: Option<String>,
+    pub p2p_address: Option<String>,
+    pub p2p_handshake: Option<bool>,
+    pub p2p_survey_peers: Option<u32>,
+    pub p2p_survey_interval: Option<String>,
+    pub onion_address: Option<String>,
+    pub onion_proxy: Option<String>,
+    pub onion_check_interval: Option<String>,
+    pub onion_timeout: Option<String>,
+    pub port_checker: Option<String>,
+    pub public_ports: Option<String>,
+    pub port_check_interval: Option<String>,
+    pub log_path: Option<String>,
+    pub log_patterns: Option<BTreeMap<String, String>>,
+    pub latency_buckets: Option<String>,
+    pub disabled_collectors: Option<String>,
 }
 
 #[derive(Clone, Debug)]
 pub enum MonerodSettingsError {
+    InvalidRpcLogin,
+    InvalidRpcLoginFile,
+    InvalidProxy,
     InvalidTlsCertPath,
+    InvalidTlsClientCertPath,
+    InvalidTlsClientKeyPath,
+    IncompleteTlsClientIdentity,
+    InvalidTlsPinnedKeys,
     InvalidTimeout,
+    InvalidConnectTimeout,
+    InvalidNameserver,
+    InvalidDnsRefreshInterval,
+    InvalidPoolIdleTimeout,
+    InvalidTcpKeepalive,
+    InvalidMethodTimeouts,
+    InvalidRetryDelay,
+    InvalidRetryJitter,
+    InvalidCircuitBreakerCooldown,
+    InvalidUserAgent,
+    InvalidHeaders,
+    InvalidZmqUrl,
+    InvalidP2pAddress,
+    InvalidP2pSurveyInterval,
+    InvalidOnionAddress,
+    InvalidOnionProxy,
+    InvalidOnionCheckInterval,
+    InvalidOnionTimeout,
+    InvalidPortChecker,
+    InvalidPublicPorts,
+    IncompletePortChecks,
+    InvalidPortCheckInterval,
+    InvalidLogPattern(String),
+    InvalidLatencyBuckets,
+    UnknownCollector(String),
+}
+
+impl MonerodSettings {
+    // the settings left unset are taken from the defaults, except the name which is per target
+    fn inherit(self, defaults: &MonerodSettings) -> MonerodSettings {
+        let defaults = defaults.clone();
+        // the login is inherited as a whole, so that a target can use a file instead of a password
+        let sets_login = self.rpc_username.is_some()
+            || self.rpc_password.is_some()
+            || self.rpc_login_file.is_some();
+        let (rpc_username, rpc_password, rpc_login_file) = if sets_login {
+            (self.rpc_username, self.rpc_password, self.rpc_login_file)
+        } else {
+            (defaults.rpc_username, defaults.rpc_password, defaults.rpc_login_file)
+        };
+        MonerodSettings {
+            name: self.name,
+            base_url: self.base_url.or(defaults.base_url),
+            rpc_username,
+            rpc_password,
+            rpc_login_file,
+            proxy: self.proxy.or(defaults.proxy),
+            env_proxy: self.env_proxy.or(defaults.env_proxy),
+            tls_cert_path: self.tls_cert_path.or(defaults.tls_cert_path),
+            tls_client_cert_path: self.tls_client_cert_path.or(defaults.tls_client_cert_path),
+            tls_client_key_path: self.tls_client_key_path.or(defaults.tls_client_key_path),
+            tls_server_name: self.tls_server_name.or(defaults.tls_server_name),
+            tls_pinned_keys: self.tls_pinned_keys.or(defaults.tls_pinned_keys),
+            skip_tls_verification: self.skip_tls_verification.or(defaults.skip_tls_verification),
+            timeout: self.timeout.or(defaults.timeout),
+            connect_timeout: self.connect_timeout.or(defaults.connect_timeout),
+            nameserver: self.nameserver.or(defaults.nameserver),
+            dns_refresh_interval: self.dns_refresh_interval.or(defaults.dns_refresh_interval),
+            pool_idle_timeout: self.pool_idle_timeout.or(defaults.pool_idle_timeout),
+            pool_max_idle_per_host: self.pool_max_idle_per_host.or(defaults.pool_max_idle_per_host),
+            tcp_keepalive: self.tcp_keepalive.or(defaults.tcp_keepalive),
+            http2: self.http2.or(defaults.http2),
+            method_timeouts: self.method_timeouts.or(defaults.method_timeouts),
+            retries: self.retries.or(defaults.retries),
+            retry_delay: self.retry_delay.or(defaults.retry_delay),
+            retry_jitter: self.retry_jitter.or(defaults.retry_jitter),
+            circuit_breaker_failures: self.circuit_breaker_failures
+                .or(defaults.circuit_breaker_failures),
+            circuit_breaker_cooldown: self.circuit_breaker_cooldown
+                .or(defaults.circuit_breaker_cooldown),
+            max_concurrent_calls: self.max_concurrent_calls.or(defaults.max_concurrent_calls),
+            max_response_size: self.max_response_size.or(defaults.max_response_size),
+            accept_compression: self.accept_compression.or(defaults.accept_compression),
+            user_agent: self.user_agent.or(defaults.user_agent),
+            headers: self.headers.or(defaults.headers),
+            zmq_url: self.zmq_url.or(defaults.zmq_url),
+            p2p_address: self.p2p_address.or(defaults.p2p_address),
+            p2p_handshake: self.p2p_handshake.or(defaults.p2p_handshake),
+            p2p_survey_peers: self.p2p_survey_peers.or(defaults.p2p_survey_peers),
+            p2p_survey_interval: self.p2p_survey_interval.or(defaults.p2p_survey_interval),
+            onion_address: self.onion_address.or(defaults.onion_address),
+            onion_proxy: self.onion_proxy.or(defaults.onion_proxy),
+            onion_check_interval: self.onion_check_interval.or(defaults.onion_check_interval),
+            onion_timeout: self.onion_timeout.or(defaults.onion_timeout),
+            port_checker: self.port_checker.or(defaults.port_checker),
+            public_ports: self.public_ports.or(defaults.public_ports),
+            port_check_interval: self.port_check_interval.or(defaults.port_check_interval),
+            log_path: self.log_path.or(defaults.log_path),
+            log_patterns: self.log_patterns.or(defaults.log_patterns),
+            latency_buckets: self.latency_buckets.or(defaults.latency_buckets),
+            disabled_collectors: self.disabled_collectors.or(defaults.disabled_collectors),
+        }
+    }
+
+    // the settings set in the overrides take the place of those of the target, but its name
+    fn overridden(self, overrides: &MonerodSettings) -> MonerodSettings {
+        let name = self.name.clone();
+        MonerodSettings { name, ..overrides.clone().inherit(&self) }
+    }
 }
 
 impl TryInto<MonerodConfig> for MonerodSettings {
@@ -119,12 +646,89 @@ impl TryInto<MonerodConfig> for MonerodSettings {
 
         let base_url = self.base_url.unwrap_or(default.base_url);
 
+        let name = match self.name.filter(|name| !name.is_empty()) {
+            None => url_name(&base_url),
+            Some(name) => name,
+        };
+
+        // an empty setting overrides an inherited one
+        let rpc_username = self.rpc_username.filter(|username| !username.is_empty());
+        let rpc_password = self.rpc_password.filter(|password| !password.is_empty());
+        let rpc_login_file = parse_path(
+            None,
+            MonerodSettingsError::InvalidRpcLoginFile,
+            self.rpc_login_file,
+        )?;
+        let rpc_login = match (rpc_username, rpc_password, rpc_login_file) {
+            (None, None, None) => default.rpc_login,
+            (Some(username), Some(password), None) => Some(RpcLoginConfig { username, password }),
+            // in the format of the --rpc-login of monerod
+            (None, None, Some(path)) => {
+                let login = fs::read_to_string(path)
+                    .map_err(|_| MonerodSettingsError::InvalidRpcLoginFile)?;
+                let (username, password) = login.trim_end_matches(&['\r', '\n'][..]).split_once(':')
+                    .filter(|(username, password)| !username.is_empty() && !password.is_empty())
+                    .ok_or(MonerodSettingsError::InvalidRpcLoginFile)?;
+                Some(RpcLoginConfig { username: username.to_string(), password: password.to_string() })
+            },
+            _ => return Err(MonerodSettingsError::InvalidRpcLogin),
+        };
+
+        let proxy = match self.proxy.as_deref() {
+            None | Some("") => default.proxy,
+            Some(proxy) => {
+                let url = reqwest::Url::parse(proxy).map_err(|_| MonerodSettingsError::InvalidProxy)?;
+                let valid = match url.scheme() {
+                    "http" | "https" => true,
+                    _ => SocksProxy::from_url(&url).is_some(),
+                };
+                if !valid {
+                    return Err(MonerodSettingsError::InvalidProxy);
+                }
+                Some(proxy.to_string())
+            },
+        };
+
+        let env_proxy = self.env_proxy.unwrap_or(default.env_proxy);
+
         let tls_cert_path = parse_path(
             default.tls_cert_path,
             MonerodSettingsError::InvalidTlsCertPath,
             self.tls_cert_path,
         )?;
 
+        let tls_client_cert_path = parse_path(
+            default.tls_client_cert_path,
+            MonerodSettingsError::InvalidTlsClientCertPath,
+            self.tls_client_cert_path,
+        )?;
+
+        let tls_client_key_path = parse_path(
+            default.tls_client_key_path,
+            MonerodSettingsError::InvalidTlsClientKeyPath,
+            self.tls_client_key_path,
+        )?;
+        if tls_client_cert_path.is_some() != tls_client_key_path.is_some() {
+            return Err(MonerodSettingsError::IncompleteTlsClientIdentity);
+        }
+
+        let tls_server_name = match self.tls_server_name.as_deref() {
+            None => default.tls_server_name,
+            Some("") => None,
+            Some(server_name) => Some(server_name.to_string()),
+        };
+
+        let tls_pinned_keys = match self.tls_pinned_keys {
+            None => default.tls_pinned_keys,
+            Some(pins) => pins
+                .split_terminator(',')
+                .map(str::trim)
+                .filter(|pin| !pin.is_empty())
+                .map(parse_pin)
+                .collect::<Option<Vec<_>>>()
+                .ok_or(MonerodSettingsError::InvalidTlsPinnedKeys)?,
+        };
+
         let skip_tls_verification = self.skip_tls_verification
             .unwrap_or(default.skip_tls_verification);
 
@@ -134,48 +738,635 @@ impl TryInto<MonerodConfig> for MonerodSettings {
                 .map_err(|_| MonerodSettingsError::InvalidTimeout)?,
         };
 
+        let connect_timeout = match self.connect_timeout.as_deref() {
+            None => default.connect_timeout,
+            Some("") => None,
+            Some(timeout) => Some(parse_duration(timeout)
+                .map_err(|_| MonerodSettingsError::InvalidConnectTimeout)?),
+        };
+
+        let nameserver = match self.nameserver.as_deref() {
+            None => default.nameserver,
+            Some("") => None,
+            Some(nameserver) => Some(parse_nameserver(nameserver)
+                .ok_or(MonerodSettingsError::InvalidNameserver)?),
+        };
+
+        let dns_refresh_interval = match self.dns_refresh_interval.as_deref() {
+            None => default.dns_refresh_interval,
+            Some("") => None,
+            Some(interval) => Some(parse_duration(interval).ok()
+                .filter(|interval| *interval > Duration::from_secs(0))
+                .ok_or(MonerodSettingsError::InvalidDnsRefreshInterval)?),
+        };
+
+        let pool_idle_timeout = match self.pool_idle_timeout.as_deref() {
+            None => default.pool_idle_timeout,
+            Some("") => None,
+            Some(timeout) => Some(parse_duration(timeout)
+                .map_err(|_| MonerodSettingsError::InvalidPoolIdleTimeout)?),
+        };
+
+        let pool_max_idle_per_host = self.pool_max_idle_per_host.or(default.pool_max_idle_per_host);
+
+        let tcp_keepalive = match self.tcp_keepalive.as_deref() {
+            None => default.tcp_keepalive,
+            Some("") => None,
+            Some(keepalive) => Some(parse_duration(keepalive).ok()
+                .filter(|keepalive| *keepalive > Duration::from_secs(0))
+                .ok_or(MonerodSettingsError::InvalidTcpKeepalive)?),
+        };
+
+        let http2 = self.http2.unwrap_or(default.http2);
+
+        let method_timeouts = match self.method_timeouts {
+            None => default.method_timeouts,
+            Some(timeouts) => timeouts
+                .split_terminator(',')
+                .map(str::trim)
+                .filter(|timeout| !timeout.is_empty())
+                .map(|timeout| {
+                    let (method, timeout) = timeout.split_once('=')?;
+                    Some((method.trim().to_string(), parse_duration(timeout.trim()).ok()?))
+                })
+                .collect::<Option<BTreeMap<_, _>>>()
+                .ok_or(MonerodSettingsError::InvalidMethodTimeouts)?,
+        };
+
+        let retries = self.retries.unwrap_or(default.retries);
+
+        let retry_delay = match self.retry_delay {
+            None => default.retry_delay,
+            Some(delay) => parse_duration(&delay)
+                .map_err(|_| MonerodSettingsError::InvalidRetryDelay)?,
+        };
+
+        let retry_jitter = match self.retry_jitter {
+            None => default.retry_jitter,
+            Some(jitter) if (0.0..=1.0).contains(&jitter) => jitter,
+            Some(_) => return Err(MonerodSettingsError::InvalidRetryJitter),
+        };
+
+        let circuit_breaker_failures = self.circuit_breaker_failures
+            .unwrap_or(default.circuit_breaker_failures);
+
+        let circuit_breaker_cooldown = match self.circuit_breaker_cooldown {
+            None => default.circuit_breaker_cooldown,
+            Some(cooldown) => parse_duration(&cooldown)
+                .map_err(|_| MonerodSettingsError::InvalidCircuitBreakerCooldown)?,
+        };
+
+        let max_concurrent_calls = self.max_concurrent_calls.unwrap_or(default.max_concurrent_calls);
+
+        let max_response_size = self.max_response_size.unwrap_or(default.max_response_size);
+
+        let accept_compression = self.accept_compression.unwrap_or(default.accept_compression);
+
+        let user_agent = match self.user_agent.as_deref() {
+            None => default.user_agent,
+            Some("") => None,
+            Some(user_agent) => match HeaderValue::from_str(user_agent) {
+                Ok(_) => Some(user_agent.to_string()),
+                Err(_) => return Err(MonerodSettingsError::InvalidUserAgent),
+            },
+        };
+
+        let headers = match self.headers {
+            None => default.headers,
+            Some(headers) => headers
+                .split_terminator(',')
+                .map(str::trim)
+                .filter(|header| !header.is_empty())
+                .map(|header| {
+                    let (name, value) = header.split_once('=')?;
+                    let (name, value) = (name.trim(), value.trim());
+                    HeaderName::from_bytes(name.as_bytes()).ok()?;
+                    HeaderValue::from_str(value).ok()?;
+                    Some((name.to_string(), value.to_string()))
+                })
+                .collect::<Option<BTreeMap<_, _>>>()
+                .ok_or(MonerodSettingsError::InvalidHeaders)?,
+        };
+
+        let zmq_url = match self.zmq_url.as_deref() {
+            None => default.zmq_url,
+            Some("") => None,
+            Some(url) => match zmq::parse_url(url) {
+                Some(_) => Some(url.to_string()),
+                None => return Err(MonerodSettingsError::InvalidZmqUrl),
+            },
+        };
+
+        let p2p_address = match self.p2p_address.as_deref() {
+            None => default.p2p_address,
+            Some("") => None,
+            Some(address) => match parse_address(address, P2P_PORT) {
+                Some(_) => Some(address.to_string()),
+                None => return Err(MonerodSettingsError::InvalidP2pAddress),
+            },
+        };
+
+        let p2p_handshake = self.p2p_handshake.unwrap_or(default.p2p_handshake);
+
+        let p2p_survey_peers = self.p2p_survey_peers.unwrap_or(default.p2p_survey_peers);
+
+        let p2p_survey_interval = match self.p2p_survey_interval {
+            None => default.p2p_survey_interval,
+            Some(interval) => parse_duration(&interval)
+                .map_err(|_| MonerodSettingsError::InvalidP2pSurveyInterval)?,
+        };
+
+        let onion_address = match self.onion_address.as_deref() {
+            None => default.onion_address,
+            Some("") => None,
+            Some(address) => match parse_address(address, P2P_PORT) {
+                Some((host, _)) if host.ends_with(".onion") => Some(address.to_string()),
+                _ => return Err(MonerodSettingsError::InvalidOnionAddress),
+            },
+        };
+
+        // only a proxy which resolves the target itself can get to onion services
+        let onion_proxy = self.onion_proxy.filter(|proxy| !proxy.is_empty()).or_else(|| proxy.clone());
+        let onion_proxy = match onion_proxy {
+            _ if onion_address.is_none() => None,
+            Some(onion_proxy) => {
+                let url = reqwest::Url::parse(&onion_proxy)
+                    .map_err(|_| MonerodSettingsError::InvalidOnionProxy)?;
+                if url.scheme() != "socks5h" || SocksProxy::from_url(&url).is_none() {
+                    return Err(MonerodSettingsError::InvalidOnionProxy);
+                }
+                Some(onion_proxy)
+            },
+            None => return Err(MonerodSettingsError::InvalidOnionProxy),
+        };
+
+        let onion_check_interval = match self.onion_check_interval {
+            None => default.onion_check_interval,
+            Some(interval) => parse_duration(&interval)
+                .map_err(|_| MonerodSettingsError::InvalidOnionCheckInterval)?,
+        };
+
+        let onion_timeout = match self.onion_timeout {
+            None => default.onion_timeout,
+            Some(timeout) => parse_duration(&timeout)
+                .map_err(|_| MonerodSettingsError::InvalidOnionTimeout)?,
+        };
+
+        let port_checker = match self.port_checker.as_deref() {
+            None => default.port_checker,
+            Some("") => None,
+            Some(url) => match reqwest::Url::parse(url) {
+                Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => Some(url.to_string()),
+                _ => return Err(MonerodSettingsError::InvalidPortChecker),
+            },
+        };
+
+        let public_ports = match self.public_ports {
+            None => default.public_ports,
+            Some(addresses) => addresses
+                .split_terminator(',')
+                .map(str::trim)
+                .filter(|address| !address.is_empty())
+                .map(|address| match parse_address(address, 0) {
+                    Some((_, port)) if port != 0 => Ok(address.to_string()),
+                    _ => Err(MonerodSettingsError::InvalidPublicPorts),
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+        };
+        // the port is the label of the metric
+        let ports = public_ports.iter()
+            .filter_map(|address| parse_address(address, 0))
+            .map(|(_, port)| port)
+            .collect::<BTreeSet<_>>();
+        if ports.len() != public_ports.len() {
+            return Err(MonerodSettingsError::InvalidPublicPorts);
+        }
+        if port_checker.is_some() == public_ports.is_empty() {
+            return Err(MonerodSettingsError::IncompletePortChecks);
+        }
+
+        let port_check_interval = match self.port_check_interval {
+            None => default.port_check_interval,
+            Some(interval) => parse_duration(&interval)
+                .map_err(|_| MonerodSettingsError::InvalidPortCheckInterval)?,
+        };
+
+        // the log may only be created once the daemon starts
+        let log_path = match self.log_path.as_deref() {
+            None | Some("") => default.log_path,
+            Some(path) => Some(PathBuf::from(path)),
+        };
+
+        let log_patterns = match self.log_patterns {
+            None => default.log_patterns,
+            Some(patterns) => {
+                for (name, pattern) in &patterns {
+                    if name.is_empty() || Regex::new(pattern).is_err() {
+                        return Err(MonerodSettingsError::InvalidLogPattern(name.clone()));
+                    }
+                }
+                patterns
+            },
+        };
+
+        let latency_buckets = match self.latency_buckets {
+            None => default.latency_buckets,
+            Some(buckets) => buckets
+                .split_terminator(',')
+                .map(|b| b.trim().parse::<f64>().ok().filter(|b| b.is_finite()))
+                .collect::<Option<Vec<f64>>>()
+                .ok_or(MonerodSettingsError::InvalidLatencyBuckets)?,
+        };
+
+        let disabled_collectors = match self.disabled_collectors {
+            None => default.disabled_collectors,
+            Some(collectors) => collectors
+                .split_terminator(',')
+                .map(str::trim)
+                .filter(|collector| !collector.is_empty())
+                .map(|collector| match COLLECTORS.contains(&collector) {
+                    true => Ok(collector.to_string()),
+                    false => Err(MonerodSettingsError::UnknownCollector(collector.to_string())),
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+        };
+
         Ok(MonerodConfig {
+            name,
             base_url,
+            rpc_login,
+            proxy,
+            env_proxy,
             tls_cert_path,
+            tls_client_cert_path,
+            tls_client_key_path,
+            tls_server_name,
+            tls_pinned_keys,
             skip_tls_verification,
             timeout,
+            connect_timeout,
+            nameserver,
+            dns_refresh_interval,
+            pool_idle_timeout,
+            pool_max_idle_per_host,
+            tcp_keepalive,
+            http2,
+            method_timeouts,
+            retries,
+            retry_delay,
+            retry_jitter,
+            circuit_breaker_failures,
+            circuit_breaker_cooldown,
+            max_concurrent_calls,
+            max_response_size,
+            accept_compression,
+            user_agent,
+            headers,
+            zmq_url,
+            p2p_address,
+            p2p_handshake,
+            p2p_survey_peers,
+            p2p_survey_interval,
+            onion_address,
+            onion_proxy,
+            onion_check_interval,
+            onion_timeout,
+            port_checker,
+            public_ports,
+            port_check_interval,
+            log_path,
+            log_patterns,
+            latency_buckets,
+            disabled_collectors,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GraphiteConfig {
+    // host:port of carbon
+    pub address: String,
+    // dot-separated nodes the paths start with, none if empty
+    pub prefix: String,
+    pub interval: Duration,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GraphiteSettings {
+    pub address: Option<String>,
+    pub prefix: Option<String>,
+    pub interval: Option<String>,
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, Debug)]
+pub enum GraphiteSettingsError {
+    InvalidAddress,
+    InvalidPrefix,
+    InvalidInterval,
+}
+
+impl GraphiteSettings {
+    // None if the pushes are disabled
+    fn try_into_config(self) -> Result<Option<GraphiteConfig>, GraphiteSettingsError> {
+        let address = match self.address.filter(|address| !address.is_empty()) {
+            None => return Ok(None),
+            Some(address) => {
+                let (host, port) = parse_address(&address, GRAPHITE_PORT)
+                    .ok_or(GraphiteSettingsError::InvalidAddress)?;
+                match host.contains(':') {
+                    true => format!("[{}]:{}", host, port),
+                    false => format!("{}:{}", host, port),
+                }
+            },
+        };
+
+        let prefix = self.prefix.unwrap_or_default();
+        let is_valid_node = |node: &str| {
+            !node.is_empty() && node.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        };
+        if !prefix.is_empty() && !prefix.split('.').all(is_valid_node) {
+            return Err(GraphiteSettingsError::InvalidPrefix);
+        }
+
+        let interval = match self.interval {
+            None => Duration::from_secs(60),
+            Some(interval) => parse_duration(&interval)
+                .ok()
+                .filter(|interval| *interval > Duration::from_secs(0))
+                .ok_or(GraphiteSettingsError::InvalidInterval)?,
+        };
+
+        Ok(Some(GraphiteConfig { address, prefix, interval }))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DiscoveryConfig {
+    pub srv_name: String,
+    pub scheme: String,
+    pub interval: Duration,
+    // the nameservers of the system if none
+    pub nameserver: Option<SocketAddr>,
+    // every discovered target is configured like this one, except the name and URL
+    pub target: MonerodConfig,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DiscoverySettings {
+    pub srv_name: Option<String>,
+    pub scheme: Option<String>,
+    pub interval: Option<String>,
+    pub nameserver: Option<String>,
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, Debug)]
+pub enum DiscoverySettingsError {
+    InvalidSrvName,
+    InvalidScheme,
+    InvalidInterval,
+    InvalidNameserver,
+}
+
+impl DiscoverySettings {
+    // None if discovery is disabled
+    fn try_into_config(
+        self,
+        target: MonerodConfig,
+    ) -> Result<Option<DiscoveryConfig>, DiscoverySettingsError> {
+        let srv_name = match self.srv_name.filter(|name| !name.is_empty()) {
+            None => return Ok(None),
+            Some(name) => name,
+        };
+        if !is_valid_name(&srv_name) {
+            return Err(DiscoverySettingsError::InvalidSrvName);
+        }
+
+        let scheme = match self.scheme.as_deref() {
+            None => "http".to_string(),
+            Some(scheme @ "http") | Some(scheme @ "https") => scheme.to_string(),
+            Some(_) => return Err(DiscoverySettingsError::InvalidScheme),
+        };
+
+        let interval = match self.interval {
+            None => Duration::from_secs(60),
+            Some(interval) => parse_duration(&interval)
+                .ok()
+                .filter(|interval| *interval > Duration::from_secs(0))
+                .ok_or(DiscoverySettingsError::InvalidInterval)?,
+        };
+
+        let nameserver = match self.nameserver.as_deref() {
+            None | Some("") => None,
+            Some(nameserver) => Some(parse_nameserver(nameserver)
+                .ok_or(DiscoverySettingsError::InvalidNameserver)?),
+        };
+
+        Ok(Some(DiscoveryConfig {
+            srv_name,
+            scheme,
+            interval,
+            nameserver,
+            target,
+        }))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RewriteRuleSettings {
+    pub metric: Option<String>,
+    pub rename: Option<String>,
+    pub drop_labels: Option<String>,
+    pub label: Option<String>,
+    pub label_values: Option<BTreeMap<String, String>>,
+}
+
+#[derive(Clone, Debug)]
+pub enum RewriteRuleSettingsError {
+    InvalidRename,
+    InvalidDropLabels,
+    MissingLabel,
+    NoRewrite,
+}
+
+impl TryInto<RewriteRule> for RewriteRuleSettings {
+    type Error = RewriteRuleSettingsError;
+
+    fn try_into(self) -> Result<RewriteRule, Self::Error> {
+        let rename = self.rename.filter(|name| !name.is_empty());
+        if !rename.as_deref().map(is_valid_metric_name).unwrap_or(true) {
+            return Err(RewriteRuleSettingsError::InvalidRename);
+        }
+
+        let drop_labels = self.drop_labels.as_deref().unwrap_or("")
+            .split_terminator(',')
+            .map(|label| label.trim().to_string())
+            .collect::<Vec<_>>();
+        // histograms can't be exported without their buckets
+        if drop_labels.iter().any(|label| label.is_empty() || label == "le") {
+            return Err(RewriteRuleSettingsError::InvalidDropLabels);
+        }
+
+        let label = self.label.filter(|label| !label.is_empty());
+        let label_values = self.label_values.unwrap_or_default();
+        if label.is_none() && !label_values.is_empty() {
+            return Err(RewriteRuleSettingsError::MissingLabel);
+        }
+
+        if rename.is_none() && drop_labels.is_empty() && label_values.is_empty() {
+            return Err(RewriteRuleSettingsError::NoRewrite);
+        }
+
+        Ok(RewriteRule {
+            metric: self.metric.filter(|metric| !metric.is_empty()),
+            rename,
+            drop_labels,
+            label,
+            label_values,
         })
     }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
+    pub namespace: String,
+    pub normalize_names: bool,
+    pub xmr_units: bool,
     pub refresh_interval: Duration,
+    // percentage of the refresh interval
+    pub refresh_jitter: u32,
+    pub max_refresh_backoff: Option<Duration>,
+    // on blocks published over ZMQ, besides the refresh interval
+    pub refresh_on_block: bool,
+    pub scrape_mode: ScrapeMode,
+    pub scrape_timeout: Duration,
     pub block_spans: Vec<u32>,
+    pub empty_spans: EmptySpanPolicy,
+    pub serve_on_failure: bool,
+    pub max_metrics_age: Option<Duration>,
+    pub stale_metrics: StalePolicy,
+    pub sample_timestamps: bool,
+    pub watchdog_factor: u32,
+    pub state_path: Option<PathBuf>,
+    pub labels: BTreeMap<String, String>,
+    pub rewrite: Vec<RewriteRule>,
+    // gauges computed from the exported metrics, by name
+    pub derived: Vec<(String, Expression)>,
+    pub systemd_ready: ReadyOn,
+    // base URLs of the nodes the targets' heights are compared to
+    pub reference_nodes: Vec<String>,
+    pub reference_timeout: Duration,
     pub server: ServerConfig,
-    pub monerod: MonerodConfig,
+    // at least one
+    pub monerod: Vec<MonerodConfig>,
+    pub discovery: Option<DiscoveryConfig>,
+    pub graphite: Option<GraphiteConfig>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
+            namespace: "monero".into(),
+            normalize_names: false,
+            xmr_units: false,
             refresh_interval: Duration::from_secs(15),
+            refresh_jitter: 0,
+            max_refresh_backoff: None,
+            refresh_on_block: true,
+            scrape_mode: ScrapeMode::Interval,
+            scrape_timeout: Duration::from_secs(10),
             block_spans: vec![30, 180, 720],
+            empty_spans: EmptySpanPolicy::Omit,
+            serve_on_failure: false,
+            max_metrics_age: None,
+            stale_metrics: StalePolicy::Fail,
+            sample_timestamps: false,
+            watchdog_factor: 4,
+            state_path: None,
+            labels: BTreeMap::new(),
+            rewrite: Vec::new(),
+            derived: Vec::new(),
+            systemd_ready: ReadyOn::Bind,
+            reference_nodes: Vec::new(),
+            reference_timeout: Duration::from_secs(10),
             server: ServerConfig::default(),
-            monerod: MonerodConfig::default(),
+            monerod: vec![MonerodConfig::default()],
+            discovery: None,
+            graphite: None,
         }
     }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Settings {
+    pub namespace: Option<String>,
+    pub normalize_names: Option<bool>,
+    pub xmr_units: Option<bool>,
     pub refresh_interval: Option<String>,
+    pub refresh_jitter: Option<u32>,
+    pub max_refresh_backoff: Option<String>,
+    pub refresh_on_block: Option<bool>,
+    pub scrape_mode: Option<String>,
+    pub scrape_timeout: Option<String>,
     pub block_spans: Option<String>,
+    pub empty_spans: Option<String>,
+    pub serve_on_failure: Option<bool>,
+    pub max_metrics_age: Option<String>,
+    pub stale_metrics: Option<String>,
+    pub sample_timestamps: Option<bool>,
+    pub watchdog_factor: Option<u32>,
+    pub state_path: Option<String>,
+    pub labels: Option<BTreeMap<String, String>>,
+    pub rewrite: Option<Vec<RewriteRuleSettings>>,
+    pub derived: Option<BTreeMap<String, String>>,
+    pub systemd_ready: Option<String>,
+    pub reference_nodes: Option<String>,
+    pub reference_timeout: Option<String>,
     pub server: Option<ServerSettings>,
-    pub monerod: Option<MonerodSettings>,
+    pub monerod_defaults: Option<MonerodSettings>,
+    pub monerod: Option<MonerodTargetsSettings>,
+    // the --monerod-* flags given along with several [[monerod]] targets, which they override
+    #[serde(skip)]
+    pub monerod_overrides: Option<MonerodSettings>,
+    pub discovery: Option<DiscoverySettings>,
+    pub graphite: Option<GraphiteSettings>,
+}
+
+// either a single [monerod] table or several [[monerod]] tables
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum MonerodTargetsSettings {
+    One(Box<MonerodSettings>),
+    Many(Vec<MonerodSettings>),
 }
 
 #[derive(Debug)]
 pub enum SettingsError {
+    InvalidNamespace,
     InvalidRefreshInterval,
+    InvalidRefreshJitter,
+    InvalidMaxRefreshBackoff,
+    InvalidScrapeMode,
+    InvalidScrapeTimeout,
     InvalidBlockSpans,
+    InvalidEmptySpans,
+    InvalidMaxMetricsAge,
+    InvalidStaleMetrics,
+    // index of the rule in the config
+    RewriteRuleSettings(usize, RewriteRuleSettingsError),
+    InvalidDerivedMetricName(String),
+    InvalidDerivedMetric(String, ParseError),
+    InvalidSystemdReady,
+    InvalidReferenceTimeout,
     ServerSettings(ServerSettingsError),
-    MonerodSettings(MonerodSettingsError),
+    // index of the target when there are several
+    MonerodSettings(Option<usize>, MonerodSettingsError),
+    NoMonerodTargets,
+    DuplicateMonerodName(String),
+    InvalidMonerodDefaults(MonerodSettingsError),
+    DiscoverySettings(DiscoverySettingsError),
+    GraphiteSettings(GraphiteSettingsError),
 }
 
 impl TryInto<Config> for Settings {
@@ -184,12 +1375,57 @@ impl TryInto<Config> for Settings {
     fn try_into(self) -> Result<Config, Self::Error> {
         let default = Config::default();
 
+        let namespace = match self.namespace.as_deref() {
+            None => default.namespace,
+            Some(namespace) => {
+                if !is_valid_metric_name(namespace) {
+                    return Err(SettingsError::InvalidNamespace);
+                }
+                namespace.to_string()
+            },
+        };
+
+        let normalize_names = self.normalize_names.unwrap_or(default.normalize_names);
+
+        let xmr_units = self.xmr_units.unwrap_or(default.xmr_units);
+
         let refresh_interval = match self.refresh_interval {
             None => default.refresh_interval,
             Some(interval) => parse_duration(&interval)
                 .map_err(|_| SettingsError::InvalidRefreshInterval)?,
         };
 
+        let refresh_jitter = match self.refresh_jitter {
+            None => default.refresh_jitter,
+            Some(jitter) if jitter <= 100 => jitter,
+            Some(_) => return Err(SettingsError::InvalidRefreshJitter),
+        };
+
+        let max_refresh_backoff = match self.max_refresh_backoff.as_deref() {
+            None => default.max_refresh_backoff,
+            Some("") => None,
+            Some(backoff) => Some(parse_duration(backoff)
+                .map_err(|_| SettingsError::InvalidMaxRefreshBackoff)?),
+        };
+
+        let refresh_on_block = self.refresh_on_block.unwrap_or(default.refresh_on_block);
+
+        let scrape_mode = match self.scrape_mode.as_deref() {
+            None => default.scrape_mode,
+            Some("interval") => ScrapeMode::Interval,
+            Some("pull") => ScrapeMode::Pull,
+            Some("revalidate") => ScrapeMode::Revalidate,
+            Some(_) => return Err(SettingsError::InvalidScrapeMode),
+        };
+
+        let scrape_timeout = match self.scrape_timeout {
+            None => default.scrape_timeout,
+            Some(timeout) => parse_duration(&timeout)
+                .ok()
+                .filter(|timeout| *timeout > Duration::from_secs(0))
+                .ok_or(SettingsError::InvalidScrapeTimeout)?,
+        };
+
         let block_spans = match self.block_spans {
             None => default.block_spans,
             Some(spans) => spans
@@ -199,33 +1435,205 @@ impl TryInto<Config> for Settings {
                 .map_err(|_| SettingsError::InvalidBlockSpans)?,
         };
 
+        let empty_spans = match self.empty_spans.as_deref() {
+            None => default.empty_spans,
+            Some("omit") => EmptySpanPolicy::Omit,
+            Some("zero") => EmptySpanPolicy::Zero,
+            Some(_) => return Err(SettingsError::InvalidEmptySpans),
+        };
+
+        let serve_on_failure = self.serve_on_failure.unwrap_or(default.serve_on_failure);
+
+        let max_metrics_age = match self.max_metrics_age.as_deref() {
+            None | Some("") => default.max_metrics_age,
+            Some(age) => Some(parse_duration(age).map_err(|_| SettingsError::InvalidMaxMetricsAge)?),
+        };
+
+        let stale_metrics = match self.stale_metrics.as_deref() {
+            None => default.stale_metrics,
+            Some("fail") => StalePolicy::Fail,
+            Some("serve") => StalePolicy::Serve,
+            Some(_) => return Err(SettingsError::InvalidStaleMetrics),
+        };
+
+        let sample_timestamps = self.sample_timestamps.unwrap_or(default.sample_timestamps);
+
+        let watchdog_factor = self.watchdog_factor.unwrap_or(default.watchdog_factor);
+
+        // the state file is created on the first refresh, so it doesn't have to exist yet
+        let state_path = match self.state_path.as_deref() {
+            None | Some("") => default.state_path,
+            Some(path) => Some(PathBuf::from(path)),
+        };
+
+        let labels = self.labels.unwrap_or(default.labels);
+
+        let rewrite = match self.rewrite {
+            None => default.rewrite,
+            Some(rules) => rules.into_iter()
+                .enumerate()
+                .map(|(i, rule)| rule.try_into().map_err(|e| SettingsError::RewriteRuleSettings(i, e)))
+                .collect::<Result<Vec<RewriteRule>, _>>()?,
+        };
+
+        let derived = match self.derived {
+            None => default.derived,
+            Some(derived) => derived.into_iter()
+                .map(|(name, expression)| {
+                    if !is_valid_metric_name(&name) {
+                        return Err(SettingsError::InvalidDerivedMetricName(name));
+                    }
+                    match Expression::parse(&expression) {
+                        Ok(expression) => Ok((name, expression)),
+                        Err(e) => Err(SettingsError::InvalidDerivedMetric(name, e)),
+                    }
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+        };
+
+        let systemd_ready = match self.systemd_ready.as_deref() {
+            None => default.systemd_ready,
+            Some("bind") => ReadyOn::Bind,
+            Some("export") => ReadyOn::Export,
+            Some(_) => return Err(SettingsError::InvalidSystemdReady),
+        };
+
+        let reference_nodes = match self.reference_nodes {
+            None => default.reference_nodes,
+            Some(urls) => urls.split_terminator(',')
+                .map(str::trim)
+                .filter(|url| !url.is_empty())
+                .map(String::from)
+                .collect(),
+        };
+
+        let reference_timeout = match self.reference_timeout {
+            None => default.reference_timeout,
+            Some(timeout) => parse_duration(&timeout)
+                .map_err(|_| SettingsError::InvalidReferenceTimeout)?,
+        };
+
         let server = match self.server {
             None => ServerConfig::default(),
             Some(server) => server.try_into().map_err(SettingsError::ServerSettings)?,
         };
 
+        let defaults = self.monerod_defaults.unwrap_or_default();
+
+        let discovery = match self.discovery {
+            None => None,
+            Some(discovery) => {
+                let target = defaults.clone().try_into()
+                    .map_err(SettingsError::InvalidMonerodDefaults)?;
+                discovery.try_into_config(target).map_err(SettingsError::DiscoverySettings)?
+            },
+        };
+
+        // the discovered targets take the place of the default one
         let monerod = match self.monerod {
-            None => MonerodConfig::default(),
-            Some(monerod) => monerod.try_into().map_err(SettingsError::MonerodSettings)?,
+            None if discovery.is_some() => Vec::new(),
+            None => vec![
+                MonerodSettings::default().inherit(&defaults).try_into()
+                    .map_err(|e| SettingsError::MonerodSettings(None, e))?,
+            ],
+            Some(MonerodTargetsSettings::One(monerod)) => vec![
+                (*monerod).inherit(&defaults).try_into()
+                    .map_err(|e| SettingsError::MonerodSettings(None, e))?,
+            ],
+            Some(MonerodTargetsSettings::Many(targets)) => {
+                let overrides = self.monerod_overrides.unwrap_or_default();
+                targets.into_iter()
+                    .enumerate()
+                    .map(|(i, monerod)| {
+                        monerod.inherit(&defaults).overridden(&overrides).try_into()
+                            .map_err(|e| SettingsError::MonerodSettings(Some(i), e))
+                    })
+                    .collect::<Result<Vec<MonerodConfig>, _>>()?
+            },
+        };
+        if monerod.is_empty() && discovery.is_none() {
+            return Err(SettingsError::NoMonerodTargets);
+        }
+        // the names tell the samples of the targets apart
+        for (i, target) in monerod.iter().enumerate() {
+            if monerod[..i].iter().any(|other| other.name == target.name) {
+                return Err(SettingsError::DuplicateMonerodName(target.name.clone()));
+            }
+        }
+
+        let graphite = match self.graphite {
+            None => None,
+            Some(graphite) => graphite.try_into_config().map_err(SettingsError::GraphiteSettings)?,
         };
 
         Ok(Config {
+            namespace,
+            normalize_names,
+            xmr_units,
             refresh_interval,
+            refresh_jitter,
+            max_refresh_backoff,
+            refresh_on_block,
+            scrape_mode,
+            scrape_timeout,
             block_spans,
+            empty_spans,
+            serve_on_failure,
+            max_metrics_age,
+            stale_metrics,
+            sample_timestamps,
+            watchdog_factor,
+            state_path,
+            labels,
+            rewrite,
+            derived,
+            systemd_ready,
+            reference_nodes,
+            reference_timeout,
             server,
             monerod,
+            discovery,
+            graphite,
         })
     }
 }
 
 impl Settings {
-    pub fn load(config_path: Option<&str>) -> Result<Settings, config::ConfigError> {
+    // the overrides, by key, take the place of the environment, and the environment of the file
+    pub fn load(
+        config_path: Option<&str>,
+        overrides: &[(&str, &str)],
+    ) -> Result<Settings, config::ConfigError> {
         let mut cfg = config::Config::default();
         if let Some(config_path) = config_path {
             cfg.merge(File::new(config_path, FileFormat::Toml).required(false))?;
         }
         cfg.merge(Environment::with_prefix("MONEROD_EXPORTER").separator("__"))?;
-        cfg.try_into()
+        // the monerod overrides apply to each of several targets, except for the name and URL,
+        // which only one of them can have
+        let targets = cfg.get_array("monerod").is_ok();
+        let mut monerod_overrides: Option<config::Config> = None;
+        for (key, value) in overrides {
+            match key.strip_prefix("monerod.") {
+                Some("name") | Some("base_url") if targets => {
+                    return Err(config::ConfigError::Message(format!(
+                        "{} can't be overridden for several [[monerod]] targets",
+                        key,
+                    )));
+                },
+                Some(key) if targets => {
+                    monerod_overrides.get_or_insert_with(Default::default).set(key, *value)?;
+                },
+                _ => {
+                    cfg.set(key, *value)?;
+                },
+            }
+        }
+        let mut settings: Settings = cfg.try_into()?;
+        settings.monerod_overrides = monerod_overrides
+            .map(|overrides| overrides.try_into())
+            .transpose()?;
+        Ok(settings)
     }
 }
 
@@ -249,10 +1657,54 @@ impl fmt::Display for ConfigLoadError {
 }
 
 impl Config {
-    pub fn load(config_path: Option<&str>) -> Result<Config, ConfigLoadError> {
-        Settings::load(config_path)
+    pub fn load(
+        config_path: Option<&str>,
+        overrides: &[(&str, &str)],
+    ) -> Result<Config, ConfigLoadError> {
+        Settings::load(config_path, overrides)
             .map_err(ConfigLoadError::Load)?
             .try_into()
             .map_err(ConfigLoadError::Validation)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    #[test]
+    fn overrides_several_targets() {
+        let path = env::temp_dir().join(format!("monerod-exporter-{}.toml", process::id()));
+        fs::write(&path, "\
+[monerod_defaults]
+timeout = \"10s\"
+retries = 1
+
+[[monerod]]
+name = \"node1\"
+timeout = \"20s\"
+
+[[monerod]]
+name = \"node2\"
+rpc_username = \"user\"
+rpc_password = \"password\"
+").unwrap();
+        let load = |overrides: &[(&str, &str)]| Config::load(path.to_str(), overrides);
+
+        let config = load(&[("monerod.timeout", "5s")]).unwrap();
+        let timeouts = config.monerod.iter().map(|target| target.timeout).collect::<Vec<_>>();
+        assert_eq!(timeouts, vec![Duration::from_secs(5); 2]);
+        assert!(config.monerod.iter().all(|target| target.retries == 1));
+        assert_eq!(config.monerod[0].name, "node1");
+        assert_eq!(config.monerod[1].name, "node2");
+        assert_eq!(config.monerod[1].rpc_login.as_ref().unwrap().username, "user");
+
+        let config = load(&[]).unwrap();
+        assert_eq!(config.monerod[0].timeout, Duration::from_secs(20));
+        assert_eq!(config.monerod[1].timeout, Duration::from_secs(10));
+
+        assert!(load(&[("monerod.base_url", "http://127.0.0.1:18081")]).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
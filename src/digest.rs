@@ -0,0 +1,290 @@
+use reqwest::header::{HeaderMap, WWW_AUTHENTICATE};
+use std::{collections::BTreeMap, fmt::Write};
+
+use crate::{config::RpcLoginConfig, hex};
+
+// per-round shift amounts and constants of MD5, see RFC 1321
+const SHIFTS: [u32; 64] = [
+    7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22,
+    5, 9, 14, 20, 5, 9, 14, 20, 5, 9, 14, 20, 5, 9, 14, 20,
+    4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23,
+    6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21,
+];
+
+const CONSTANTS: [u32; 64] = [
+    0xd76aa478, 0xe8c7b756, 0x242070db, 0xc1bdceee, 0xf57c0faf, 0x4787c62a, 0xa8304613, 0xfd469501,
+    0x698098d8, 0x8b44f7af, 0xffff5bb1, 0x895cd7be, 0x6b901122, 0xfd987193, 0xa679438e, 0x49b40821,
+    0xf61e2562, 0xc040b340, 0x265e5a51, 0xe9b6c7aa, 0xd62f105d, 0x02441453, 0xd8a1e681, 0xe7d3fbc8,
+    0x21e1cde6, 0xc33707d6, 0xf4d50d87, 0x455a14ed, 0xa9e3e905, 0xfcefa3f8, 0x676f02d9, 0x8d2a4c8a,
+    0xfffa3942, 0x8771f681, 0x6d9d6122, 0xfde5380c, 0xa4beea44, 0x4bdecfa9, 0xf6bb4b60, 0xbebfbc70,
+    0x289b7ec6, 0xeaa127fa, 0xd4ef3085, 0x04881d05, 0xd9d4d039, 0xe6db99e5, 0x1fa27cf8, 0xc4ac5665,
+    0xf4292244, 0x432aff97, 0xab9423a7, 0xfc93a039, 0x655b59c3, 0x8f0ccc92, 0xffeff47d, 0x85845dd1,
+    0x6fa87e4f, 0xfe2ce6e0, 0xa3014314, 0x4e0811a1, 0xf7537e82, 0xbd3af235, 0x2ad7d2bb, 0xeb86d391,
+];
+
+// MD5 isn't fit for anything else, but it's the only algorithm monerod supports
+fn md5(data: &[u8]) -> [u8; 16] {
+    let mut state: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64).wrapping_mul(8)).to_le_bytes());
+
+    for block in message.chunks(64) {
+        let mut words = [0u32; 16];
+        for (word, bytes) in words.iter_mut().zip(block.chunks(4)) {
+            *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+
+        let [mut a, mut b, mut c, mut d] = state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let rotated = a.wrapping_add(f)
+                .wrapping_add(CONSTANTS[i])
+                .wrapping_add(words[g])
+                .rotate_left(SHIFTS[i]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(rotated);
+        }
+
+        for (word, value) in state.iter_mut().zip([a, b, c, d].iter()) {
+            *word = word.wrapping_add(*value);
+        }
+    }
+
+    let mut digest = [0; 16];
+    for (bytes, word) in digest.chunks_mut(4).zip(state.iter()) {
+        bytes.copy_from_slice(&word.to_le_bytes());
+    }
+    digest
+}
+
+fn md5_hex(data: &str) -> String {
+    hex::encode(&md5(data.as_bytes()))
+}
+
+// parameters of a challenge, with the quotes and escapes of quoted values removed
+fn parse_parameters(parameters: &str) -> BTreeMap<String, String> {
+    let mut parsed = BTreeMap::new();
+    let mut chars = parameters.chars().peekable();
+    loop {
+        while chars.peek().map(|c| c.is_whitespace() || *c == ',').unwrap_or(false) {
+            chars.next();
+        }
+        let name = chars.by_ref()
+            .take_while(|c| *c != '=')
+            .collect::<String>()
+            .trim()
+            .to_ascii_lowercase();
+        if name.is_empty() {
+            return parsed;
+        }
+
+        let mut value = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => value.extend(chars.next()),
+                    c => value.push(c),
+                }
+            }
+        } else {
+            value = chars.by_ref().take_while(|c| *c != ',').collect::<String>().trim().to_string();
+        }
+        parsed.insert(name, value);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Algorithm {
+    Md5,
+    Md5Sess,
+}
+
+#[derive(Clone, Debug)]
+pub struct DigestChallenge {
+    realm: String,
+    nonce: String,
+    opaque: Option<String>,
+    algorithm: Algorithm,
+    qop_auth: bool,
+}
+
+impl DigestChallenge {
+    fn parse(parameters: &str) -> Option<DigestChallenge> {
+        let parameters = parse_parameters(parameters);
+        let algorithm = match parameters.get("algorithm").map(|a| a.to_ascii_uppercase()).as_deref() {
+            None | Some("MD5") => Algorithm::Md5,
+            Some("MD5-SESS") => Algorithm::Md5Sess,
+            Some(_) => return None,
+        };
+        // without qop the legacy RFC 2069 digest is used
+        let qop_auth = match parameters.get("qop") {
+            None => false,
+            Some(qop) if qop.split(',').any(|qop| qop.trim() == "auth") => true,
+            Some(_) => return None,
+        };
+
+        Some(DigestChallenge {
+            realm: parameters.get("realm")?.clone(),
+            nonce: parameters.get("nonce")?.clone(),
+            opaque: parameters.get("opaque").cloned(),
+            algorithm,
+            qop_auth,
+        })
+    }
+
+    // the request digest of RFC 2617, or of RFC 2069 without qop
+    fn response(
+        &self,
+        login: &RpcLoginConfig,
+        method: &str,
+        uri: &str,
+        nc: &str,
+        cnonce: &str,
+    ) -> String {
+        let mut ha1 = md5_hex(&format!("{}:{}:{}", login.username, self.realm, login.password));
+        if self.algorithm == Algorithm::Md5Sess {
+            ha1 = md5_hex(&format!("{}:{}:{}", ha1, self.nonce, cnonce));
+        }
+        let ha2 = md5_hex(&format!("{}:{}", method, uri));
+        if self.qop_auth {
+            md5_hex(&format!("{}:{}:{}:{}:auth:{}", ha1, self.nonce, nc, cnonce, ha2))
+        } else {
+            md5_hex(&format!("{}:{}:{}", ha1, self.nonce, ha2))
+        }
+    }
+}
+
+// how the RPC login is sent, as the daemon asked for it
+#[derive(Debug)]
+pub enum Authentication {
+    Basic,
+    Digest {
+        challenge: DigestChallenge,
+        // requests made with the nonce of the challenge
+        nonce_count: u32,
+    },
+}
+
+impl Authentication {
+    // the first challenge that can be answered, daemons may offer several
+    pub fn from_headers(headers: &HeaderMap) -> Option<Authentication> {
+        headers.get_all(WWW_AUTHENTICATE).iter()
+            .filter_map(|header| header.to_str().ok())
+            .find_map(|header| {
+                let (scheme, parameters) = match header.trim().find(' ') {
+                    Some(i) => (&header.trim()[..i], &header.trim()[i + 1..]),
+                    None => (header.trim(), ""),
+                };
+                if scheme.eq_ignore_ascii_case("digest") {
+                    let challenge = DigestChallenge::parse(parameters)?;
+                    Some(Authentication::Digest { challenge, nonce_count: 0 })
+                } else if scheme.eq_ignore_ascii_case("basic") {
+                    Some(Authentication::Basic)
+                } else {
+                    None
+                }
+            })
+    }
+
+    // the Authorization header of a request, none if no client nonce could be generated
+    pub fn authorization(&mut self, login: &RpcLoginConfig, method: &str, uri: &str) -> Option<String> {
+        let (challenge, nonce_count) = match self {
+            Authentication::Basic => {
+                let credentials = format!("{}:{}", login.username, login.password);
+                return Some(format!("Basic {}", base64::encode(credentials)));
+            },
+            Authentication::Digest { challenge, nonce_count } => (challenge, nonce_count),
+        };
+
+        let mut cnonce = [0; 16];
+        getrandom::getrandom(&mut cnonce).ok()?;
+        let cnonce = hex::encode(&cnonce);
+        *nonce_count += 1;
+        let nc = format!("{:08x}", nonce_count);
+
+        let response = challenge.response(login, method, uri, &nc, &cnonce);
+
+        let quote = |value: &str| value.replace('\\', "\\\\").replace('"', "\\\"");
+        let mut authorization = format!(
+            "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", response=\"{}\"",
+            quote(&login.username),
+            quote(&challenge.realm),
+            quote(&challenge.nonce),
+            quote(uri),
+            response,
+        );
+        authorization.push_str(match challenge.algorithm {
+            Algorithm::Md5 => ", algorithm=MD5",
+            Algorithm::Md5Sess => ", algorithm=MD5-sess",
+        });
+        if challenge.qop_auth {
+            let _ = write!(authorization, ", qop=auth, nc={}, cnonce=\"{}\"", nc, cnonce);
+        }
+        if let Some(opaque) = &challenge.opaque {
+            let _ = write!(authorization, ", opaque=\"{}\"", quote(opaque));
+        }
+        Some(authorization)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_the_rfc_1321_suite() {
+        let suite = [
+            ("", "d41d8cd98f00b204e9800998ecf8427e"),
+            ("a", "0cc175b9c0f1b6a831c399e269772661"),
+            ("abc", "900150983cd24fb0d6963f7d28e17f72"),
+            ("message digest", "f96b697d7cb7938d525a2f31aaf161d0"),
+            ("abcdefghijklmnopqrstuvwxyz", "c3fcd3d76192e4007dfb496cca67e13b"),
+            (
+                "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789",
+                "d174ab98d277d9f5a5611c2c9f419d9f",
+            ),
+            (
+                "12345678901234567890123456789012345678901234567890123456789012345678901234567890",
+                "57edf4a22be3c955ac49da2e2107b67a",
+            ),
+        ];
+        for (message, digest) in suite.iter() {
+            assert_eq!(md5_hex(message), *digest, "{:?}", message);
+        }
+    }
+
+    #[test]
+    fn answers_the_rfc_2617_example() {
+        let parameters = "realm=\"testrealm@host.com\", qop=\"auth,auth-int\", \
+            nonce=\"dcd98b7102dd2f0e8b11d0f600bfb0c093\", opaque=\"5ccc069c403ebaf9f0171e9517f40e41\"";
+        let challenge = DigestChallenge::parse(parameters).unwrap();
+        assert_eq!(challenge.opaque.as_deref(), Some("5ccc069c403ebaf9f0171e9517f40e41"));
+        let login = RpcLoginConfig {
+            username: "Mufasa".to_string(),
+            password: "Circle Of Life".to_string(),
+        };
+        let response = challenge.response(&login, "GET", "/dir/index.html", "00000001", "0a4f113b");
+        assert_eq!(response, "6629fae49393a05397450978507c4ef1");
+    }
+
+    #[test]
+    fn rejects_unsupported_challenges() {
+        assert!(DigestChallenge::parse("realm=\"r\", nonce=\"n\", algorithm=SHA-256").is_none());
+        assert!(DigestChallenge::parse("realm=\"r\", nonce=\"n\", qop=\"auth-int\"").is_none());
+        assert!(DigestChallenge::parse("realm=\"r\"").is_none());
+    }
+}
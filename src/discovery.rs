@@ -0,0 +1,418 @@
+use std::{
+    collections::BTreeMap,
+    error,
+    fmt,
+    fs,
+    future,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use futures_util::future::join;
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use tokio::{net::UdpSocket, time::{interval, timeout}};
+use tracing::{info, warn};
+
+use crate::{
+    config::{DiscoveryConfig, MonerodConfig},
+    metrics::{Exporter, Publisher},
+};
+
+pub const DNS_PORT: u16 = 53;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const TYPE_OPT: u16 = 41;
+const CLASS_IN: u16 = 1;
+
+const RCODE_NXDOMAIN: u8 = 3;
+
+// advertised with EDNS, so that large record sets don't get truncated
+const MAX_UDP_PAYLOAD: u16 = 4096;
+
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+// a malicious response could make compressed names point at each other
+const MAX_NAME_POINTERS: usize = 16;
+
+#[derive(Debug)]
+pub enum DiscoveryError {
+    Io(io::Error),
+    Random(getrandom::Error),
+    Timeout,
+    Truncated,
+    InvalidResponse,
+    Rcode(u8),
+    // a host with neither A nor AAAA records
+    NoAddresses,
+}
+
+impl error::Error for DiscoveryError {}
+
+impl fmt::Display for DiscoveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiscoveryError::Io(e) => write!(f, "I/O error: {}", e),
+            DiscoveryError::Random(e) => write!(f, "failed to generate a query ID: {}", e),
+            DiscoveryError::Timeout => f.write_str("no response from the nameserver"),
+            DiscoveryError::Truncated => f.write_str("response truncated"),
+            DiscoveryError::InvalidResponse => f.write_str("invalid response"),
+            DiscoveryError::Rcode(rcode) => write!(f, "error response with code {}", rcode),
+            DiscoveryError::NoAddresses => f.write_str("no addresses found"),
+        }
+    }
+}
+
+impl From<io::Error> for DiscoveryError {
+    fn from(e: io::Error) -> Self {
+        DiscoveryError::Io(e)
+    }
+}
+
+// the daemon behind an SRV record
+#[derive(Debug, PartialEq)]
+struct Service {
+    host: String,
+    port: u16,
+}
+
+#[derive(Debug, PartialEq)]
+enum Record {
+    Service(Service),
+    // of an A or AAAA record
+    Address(IpAddr),
+}
+
+// a domain name of labels of 1 to 63 characters, up to 253 characters
+pub fn is_valid_name(name: &str) -> bool {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    !name.is_empty()
+        && name.len() <= 253
+        && name.split('.').all(|label| !label.is_empty() && label.len() <= 63)
+}
+
+fn query(id: u16, name: &str, record_type: u16) -> Vec<u8> {
+    let mut message = Vec::with_capacity(512);
+    message.extend_from_slice(&id.to_be_bytes());
+    // a standard query with recursion desired
+    message.extend_from_slice(&[0x01, 0x00]);
+    // one question and one additional record for EDNS
+    message.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 1]);
+
+    for label in name.trim_end_matches('.').split('.') {
+        message.push(label.len() as u8);
+        message.extend_from_slice(label.as_bytes());
+    }
+    message.push(0);
+    message.extend_from_slice(&record_type.to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    // the OPT record has the root name, and the payload size in place of the class
+    message.push(0);
+    message.extend_from_slice(&TYPE_OPT.to_be_bytes());
+    message.extend_from_slice(&MAX_UDP_PAYLOAD.to_be_bytes());
+    // no extended flags and no options
+    message.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+
+    message
+}
+
+struct Reader<'a> {
+    message: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, length: usize) -> Option<&'a [u8]> {
+        let bytes = self.message.get(self.position..self.position.checked_add(length)?)?;
+        self.position += length;
+        Some(bytes)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    // labels may end with a pointer to the rest of the name elsewhere in the message
+    fn name(&mut self) -> Option<String> {
+        let mut labels = Vec::new();
+        let mut position = self.position;
+        let mut pointers = 0;
+        loop {
+            let length = *self.message.get(position)? as usize;
+            if length & 0xc0 == 0xc0 {
+                let offset = (length & 0x3f) << 8 | *self.message.get(position + 1)? as usize;
+                if pointers == 0 {
+                    self.position = position + 2;
+                }
+                pointers += 1;
+                if pointers > MAX_NAME_POINTERS {
+                    return None;
+                }
+                position = offset;
+            } else if length == 0 {
+                if pointers == 0 {
+                    self.position = position + 1;
+                }
+                return Some(labels.join("."));
+            } else {
+                let label = self.message.get(position + 1..position + 1 + length)?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                position += 1 + length;
+            }
+        }
+    }
+}
+
+// None if the message isn't a response to the query
+fn parse_response(id: u16, message: &[u8]) -> Option<Result<Vec<Record>, DiscoveryError>> {
+    let mut reader = Reader { message, position: 0 };
+    let response_id = reader.u16()?;
+    let flags = reader.u16()?;
+    if response_id != id || flags & 0x8000 == 0 {
+        return None;
+    }
+    if flags & 0x0200 != 0 {
+        return Some(Err(DiscoveryError::Truncated));
+    }
+    // a name without records has no targets
+    match (flags & 0x000f) as u8 {
+        0 => {},
+        RCODE_NXDOMAIN => return Some(Ok(Vec::new())),
+        rcode => return Some(Err(DiscoveryError::Rcode(rcode))),
+    }
+
+    Some(parse_records(&mut reader).ok_or(DiscoveryError::InvalidResponse))
+}
+
+fn parse_records(reader: &mut Reader) -> Option<Vec<Record>> {
+    let questions = reader.u16()?;
+    let answers = reader.u16()?;
+    reader.bytes(4)?;
+
+    for _ in 0..questions {
+        reader.name()?;
+        reader.bytes(4)?;
+    }
+
+    // the priorities and weights are for picking one of the daemons, every one of them is scraped;
+    // the CNAME records an address may come along with are skipped
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        reader.name()?;
+        let record_type = reader.u16()?;
+        let class = reader.u16()?;
+        reader.bytes(4)?;
+        let length = reader.u16()? as usize;
+        let end = reader.position + length;
+        match (record_type, class, length) {
+            (TYPE_SRV, CLASS_IN, _) => {
+                reader.bytes(4)?;
+                let port = reader.u16()?;
+                let host = reader.name()?;
+                // a target of "." means that there is no such service
+                if !host.is_empty() {
+                    records.push(Record::Service(Service { host, port }));
+                }
+            },
+            (TYPE_A, CLASS_IN, 4) => {
+                let octets = reader.bytes(4)?;
+                let address = Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]);
+                records.push(Record::Address(IpAddr::V4(address)));
+            },
+            (TYPE_AAAA, CLASS_IN, 16) => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(reader.bytes(16)?);
+                records.push(Record::Address(IpAddr::V6(Ipv6Addr::from(octets))));
+            },
+            _ => {},
+        }
+        reader.position = end;
+    }
+    Some(records)
+}
+
+async fn lookup(
+    nameserver: SocketAddr,
+    name: &str,
+    record_type: u16,
+) -> Result<Vec<Record>, DiscoveryError> {
+    let mut id = [0; 2];
+    // a random ID makes spoofing responses harder
+    getrandom::getrandom(&mut id).map_err(DiscoveryError::Random)?;
+    let id = u16::from_be_bytes(id);
+
+    let local_address = match nameserver {
+        SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+    };
+    let socket = UdpSocket::bind(local_address).await?;
+    socket.connect(nameserver).await?;
+    socket.send(&query(id, name, record_type)).await?;
+
+    let mut buffer = vec![0; MAX_UDP_PAYLOAD as usize];
+    let response = async {
+        // late responses to earlier queries are skipped
+        loop {
+            let length = socket.recv(&mut buffer).await?;
+            if let Some(records) = parse_response(id, &buffer[..length]) {
+                return records;
+            }
+        }
+    };
+    timeout(LOOKUP_TIMEOUT, response).await.map_err(|_| DiscoveryError::Timeout)?
+}
+
+// the nameservers of resolv.conf, or the local one like the system resolver
+fn system_nameservers() -> Vec<SocketAddr> {
+    let resolv_conf = fs::read_to_string("/etc/resolv.conf").unwrap_or_default();
+    let nameservers = resolv_conf.lines()
+        .filter_map(|line| match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["nameserver", address, ..] => address.parse::<IpAddr>().ok(),
+            _ => None,
+        })
+        .map(|ip| SocketAddr::new(ip, DNS_PORT))
+        .collect::<Vec<_>>();
+
+    if nameservers.is_empty() {
+        vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), DNS_PORT)]
+    } else {
+        nameservers
+    }
+}
+
+// the nameservers are tried in order until one of them answers
+async fn resolve(
+    nameservers: &[SocketAddr],
+    name: &str,
+    record_type: u16,
+) -> Result<Vec<Record>, DiscoveryError> {
+    let mut last_error = DiscoveryError::Timeout;
+    for nameserver in nameservers {
+        match lookup(*nameserver, name, record_type).await {
+            Ok(records) => return Ok(records),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+// the IPv4 and IPv6 addresses of a name, either of the lookups may fail
+async fn resolve_addresses(nameserver: SocketAddr, name: &str) -> Result<Vec<IpAddr>, DiscoveryError> {
+    let (v4, v6) = join(lookup(nameserver, name, TYPE_A), lookup(nameserver, name, TYPE_AAAA)).await;
+    let records = match (v4, v6) {
+        (Err(e), Err(_)) => return Err(e),
+        (v4, v6) => v4.into_iter().chain(v6).flatten(),
+    };
+    let addresses = records
+        .filter_map(|record| match record {
+            Record::Address(address) => Some(address),
+            Record::Service(_) => None,
+        })
+        .collect::<Vec<_>>();
+    match addresses.is_empty() {
+        true => Err(DiscoveryError::NoAddresses),
+        false => Ok(addresses),
+    }
+}
+
+// resolves the hosts of daemons with a nameserver of their own instead of the system resolver
+pub struct NameserverResolver {
+    pub nameserver: SocketAddr,
+}
+
+impl Resolve for NameserverResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let nameserver = self.nameserver;
+        Box::pin(async move {
+            let addresses = resolve_addresses(nameserver, name.as_str()).await?;
+            // the port is that of the URL, whatever it's set to here
+            let addrs = addresses.into_iter().map(|address| SocketAddr::new(address, 0));
+            Ok(Box::new(addrs) as Addrs)
+        })
+    }
+}
+
+// keeps the targets in line with the SRV records, the configured targets are always exported
+// and a failed lookup leaves the targets as they are
+pub async fn run<F>(publisher: Arc<Publisher>, config: Option<&DiscoveryConfig>, create_exporter: F)
+where
+    F: Fn(&MonerodConfig) -> Result<Exporter, Box<dyn error::Error>>,
+{
+    let config = match config {
+        None => return future::pending().await,
+        Some(config) => config,
+    };
+
+    let nameservers = match config.nameserver {
+        None => system_nameservers(),
+        Some(nameserver) => vec![nameserver],
+    };
+    let configured = publisher.exporters();
+    // the exporters are kept for as long as their targets are, along with the blocks they cached
+    let mut discovered = BTreeMap::<String, Arc<Exporter>>::new();
+
+    let mut ticks = interval(config.interval);
+    loop {
+        ticks.tick().await;
+
+        let services = match resolve(&nameservers, &config.srv_name, TYPE_SRV).await {
+            Ok(records) => records.into_iter().filter_map(|record| match record {
+                Record::Service(service) => Some(service),
+                Record::Address(_) => None,
+            }),
+            Err(e) => {
+                warn!("failed to resolve {}: {}", config.srv_name, e);
+                continue;
+            },
+        };
+
+        let mut targets = BTreeMap::new();
+        for service in services {
+            let name = format!("{}:{}", service.host, service.port);
+            if targets.contains_key(&name) || configured.iter().any(|exporter| exporter.name() == name) {
+                continue;
+            }
+
+            let exporter = match discovered.remove(&name) {
+                Some(exporter) => exporter,
+                None => {
+                    let target = MonerodConfig {
+                        name: name.clone(),
+                        base_url: format!("{}://{}", config.scheme, name),
+                        // the publisher and the addresses of the template aren't the target's
+                        zmq_url: None,
+                        p2p_address: None,
+                        onion_address: None,
+                        onion_proxy: None,
+                        port_checker: None,
+                        public_ports: Vec::new(),
+                        log_path: None,
+                        ..config.target.clone()
+                    };
+                    match create_exporter(&target) {
+                        Ok(exporter) => {
+                            info!("discovered target {}", name);
+                            Arc::new(exporter)
+                        },
+                        Err(e) => {
+                            warn!("failed to create exporter for discovered target {}: {}", name, e);
+                            continue;
+                        },
+                    }
+                },
+            };
+            targets.insert(name, exporter);
+        }
+
+        for name in discovered.keys() {
+            info!("target {} is no longer discovered", name);
+        }
+        discovered = targets;
+
+        publisher.set_exporters(configured.iter().chain(discovered.values()).cloned().collect());
+    }
+}
@@ -0,0 +1,420 @@
+use serde_json::{Map, Number, Value};
+use std::{convert::TryInto, fmt};
+
+use crate::hex;
+
+// the two signatures and the version every storage starts with
+const HEADER: [u8; 9] = [0x01, 0x11, 0x01, 0x01, 0x01, 0x01, 0x02, 0x01, 0x01];
+
+// as deep as monerod reads storages itself
+const MAX_DEPTH: usize = 100;
+
+const TYPE_INT64: u8 = 1;
+const TYPE_INT32: u8 = 2;
+const TYPE_INT16: u8 = 3;
+const TYPE_INT8: u8 = 4;
+const TYPE_UINT64: u8 = 5;
+const TYPE_UINT32: u8 = 6;
+const TYPE_UINT16: u8 = 7;
+const TYPE_UINT8: u8 = 8;
+const TYPE_DOUBLE: u8 = 9;
+const TYPE_STRING: u8 = 10;
+const TYPE_BOOL: u8 = 11;
+const TYPE_OBJECT: u8 = 12;
+const TYPE_ARRAY: u8 = 13;
+const ARRAY_FLAG: u8 = 0x80;
+
+#[derive(Clone, Debug)]
+pub enum EpeeError {
+    InvalidHeader,
+    UnexpectedEnd,
+    // the type byte of an entry
+    InvalidType(u8),
+    TooDeep,
+    TrailingBytes,
+    // a request that isn't an object, or has arrays of mixed types, keys over 255 bytes or huge lengths
+    UnsupportedValue,
+}
+
+impl fmt::Display for EpeeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EpeeError::InvalidHeader => f.write_str("invalid portable storage header"),
+            EpeeError::UnexpectedEnd => f.write_str("unexpected end of portable storage"),
+            EpeeError::InvalidType(type_) => write!(f, "invalid portable storage type {}", type_),
+            EpeeError::TooDeep => f.write_str("portable storage nested too deep"),
+            EpeeError::TrailingBytes => f.write_str("trailing bytes after portable storage"),
+            EpeeError::UnsupportedValue => f.write_str("value not representable in portable storage"),
+        }
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], EpeeError> {
+        if len > self.data.len() {
+            return Err(EpeeError::UnexpectedEnd);
+        }
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn fixed<const N: usize>(&mut self) -> Result<[u8; N], EpeeError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    // the two low bits of the first byte are the log2 of its length, the rest is the value
+    fn varint(&mut self) -> Result<u64, EpeeError> {
+        let [first] = self.fixed::<1>()?;
+        let len = 1 << (first & 0x03);
+        let mut bytes = [0; 8];
+        bytes[0] = first;
+        bytes[1..len].copy_from_slice(self.take(len - 1)?);
+        Ok(u64::from_le_bytes(bytes) >> 2)
+    }
+
+    // every element takes at least a byte, so that a bogus count can't allocate more than the storage
+    fn count(&mut self) -> Result<usize, EpeeError> {
+        match self.varint()? {
+            count if count > self.data.len() as u64 => Err(EpeeError::UnexpectedEnd),
+            count => Ok(count as usize),
+        }
+    }
+
+    fn section(&mut self, depth: usize) -> Result<Value, EpeeError> {
+        if depth > MAX_DEPTH {
+            return Err(EpeeError::TooDeep);
+        }
+        let mut object = Map::new();
+        for _ in 0..self.count()? {
+            let [len] = self.fixed::<1>()?;
+            let name = String::from_utf8_lossy(self.take(len as usize)?).into_owned();
+            let [type_] = self.fixed::<1>()?;
+            let value = match type_ & ARRAY_FLAG {
+                0 => self.value(type_, depth)?,
+                _ => self.array(type_ & !ARRAY_FLAG, depth)?,
+            };
+            object.insert(name, value);
+        }
+        Ok(Value::Object(object))
+    }
+
+    fn array(&mut self, type_: u8, depth: usize) -> Result<Value, EpeeError> {
+        if !(TYPE_INT64..=TYPE_ARRAY).contains(&type_) {
+            return Err(EpeeError::InvalidType(type_));
+        }
+        let count = self.count()?;
+        (0..count).map(|_| self.value(type_, depth)).collect::<Result<_, _>>().map(Value::Array)
+    }
+
+    fn value(&mut self, type_: u8, depth: usize) -> Result<Value, EpeeError> {
+        Ok(match type_ {
+            TYPE_INT64 => Value::from(i64::from_le_bytes(self.fixed()?)),
+            TYPE_INT32 => Value::from(i32::from_le_bytes(self.fixed()?)),
+            TYPE_INT16 => Value::from(i16::from_le_bytes(self.fixed()?)),
+            TYPE_INT8 => Value::from(i8::from_le_bytes(self.fixed()?)),
+            TYPE_UINT64 => Value::from(u64::from_le_bytes(self.fixed()?)),
+            TYPE_UINT32 => Value::from(u32::from_le_bytes(self.fixed()?)),
+            TYPE_UINT16 => Value::from(u16::from_le_bytes(self.fixed()?)),
+            TYPE_UINT8 => Value::from(u8::from_le_bytes(self.fixed()?)),
+            TYPE_DOUBLE => Number::from_f64(f64::from_le_bytes(self.fixed()?))
+                .map(Value::Number)
+                .unwrap_or(Value::Null),
+            // blobs which aren't text, such as hashes, are hex-encoded like monerod does in JSON
+            TYPE_STRING => {
+                let len = self.count()?;
+                let bytes = self.take(len)?;
+                match std::str::from_utf8(bytes) {
+                    Ok(text) if !text.chars().any(char::is_control) => Value::from(text),
+                    _ => Value::from(hex::encode(bytes)),
+                }
+            },
+            TYPE_BOOL => Value::from(self.fixed::<1>()?[0] != 0),
+            TYPE_OBJECT => self.section(depth + 1)?,
+            TYPE_ARRAY => match self.fixed::<1>()? {
+                [type_] if type_ & ARRAY_FLAG != 0 => self.array(type_ & !ARRAY_FLAG, depth + 1)?,
+                [type_] => return Err(EpeeError::InvalidType(type_)),
+            },
+            type_ => return Err(EpeeError::InvalidType(type_)),
+        })
+    }
+}
+
+// a storage as the JSON object monerod would have responded with otherwise
+pub fn from_slice(data: &[u8]) -> Result<Value, EpeeError> {
+    let mut reader = Reader { data };
+    if reader.take(HEADER.len()).map_err(|_| EpeeError::InvalidHeader)? != HEADER {
+        return Err(EpeeError::InvalidHeader);
+    }
+    let value = reader.section(0)?;
+    match reader.data.is_empty() {
+        true => Ok(value),
+        false => Err(EpeeError::TrailingBytes),
+    }
+}
+
+fn write_varint(data: &mut Vec<u8>, value: u64) -> Result<(), EpeeError> {
+    match value {
+        0..=0x3f => data.push((value << 2) as u8),
+        0x40..=0x3fff => data.extend_from_slice(&((value << 2 | 1) as u16).to_le_bytes()),
+        0x4000..=0x3fff_ffff => data.extend_from_slice(&((value << 2 | 2) as u32).to_le_bytes()),
+        0x4000_0000..=0x3fff_ffff_ffff_ffff => data.extend_from_slice(&(value << 2 | 3).to_le_bytes()),
+        _ => return Err(EpeeError::UnsupportedValue),
+    }
+    Ok(())
+}
+
+fn value_type(value: &Value) -> Result<u8, EpeeError> {
+    match value {
+        Value::Number(number) if number.is_u64() => Ok(TYPE_UINT64),
+        Value::Number(number) if number.is_i64() => Ok(TYPE_INT64),
+        Value::Number(_) => Ok(TYPE_DOUBLE),
+        Value::String(_) => Ok(TYPE_STRING),
+        Value::Bool(_) => Ok(TYPE_BOOL),
+        Value::Object(_) => Ok(TYPE_OBJECT),
+        Value::Array(_) => Ok(TYPE_ARRAY),
+        Value::Null => Err(EpeeError::UnsupportedValue),
+    }
+}
+
+// the elements of an array share a type, numbers taking the one which fits them all
+fn array_type(elements: &[Value]) -> Result<u8, EpeeError> {
+    let mut types = elements.iter().map(value_type);
+    let first = types.next().unwrap_or(Ok(TYPE_UINT64))?;
+    types.try_fold(first, |array_type, type_| match (array_type, type_?) {
+        (array_type, type_) if array_type == type_ => Ok(array_type),
+        (TYPE_DOUBLE, TYPE_UINT64) | (TYPE_DOUBLE, TYPE_INT64) => Ok(TYPE_DOUBLE),
+        (TYPE_UINT64, TYPE_DOUBLE) | (TYPE_INT64, TYPE_DOUBLE) => Ok(TYPE_DOUBLE),
+        (TYPE_UINT64, TYPE_INT64) | (TYPE_INT64, TYPE_UINT64) => Ok(TYPE_INT64),
+        _ => Err(EpeeError::UnsupportedValue),
+    })
+}
+
+fn write_section(data: &mut Vec<u8>, object: &Map<String, Value>) -> Result<(), EpeeError> {
+    // monerod takes missing fields for their defaults
+    let entries = object.iter()
+        .filter(|(_, value)| match value {
+            Value::Null => false,
+            Value::Array(elements) => !elements.is_empty(),
+            _ => true,
+        })
+        .collect::<Vec<_>>();
+    write_varint(data, entries.len() as u64)?;
+    for (name, value) in entries {
+        if name.len() > u8::MAX as usize {
+            return Err(EpeeError::UnsupportedValue);
+        }
+        data.push(name.len() as u8);
+        data.extend_from_slice(name.as_bytes());
+        match value {
+            Value::Array(elements) => write_array(data, elements)?,
+            value => {
+                let type_ = value_type(value)?;
+                data.push(type_);
+                write_value(data, value, type_)?;
+            },
+        }
+    }
+    Ok(())
+}
+
+fn write_array(data: &mut Vec<u8>, elements: &[Value]) -> Result<(), EpeeError> {
+    let type_ = array_type(elements)?;
+    data.push(type_ | ARRAY_FLAG);
+    write_varint(data, elements.len() as u64)?;
+    for element in elements {
+        write_value(data, element, type_)?;
+    }
+    Ok(())
+}
+
+fn write_value(data: &mut Vec<u8>, value: &Value, type_: u8) -> Result<(), EpeeError> {
+    match (type_, value) {
+        (TYPE_UINT64, Value::Number(number)) => {
+            let number = number.as_u64().ok_or(EpeeError::UnsupportedValue)?;
+            data.extend_from_slice(&number.to_le_bytes());
+        },
+        (TYPE_INT64, Value::Number(number)) => {
+            let number = number.as_i64().ok_or(EpeeError::UnsupportedValue)?;
+            data.extend_from_slice(&number.to_le_bytes());
+        },
+        (TYPE_DOUBLE, Value::Number(number)) => {
+            let number = number.as_f64().ok_or(EpeeError::UnsupportedValue)?;
+            data.extend_from_slice(&number.to_le_bytes());
+        },
+        (TYPE_STRING, Value::String(string)) => {
+            write_varint(data, string.len() as u64)?;
+            data.extend_from_slice(string.as_bytes());
+        },
+        (TYPE_BOOL, Value::Bool(bool)) => data.push(*bool as u8),
+        (TYPE_OBJECT, Value::Object(object)) => write_section(data, object)?,
+        (TYPE_ARRAY, Value::Array(elements)) => write_array(data, elements)?,
+        _ => return Err(EpeeError::UnsupportedValue),
+    }
+    Ok(())
+}
+
+// a field of a storage written in place of a JSON value, for binary strings such as network ids
+#[derive(Clone, Debug)]
+pub enum Field {
+    Uint(u64),
+    Blob(Vec<u8>),
+    Object(Vec<(&'static str, Field)>),
+}
+
+fn write_fields(data: &mut Vec<u8>, fields: &[(&str, Field)]) -> Result<(), EpeeError> {
+    write_varint(data, fields.len() as u64)?;
+    for (name, field) in fields {
+        if name.len() > u8::MAX as usize {
+            return Err(EpeeError::UnsupportedValue);
+        }
+        data.push(name.len() as u8);
+        data.extend_from_slice(name.as_bytes());
+        match field {
+            Field::Uint(value) => {
+                data.push(TYPE_UINT64);
+                data.extend_from_slice(&value.to_le_bytes());
+            },
+            Field::Blob(bytes) => {
+                data.push(TYPE_STRING);
+                write_varint(data, bytes.len() as u64)?;
+                data.extend_from_slice(bytes);
+            },
+            Field::Object(fields) => {
+                data.push(TYPE_OBJECT);
+                write_fields(data, fields)?;
+            },
+        }
+    }
+    Ok(())
+}
+
+pub fn fields_to_vec(fields: &[(&str, Field)]) -> Result<Vec<u8>, EpeeError> {
+    let mut data = HEADER.to_vec();
+    write_fields(&mut data, fields)?;
+    Ok(data)
+}
+
+// a request, whose strings are sent as their UTF-8 bytes
+pub fn to_vec(value: &Value) -> Result<Vec<u8>, EpeeError> {
+    let object = match value {
+        Value::Object(object) => object,
+        _ => return Err(EpeeError::UnsupportedValue),
+    };
+    let mut data = HEADER.to_vec();
+    write_section(&mut data, object)?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn writes_varints_of_every_width() {
+        let widths = [
+            (0, 1), (0x3f, 1), (0x40, 2), (0x3fff, 2), (0x4000, 4), (0x3fff_ffff, 4),
+            (0x4000_0000, 8), (0x3fff_ffff_ffff_ffff, 8),
+        ];
+        for (value, width) in widths.iter() {
+            let mut data = Vec::new();
+            write_varint(&mut data, *value).unwrap();
+            assert_eq!(data.len(), *width, "{:#x}", value);
+            let mut reader = Reader { data: &data };
+            assert_eq!(reader.varint().unwrap(), *value);
+            assert!(reader.data.is_empty());
+        }
+        assert!(write_varint(&mut Vec::new(), 0x4000_0000_0000_0000).is_err());
+    }
+
+    #[test]
+    fn round_trips_values() {
+        let value = json!({
+            "empty": "",
+            "short": "a".repeat(0x3f),
+            "medium": "b".repeat(0x40),
+            "long": "c".repeat(0x4000),
+            "numbers": [0, u64::MAX],
+            "signed": [-1, 1],
+            "doubles": [0.5, 1],
+            "nested": [[1, 2], [3]],
+            "objects": [{"ok": true}, {"ok": false, "inner": {"name": "x"}}],
+        });
+        let mut expected = value.clone();
+        expected["doubles"] = json!([0.5, 1.0]);
+        assert_eq!(from_slice(&to_vec(&value).unwrap()).unwrap(), expected);
+    }
+
+    #[test]
+    fn skips_empty_fields() {
+        let value = json!({"missing": null, "none": [], "some": [1]});
+        assert_eq!(from_slice(&to_vec(&value).unwrap()).unwrap(), json!({"some": [1]}));
+    }
+
+    #[test]
+    fn rejects_unrepresentable_values() {
+        assert!(to_vec(&json!([1])).is_err());
+        assert!(to_vec(&json!({"mixed": [1, "a"]})).is_err());
+        assert!(to_vec(&json!({"nulls": [null]})).is_err());
+        let mut long_key = Map::new();
+        long_key.insert("k".repeat(256), json!(1));
+        assert!(to_vec(&Value::Object(long_key)).is_err());
+    }
+
+    #[test]
+    fn hex_encodes_binary_blobs() {
+        let data = fields_to_vec(&[
+            ("hash", Field::Blob(vec![0x00, 0x01, 0xab, 0xff])),
+            ("text", Field::Blob(b"text".to_vec())),
+            ("newline", Field::Blob(b"a\n".to_vec())),
+            ("object", Field::Object(vec![("id", Field::Uint(7))])),
+        ]).unwrap();
+        assert_eq!(from_slice(&data).unwrap(), json!({
+            "hash": "0001abff",
+            "text": "text",
+            "newline": "610a",
+            "object": {"id": 7},
+        }));
+    }
+
+    #[test]
+    fn rejects_truncated_storages() {
+        let value = json!({"hash": "ab".repeat(32), "height": 1, "nested": [[1], [2, 3]]});
+        let data = to_vec(&value).unwrap();
+        for len in 0..data.len() {
+            match from_slice(&data[..len]) {
+                Err(EpeeError::InvalidHeader) => assert!(len < HEADER.len()),
+                Err(EpeeError::UnexpectedEnd) => assert!(len >= HEADER.len()),
+                result => panic!("{:?} for {} of {} bytes", result, len, data.len()),
+            }
+        }
+        let mut trailing = data.clone();
+        trailing.push(0);
+        assert!(matches!(from_slice(&trailing), Err(EpeeError::TrailingBytes)));
+    }
+
+    #[test]
+    fn rejects_bogus_storages() {
+        let mut data = HEADER.to_vec();
+        // a single entry named a of type 14
+        data.extend_from_slice(&[0x04, 0x01, b'a', 14]);
+        assert!(matches!(from_slice(&data), Err(EpeeError::InvalidType(14))));
+
+        // a count larger than the storage
+        let mut data = HEADER.to_vec();
+        data.push(0xfc);
+        assert!(matches!(from_slice(&data), Err(EpeeError::UnexpectedEnd)));
+
+        let mut data = HEADER.to_vec();
+        for _ in 0..=MAX_DEPTH + 1 {
+            data.extend_from_slice(&[0x04, 0x01, b'a', TYPE_OBJECT]);
+        }
+        data.push(0x00);
+        assert!(matches!(from_slice(&data), Err(EpeeError::TooDeep)));
+    }
+}
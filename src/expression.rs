@@ -0,0 +1,230 @@
+use serde::Deserialize;
+use std::{collections::BTreeMap, fmt};
+
+use crate::prometheus::{Labels, Metric};
+
+// arithmetic on the exported series, e.g. "monero_node_free_space / 1e9"
+#[derive(Clone, Debug, Deserialize)]
+pub enum Expression {
+    Number(f64),
+    Series(String),
+    Negate(Box<Expression>),
+    Binary(Box<Expression>, Operator, Box<Expression>),
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+}
+
+#[derive(Clone, Debug)]
+pub enum ParseError {
+    UnexpectedEnd,
+    // position of the character, starting at 1
+    UnexpectedCharacter(usize),
+    InvalidNumber(usize),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::UnexpectedEnd => f.write_str("unexpected end of expression"),
+            ParseError::UnexpectedCharacter(position) => {
+                write!(f, "unexpected character at position {}", position)
+            },
+            ParseError::InvalidNumber(position) => write!(f, "invalid number at position {}", position),
+        }
+    }
+}
+
+// the values of a series by their sorted labels
+type Vector = BTreeMap<Labels, f64>;
+
+enum Value {
+    Scalar(f64),
+    Vector(Vector),
+}
+
+impl Operator {
+    fn apply(self, left: f64, right: f64) -> f64 {
+        match self {
+            Operator::Add => left + right,
+            Operator::Subtract => left - right,
+            Operator::Multiply => left * right,
+            Operator::Divide => left / right,
+        }
+    }
+}
+
+struct Parser<'a> {
+    chars: Vec<(usize, char)>,
+    source: &'a str,
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&mut self) -> Option<char> {
+        while let Some((_, c)) = self.chars.get(self.position) {
+            if !c.is_whitespace() {
+                return Some(*c);
+            }
+            self.position += 1;
+        }
+        None
+    }
+
+    fn unexpected(&self) -> ParseError {
+        match self.chars.get(self.position) {
+            None => ParseError::UnexpectedEnd,
+            Some(_) => ParseError::UnexpectedCharacter(self.position + 1),
+        }
+    }
+
+    // sums of products, both left-associative
+    fn expression(&mut self) -> Result<Expression, ParseError> {
+        let mut expression = self.term()?;
+        loop {
+            let operator = match self.peek() {
+                Some('+') => Operator::Add,
+                Some('-') => Operator::Subtract,
+                _ => return Ok(expression),
+            };
+            self.position += 1;
+            expression = Expression::Binary(Box::new(expression), operator, Box::new(self.term()?));
+        }
+    }
+
+    fn term(&mut self) -> Result<Expression, ParseError> {
+        let mut term = self.factor()?;
+        loop {
+            let operator = match self.peek() {
+                Some('*') => Operator::Multiply,
+                Some('/') => Operator::Divide,
+                _ => return Ok(term),
+            };
+            self.position += 1;
+            term = Expression::Binary(Box::new(term), operator, Box::new(self.factor()?));
+        }
+    }
+
+    fn factor(&mut self) -> Result<Expression, ParseError> {
+        match self.peek() {
+            Some('-') => {
+                self.position += 1;
+                Ok(Expression::Negate(Box::new(self.factor()?)))
+            },
+            Some('(') => {
+                self.position += 1;
+                let expression = self.expression()?;
+                if self.peek() != Some(')') {
+                    return Err(self.unexpected());
+                }
+                self.position += 1;
+                Ok(expression)
+            },
+            Some(c) if c.is_ascii_digit() || c == '.' => self.number(),
+            Some(c) if c.is_ascii_alphabetic() || c == '_' || c == ':' => Ok(self.series()),
+            _ => Err(self.unexpected()),
+        }
+    }
+
+    fn number(&mut self) -> Result<Expression, ParseError> {
+        let start = self.position;
+        while let Some((_, c)) = self.chars.get(self.position) {
+            // the sign of an exponent belongs to the number
+            let is_exponent_sign = (*c == '-' || *c == '+')
+                && matches!(self.chars.get(self.position - 1), Some((_, 'e')) | Some((_, 'E')));
+            if !(c.is_ascii_digit() || *c == '.' || *c == 'e' || *c == 'E' || is_exponent_sign) {
+                break;
+            }
+            self.position += 1;
+        }
+        self.slice(start).parse::<f64>()
+            .map(Expression::Number)
+            .map_err(|_| ParseError::InvalidNumber(start + 1))
+    }
+
+    fn series(&mut self) -> Expression {
+        let start = self.position;
+        while let Some((_, c)) = self.chars.get(self.position) {
+            if !(c.is_ascii_alphanumeric() || *c == '_' || *c == ':') {
+                break;
+            }
+            self.position += 1;
+        }
+        Expression::Series(self.slice(start).to_string())
+    }
+
+    fn slice(&self, start: usize) -> &'a str {
+        let end = self.chars.get(self.position).map(|(i, _)| *i).unwrap_or_else(|| self.source.len());
+        &self.source[self.chars[start].0..end]
+    }
+}
+
+impl Expression {
+    pub fn parse(source: &str) -> Result<Expression, ParseError> {
+        let mut parser = Parser {
+            chars: source.char_indices().collect(),
+            source,
+            position: 0,
+        };
+        let expression = parser.expression()?;
+        match parser.peek() {
+            None => Ok(expression),
+            Some(_) => Err(parser.unexpected()),
+        }
+    }
+
+    // series are matched by their labels, those without a match on the other side are left out
+    fn evaluate(&self, metrics: &[Metric]) -> Value {
+        match self {
+            Expression::Number(number) => Value::Scalar(*number),
+            Expression::Series(name) => Value::Vector(metrics.iter()
+                .flat_map(Metric::series)
+                .filter(|(series, _, _)| series == name)
+                .map(|(_, mut labels, value)| {
+                    labels.sort();
+                    (labels, value)
+                })
+                .collect()),
+            Expression::Negate(expression) => match expression.evaluate(metrics) {
+                Value::Scalar(value) => Value::Scalar(-value),
+                Value::Vector(vector) => Value::Vector(vector.into_iter()
+                    .map(|(labels, value)| (labels, -value))
+                    .collect()),
+            },
+            Expression::Binary(left, operator, right) => {
+                match (left.evaluate(metrics), right.evaluate(metrics)) {
+                    (Value::Scalar(left), Value::Scalar(right)) => {
+                        Value::Scalar(operator.apply(left, right))
+                    },
+                    (Value::Vector(left), Value::Scalar(right)) => Value::Vector(left.into_iter()
+                        .map(|(labels, left)| (labels, operator.apply(left, right)))
+                        .collect()),
+                    (Value::Scalar(left), Value::Vector(right)) => Value::Vector(right.into_iter()
+                        .map(|(labels, right)| (labels, operator.apply(left, right)))
+                        .collect()),
+                    (Value::Vector(left), Value::Vector(right)) => Value::Vector(left.into_iter()
+                        .filter_map(|(labels, left)| {
+                            let right = *right.get(&labels)?;
+                            Some((labels, operator.apply(left, right)))
+                        })
+                        .collect()),
+                }
+            },
+        }
+    }
+
+    // none if the series it refers to weren't exported
+    pub fn to_metric(&self, name: &str, metrics: &[Metric]) -> Option<Metric> {
+        let values = match self.evaluate(metrics) {
+            Value::Scalar(value) => vec![(Vec::new(), value)],
+            Value::Vector(vector) if vector.is_empty() => return None,
+            Value::Vector(vector) => vector.into_iter().collect(),
+        };
+        Some(Metric::new_gauge_with_labels(name, values))
+    }
+}
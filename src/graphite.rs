@@ -0,0 +1,100 @@
+use std::{
+    fmt::Write,
+    future, io,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::AsyncWriteExt,
+    net::TcpStream,
+    time::{interval, timeout},
+};
+use tracing::{info, warn};
+
+use crate::{config::GraphiteConfig, metrics::Publisher, prometheus::Labels};
+
+// of the plaintext protocol of carbon
+pub const GRAPHITE_PORT: u16 = 2003;
+
+// of connecting and sending a push, which is dropped after it
+const PUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+// dots separate the nodes of a path and spaces the fields of a line, so neither may be in a node
+fn escape(node: &str) -> String {
+    node.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '_' })
+        .collect()
+}
+
+// the name followed by the name and value of every label, sorted by name,
+// e.g. monero_node_up{target="a"} is <prefix>.monero_node_up.target.a
+fn path(prefix: &str, name: &str, labels: &Labels) -> String {
+    let mut labels = labels.iter().filter(|(_, value)| !value.is_empty()).collect::<Vec<_>>();
+    labels.sort();
+    let mut path = match prefix {
+        "" => escape(name),
+        prefix => format!("{}.{}", prefix, escape(name)),
+    };
+    for (name, value) in labels {
+        path.push('.');
+        path.push_str(&escape(name));
+        path.push('.');
+        path.push_str(&escape(value));
+    }
+    path
+}
+
+// a line per sample, all at the time of the push; NaN and infinite values can't be sent
+fn render(prefix: &str, series: &[(String, Labels, f64)], timestamp: u64) -> String {
+    let mut lines = String::new();
+    for (name, labels, value) in series.iter().filter(|(_, _, value)| value.is_finite()) {
+        let _ = writeln!(lines, "{} {} {}", path(prefix, name, labels), value, timestamp);
+    }
+    lines
+}
+
+async fn send(address: &str, lines: &str) -> io::Result<()> {
+    let push = async {
+        let mut stream = TcpStream::connect(address).await?;
+        stream.write_all(lines.as_bytes()).await?;
+        stream.shutdown().await
+    };
+    timeout(PUSH_TIMEOUT, push).await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "push timed out"))?
+}
+
+// pushes the exported metrics every interval, for as long as the refresh loop runs
+pub async fn run(publisher: Arc<Publisher>, config: Option<&GraphiteConfig>) {
+    let config = match config {
+        None => return future::pending().await,
+        Some(config) => config,
+    };
+
+    let mut failing = false;
+    let mut ticks = interval(config.interval);
+    loop {
+        ticks.tick().await;
+
+        // nothing is pushed until there are metrics, nor while they're stale
+        let series = match publisher.push_series().await {
+            Some(series) => series,
+            None => continue,
+        };
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let lines = render(&config.prefix, &series, timestamp);
+
+        match send(&config.address, &lines).await {
+            Ok(_) if failing => {
+                info!("pushed metrics to Graphite at {} again", config.address);
+                failing = false;
+            },
+            Ok(_) => {},
+            // only the first of consecutive failures is logged
+            Err(e) if !failing => {
+                warn!("failed to push metrics to Graphite at {}: {}", config.address, e);
+                failing = true;
+            },
+            Err(_) => {},
+        }
+    }
+}
@@ -0,0 +1,129 @@
+// descriptions rendered in "# HELP" lines of the exported metrics
+
+// metrics of the monitored node, named before the namespace and unit suffix are applied
+pub fn get_node_help(name: &str) -> Option<&'static str> {
+    let help = match name {
+        // Node metrics
+        "node_info" => "Node identity attributes as labels",
+        "node_up" => "Whether the node responded to the last refresh",
+        "node_circuit_open" => "Whether the node isn't called after failing too many refreshes in a row",
+        "node_busy" => "Whether the node responded as busy, e.g. while starting up, to the last refresh",
+        "node_database_size" => "Size of the blockchain database in bytes",
+        "node_free_space" => "Free disk space available to the node in bytes",
+        "node_grey_peerlist_size" => "Number of peers in the grey peer list",
+        "node_incoming_connections_count" => "Number of incoming P2P connections",
+        "node_offline" => "Whether the node is offline",
+        "node_outgoing_connections_count" => "Number of outgoing P2P connections",
+        "node_rpc_connections_count" => "Number of RPC client connections",
+        "node_synchronized" => "Whether the node is synchronized with the network",
+        "node_tx_pool_size" => "Number of transactions in the pool as reported by get_info",
+        "node_white_peerlist_size" => "Number of peers in the white peer list",
+        "p2p_reachable" => "Whether a TCP connection to the P2P port of the node could be made",
+        "p2p_connect_duration_seconds" => "Time it took to connect to the P2P port of the node",
+        "p2p_handshake_succeeded" => "Whether the node completed a P2P handshake over the connection to its P2P port",
+        "p2p_handshake_duration_seconds" => "Time it took the node to respond to the P2P handshake",
+        "p2p_top_height" => "Height the node advertised in its P2P handshake",
+        "p2p_top_version" => "Hard fork version of the top block the node advertised in its P2P handshake",
+        "p2p_survey_peers" => "Peers of the node sampled by the last P2P survey",
+        "p2p_survey_handshakes" => "Peers sampled by the last P2P survey which completed a handshake",
+        "p2p_survey_top_height" => "Quantiles of the heights the surveyed peers advertised",
+        "p2p_survey_handshake_duration_seconds" => "Quantiles of the time the surveyed peers took to respond to the handshake",
+        "p2p_survey_top_version_peers" => "Surveyed peers by the hard fork version of the top block they advertised",
+        "onion_reachable" => "Whether the onion service of the node could be connected to through Tor",
+        "onion_connect_duration_seconds" => "Time it took to connect to the onion service of the node through Tor",
+        "node_port_reachable" => "Whether the public port of the node could be connected to by the port checker",
+        "node_zmq_connected" => "Whether the subscription to the ZMQ events of the node is connected",
+
+        // Node metrics - transaction pool
+        "txpool_bytes_max" => "Size of the largest transaction in the pool in bytes",
+        "txpool_bytes_med" => "Median transaction size in the pool in bytes",
+        "txpool_bytes_min" => "Size of the smallest transaction in the pool in bytes",
+        "txpool_bytes_total" => "Total size of all transactions in the pool in bytes",
+        "txpool_double_spends" => "Number of double spend transactions in the pool",
+        "txpool_txs_failing" => "Number of failing transactions in the pool",
+        "txpool_txs_not_relayed" => "Number of transactions in the pool which were not relayed",
+        "txpool_oldest_tx" => "Time when the oldest transaction in the pool was received",
+        "txpool_oldest_tx_age_seconds" => "Age of the oldest transaction in the pool",
+        "txpool_txs_above_10min" => "Number of transactions in the pool older than 10 minutes",
+        "txpool_txs_total" => "Total number of transactions in the pool",
+        "txpool_txs_received_total" => "Transactions added to the pool as published over ZMQ",
+        "txpool_received_bytes_total" => "Size in bytes of the transactions added to the pool as published over ZMQ",
+        "txpool_txs_added_total" => "Transactions which were added to the pool and were still in it at a refresh",
+        "txpool_txs_mined_total" => "Transactions which left the pool for a block",
+        "txpool_txs_dropped_total" => "Transactions which left the pool without being mined, expired or double spent",
+        "txpool_double_spends_detected_total" => "Rises of the number of transactions in the pool which are double spends",
+
+        // Log metrics
+        "log_readable" => "Whether the log of the node could be read the last time it was polled",
+        "log_lines_total" => "Lines written to the log of the node which matched the pattern",
+
+        // Network metrics
+        "network_block_size_limit" => "Maximum allowed block size in bytes",
+        "network_block_size_median" => "Median block size of the latest 100 blocks in bytes",
+        "network_block_weight_limit" => "Maximum allowed block weight",
+        "network_block_weight_median" => "Median block weight of the latest 100 blocks",
+        "network_cumulative_difficulty" => "Cumulative difficulty of all blocks in the chain",
+        "network_difficulty" => "Network difficulty of the next block",
+        "network_height" => "Current length of the longest chain known to the node",
+        "network_target" => "Target block time in seconds",
+        "network_target_height" => "Height of the chain the node is synchronizing to",
+        "network_tx_count" => "Total number of non-coinbase transactions in the chain",
+
+        // Network metrics - blocks
+        "blocks_observed" => "Number of non-orphan blocks found in the span",
+        "blocks_avg_txes" => "Average number of transactions per block over the span",
+        "blocks_max_txes" => "Maximum number of transactions per block over the span",
+        "blocks_avg_reward" => "Average block reward over the span",
+        "blocks_max_reward" => "Maximum block reward over the span",
+        "blocks_avg_size" => "Average block size in bytes over the span",
+        "blocks_max_size" => "Maximum block size in bytes over the span",
+        "blocks_difficulty_change" => "Ratio of the newest to the oldest block difficulty in the span",
+        "blocks_txes" => "Quantiles of the number of transactions per block over the span",
+        "blocks_reward" => "Quantiles of the block reward over the span",
+        "blocks_size" => "Quantiles of the block size in bytes over the span",
+        "block_propagation_delay_seconds" => "Time from the timestamp of new blocks until the node learned about them",
+        "blocks_published_total" => "Blocks added to the main chain as published over ZMQ, again after a reorganization",
+
+        // Reference metrics, only exported with reference nodes
+        "node_reference_lag" => "Number of blocks the node is behind the reference node",
+        "reference_height" => "Current length of the longest chain known to the reference node",
+
+        // Cluster metrics, only exported with several targets
+        "cluster_height_spread" => "Difference between the highest and the lowest height of the targets",
+        "cluster_blocks_behind" => "Number of blocks the target is behind the highest target",
+        "cluster_forked" => "Whether the target has another block than most targets at the lowest height",
+        "cluster_forked_nodes" => "Number of targets with another block than most targets at the lowest height",
+
+        _ => return None,
+    };
+    Some(help)
+}
+
+pub fn get_exporter_help(name: &str) -> Option<&'static str> {
+    let help = match name {
+        // Exporter metrics
+        "monerod_exporter_build_info" => "Exporter build information as labels",
+        "monerod_exporter_config_info" => "Effective exporter configuration as labels",
+        "monerod_exporter_last_refresh_duration_seconds" => "Duration of the last refresh",
+        "monerod_exporter_refresh_duration_seconds" => "Distribution of refresh durations",
+        "monerod_exporter_refreshes_total" => "Total number of refreshes",
+        "monerod_exporter_refresh_failures_total" => "Total number of failed refreshes",
+        "monerod_exporter_last_success_timestamp_seconds" => "Unix timestamp of the last successful refresh",
+        "monerod_exporter_consecutive_failures" => "Number of failed refreshes since the last successful one",
+        "monerod_exporter_last_error_info" => "Kind of the error of the last refresh, only while it failed",
+        "monerod_exporter_rpc_duration_seconds" => "Latency of Monero RPC calls",
+        "monerod_exporter_samples" => "Number of samples rendered in the last refresh",
+        "monerod_exporter_collector_error" => "Whether the collector failed in the last refresh, leaving its metrics out",
+
+        // Process metrics
+        "process_cpu_seconds_total" => "Total user and system CPU time spent in seconds",
+        "process_start_time_seconds" => "Start time of the process since unix epoch in seconds",
+        "process_virtual_memory_bytes" => "Virtual memory size in bytes",
+        "process_resident_memory_bytes" => "Resident memory size in bytes",
+        "process_open_fds" => "Number of open file descriptors",
+        "process_max_fds" => "Maximum number of open file descriptors",
+
+        _ => return None,
+    };
+    Some(help)
+}
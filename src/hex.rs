@@ -0,0 +1,10 @@
+use std::fmt::Write;
+
+// lowercase, like the hashes of monerod and the digests of RFC 2617
+pub fn encode(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        let _ = write!(hex, "{:02x}", byte);
+    }
+    hex
+}
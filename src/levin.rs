@@ -0,0 +1,337 @@
+use serde::Deserialize;
+use serde_json::Value;
+use std::{
+    convert::TryInto,
+    error, fmt, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::epee::{self, EpeeError, Field};
+
+// the P2P protocol of monerod, only as far as a handshake goes
+const SIGNATURE: u64 = 0x0101_0101_0101_2101;
+const HEADER_LEN: usize = 33;
+const PROTOCOL_VERSION: u32 = 1;
+
+const FLAG_REQUEST: u32 = 0x01;
+const FLAG_RESPONSE: u32 = 0x02;
+
+const COMMAND_HANDSHAKE: u32 = 1001;
+
+// fluffy blocks, which every node supports
+const SUPPORT_FLAGS: u64 = 0x01;
+
+// far more than a handshake response with a full peer list
+const MAX_BODY_SIZE: u64 = 1024 * 1024;
+
+const ADDRESS_IPV4: u64 = 1;
+const ADDRESS_IPV6: u64 = 2;
+
+#[derive(Debug)]
+pub enum LevinError {
+    Io(io::Error),
+    InvalidSignature,
+    PacketTooLarge,
+    // the return code of the response
+    Rejected(i32),
+    InvalidResponse,
+    Epee(EpeeError),
+}
+
+impl error::Error for LevinError {}
+
+impl fmt::Display for LevinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LevinError::Io(e) => write!(f, "I/O error: {}", e),
+            LevinError::InvalidSignature => {
+                f.write_str("invalid signature, the peer doesn't speak Levin")
+            },
+            LevinError::PacketTooLarge => f.write_str("packet too large"),
+            LevinError::Rejected(code) => write!(f, "handshake rejected with code {}", code),
+            LevinError::InvalidResponse => f.write_str("invalid handshake response"),
+            LevinError::Epee(e) => write!(f, "failed to parse handshake response: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for LevinError {
+    fn from(e: io::Error) -> Self {
+        LevinError::Io(e)
+    }
+}
+
+// of the nettypes get_info reports
+pub fn network_id(nettype: &str) -> Option<[u8; 16]> {
+    let last = match nettype {
+        "mainnet" => 0x10,
+        "testnet" => 0x11,
+        "stagenet" => 0x12,
+        _ => return None,
+    };
+    Some([
+        0x12, 0x30, 0xf1, 0x71, 0x61, 0x04, 0x41, 0x61, 0x17, 0x31, 0x00, 0x82, 0x16, 0xa1, 0xa1, last,
+    ])
+}
+
+#[derive(Debug, Deserialize)]
+struct HandshakeResponse {
+    payload_data: SyncData,
+    #[serde(default)]
+    local_peerlist_new: Vec<PeerlistEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SyncData {
+    current_height: u64,
+    #[serde(default)]
+    top_id: String,
+    #[serde(default)]
+    top_version: u8,
+}
+
+#[derive(Debug, Deserialize)]
+struct PeerlistEntry {
+    adr: Address,
+}
+
+// binary strings are hex-encoded, unless they happen to be text
+fn blob(value: &str, len: usize) -> Option<Vec<u8>> {
+    if value.len() == len {
+        return Some(value.as_bytes().to_vec());
+    }
+    if value.len() != len * 2 {
+        return None;
+    }
+    (0..value.len()).step_by(2)
+        .map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// an address of a type and its fields, which differ by type
+#[derive(Debug, Deserialize)]
+struct Address {
+    #[serde(rename = "type")]
+    type_: u64,
+    addr: Value,
+}
+
+impl Address {
+    // onion and I2P addresses can't be connected to directly, so they're left out
+    fn socket_addr(&self) -> Option<SocketAddr> {
+        let port = self.addr.get("m_port")?.as_u64()?.try_into().ok()?;
+        let ip = match self.type_ {
+            // in network byte order, which the little-endian storage reverses
+            ADDRESS_IPV4 => {
+                let ip: u32 = self.addr.get("m_ip")?.as_u64()?.try_into().ok()?;
+                IpAddr::V4(Ipv4Addr::from(ip.to_le_bytes()))
+            },
+            ADDRESS_IPV6 => {
+                let bytes: [u8; 16] = blob(self.addr.get("addr")?.as_str()?, 16)?.try_into().ok()?;
+                IpAddr::V6(Ipv6Addr::from(bytes))
+            },
+            _ => return None,
+        };
+        Some(SocketAddr::new(ip, port))
+    }
+}
+
+// what a peer advertised in its handshake
+#[derive(Clone, Debug)]
+pub struct Handshake {
+    pub current_height: u64,
+    pub top_id: String,
+    // the hard fork version of its top block
+    pub top_version: u8,
+    // the peers it knows of which can be connected to
+    pub peers: Vec<SocketAddr>,
+}
+
+fn header(body_len: usize, command: u32, expect_response: bool, flags: u32) -> [u8; HEADER_LEN] {
+    let mut header = [0; HEADER_LEN];
+    header[0..8].copy_from_slice(&SIGNATURE.to_le_bytes());
+    header[8..16].copy_from_slice(&(body_len as u64).to_le_bytes());
+    header[16] = expect_response as u8;
+    header[17..21].copy_from_slice(&command.to_le_bytes());
+    // the return code, 0 for requests
+    header[25..29].copy_from_slice(&flags.to_le_bytes());
+    header[29..33].copy_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+    header
+}
+
+// a handshake claiming only the genesis block, whose version the peer doesn't check against
+// its own chain unlike the one of any later block, and no port, so that it doesn't connect back
+pub async fn handshake(stream: &mut TcpStream, network_id: [u8; 16]) -> Result<Handshake, LevinError> {
+    // only told apart from the peer's own, to detect connections to itself
+    let mut peer_id = [0; 8];
+    if getrandom::getrandom(&mut peer_id).is_err() {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+        peer_id = (nanos as u64).to_le_bytes();
+    }
+    let body = epee::fields_to_vec(&[
+        ("node_data", Field::Object(vec![
+            ("network_id", Field::Blob(network_id.to_vec())),
+            ("my_port", Field::Uint(0)),
+            ("rpc_port", Field::Uint(0)),
+            ("rpc_credits_per_hash", Field::Uint(0)),
+            ("peer_id", Field::Uint(u64::from_le_bytes(peer_id))),
+            ("support_flags", Field::Uint(SUPPORT_FLAGS)),
+        ])),
+        ("payload_data", Field::Object(vec![
+            ("current_height", Field::Uint(1)),
+            ("cumulative_difficulty", Field::Uint(0)),
+            ("cumulative_difficulty_top64", Field::Uint(0)),
+            ("top_id", Field::Blob(vec![0; 32])),
+            ("top_version", Field::Uint(1)),
+            ("pruning_seed", Field::Uint(0)),
+        ])),
+    ])
+    .map_err(LevinError::Epee)?;
+
+    let mut request = header(body.len(), COMMAND_HANDSHAKE, true, FLAG_REQUEST).to_vec();
+    request.extend_from_slice(&body);
+    stream.write_all(&request).await?;
+
+    // whatever the peer sends before its response, e.g. timed sync requests, is skipped
+    let body = loop {
+        let mut header = [0; HEADER_LEN];
+        stream.read_exact(&mut header).await?;
+        if u64::from_le_bytes(header[0..8].try_into().unwrap()) != SIGNATURE {
+            return Err(LevinError::InvalidSignature);
+        }
+        let body_len = u64::from_le_bytes(header[8..16].try_into().unwrap());
+        if body_len > MAX_BODY_SIZE {
+            return Err(LevinError::PacketTooLarge);
+        }
+        let command = u32::from_le_bytes(header[17..21].try_into().unwrap());
+        let return_code = i32::from_le_bytes(header[21..25].try_into().unwrap());
+        let flags = u32::from_le_bytes(header[25..29].try_into().unwrap());
+
+        let mut body = vec![0; body_len as usize];
+        stream.read_exact(&mut body).await?;
+        if command != COMMAND_HANDSHAKE || flags & FLAG_RESPONSE == 0 {
+            continue;
+        }
+        if return_code < 0 {
+            return Err(LevinError::Rejected(return_code));
+        }
+        break body;
+    };
+
+    let response = epee::from_slice(&body).map_err(LevinError::Epee)?;
+    let response: HandshakeResponse = serde_json::from_value(response)
+        .map_err(|_| LevinError::InvalidResponse)?;
+    Ok(Handshake {
+        current_height: response.payload_data.current_height,
+        top_id: response.payload_data.top_id,
+        top_version: response.payload_data.top_version,
+        peers: response.local_peerlist_new.iter().filter_map(|entry| entry.adr.socket_addr()).collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tokio::net::TcpListener;
+
+    // a peer answering the handshake with the frames, after reading the request
+    async fn handshake_with(frames: Vec<Vec<u8>>) -> Result<Handshake, LevinError> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peer = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0; HEADER_LEN];
+            stream.read_exact(&mut request).await.unwrap();
+            let expected = header(0, COMMAND_HANDSHAKE, true, FLAG_REQUEST);
+            assert_eq!(request[..8], expected[..8]);
+            assert_eq!(request[16..], expected[16..]);
+            let body_len = u64::from_le_bytes(request[8..16].try_into().unwrap());
+            let mut body = vec![0; body_len as usize];
+            stream.read_exact(&mut body).await.unwrap();
+            epee::from_slice(&body).unwrap();
+            for frame in frames {
+                stream.write_all(&frame).await.unwrap();
+            }
+        });
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let handshake = handshake(&mut stream, network_id("mainnet").unwrap()).await;
+        peer.await.unwrap();
+        handshake
+    }
+
+    fn frame(command: u32, flags: u32, return_code: i32, body: &[u8]) -> Vec<u8> {
+        let mut frame = header(body.len(), command, false, flags).to_vec();
+        frame[21..25].copy_from_slice(&return_code.to_le_bytes());
+        frame.extend_from_slice(body);
+        frame
+    }
+
+    #[tokio::test]
+    async fn reads_the_handshake_response() {
+        let body = epee::fields_to_vec(&[
+            ("node_data", Field::Object(vec![("peer_id", Field::Uint(1))])),
+            ("payload_data", Field::Object(vec![
+                ("current_height", Field::Uint(3_000_000)),
+                ("top_id", Field::Blob(vec![0xab; 32])),
+                ("top_version", Field::Uint(16)),
+            ])),
+        ]).unwrap();
+        // a timed sync request from the peer comes first
+        let frames = vec![
+            frame(1002, FLAG_REQUEST, 0, &[0; 10]),
+            frame(COMMAND_HANDSHAKE, FLAG_RESPONSE, 0, &body),
+        ];
+        let handshake = handshake_with(frames).await.unwrap();
+        assert_eq!(handshake.current_height, 3_000_000);
+        assert_eq!(handshake.top_id, "ab".repeat(32));
+        assert_eq!(handshake.top_version, 16);
+        assert!(handshake.peers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_responses() {
+        let rejected = handshake_with(vec![frame(COMMAND_HANDSHAKE, FLAG_RESPONSE, -1, &[])]).await;
+        assert!(matches!(rejected, Err(LevinError::Rejected(-1))));
+
+        let mut unsigned = frame(COMMAND_HANDSHAKE, FLAG_RESPONSE, 0, &[]);
+        unsigned[0] = 0;
+        assert!(matches!(handshake_with(vec![unsigned]).await, Err(LevinError::InvalidSignature)));
+
+        let mut large = frame(COMMAND_HANDSHAKE, FLAG_RESPONSE, 0, &[]);
+        large[8..16].copy_from_slice(&(MAX_BODY_SIZE + 1).to_le_bytes());
+        assert!(matches!(handshake_with(vec![large]).await, Err(LevinError::PacketTooLarge)));
+
+        let body = epee::fields_to_vec(&[("payload_data", Field::Object(Vec::new()))]).unwrap();
+        let incomplete = handshake_with(vec![frame(COMMAND_HANDSHAKE, FLAG_RESPONSE, 0, &body)]).await;
+        assert!(matches!(incomplete, Err(LevinError::InvalidResponse)));
+    }
+
+    fn socket_addr(type_: u64, addr: Value) -> Option<SocketAddr> {
+        Address { type_, addr }.socket_addr()
+    }
+
+    #[test]
+    fn reads_peer_addresses() {
+        // 1.2.3.4 as monerod stores it in memory
+        let ipv4 = socket_addr(ADDRESS_IPV4, json!({"m_ip": 0x0403_0201, "m_port": 18080}));
+        assert_eq!(ipv4, Some("1.2.3.4:18080".parse().unwrap()));
+
+        let ipv6 = json!({"addr": "20010db8000000000000000000000001", "m_port": 18080});
+        assert_eq!(socket_addr(ADDRESS_IPV6, ipv6), Some("[2001:db8::1]:18080".parse().unwrap()));
+        // a blob of printable bytes isn't hex-encoded
+        let text = json!({"addr": "abcdefghijklmnop", "m_port": 1});
+        let ip = Ipv6Addr::from(*b"abcdefghijklmnop");
+        assert_eq!(socket_addr(ADDRESS_IPV6, text), Some(SocketAddr::new(ip.into(), 1)));
+
+        let onion = json!({"host": "example.onion", "port": 18083});
+        assert_eq!(socket_addr(4, onion), None);
+        assert_eq!(socket_addr(ADDRESS_IPV4, json!({"m_ip": 1, "m_port": 70000})), None);
+        assert_eq!(socket_addr(ADDRESS_IPV6, json!({"addr": "0001", "m_port": 1})), None);
+    }
+}
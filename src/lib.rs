@@ -0,0 +1,28 @@
+// the exporter as a library, so that it can be embedded along with custom collectors
+pub mod allowlist;
+pub mod auth;
+pub mod cli;
+pub mod client;
+pub mod compression;
+pub mod config;
+pub mod digest;
+pub mod discovery;
+pub mod epee;
+pub mod expression;
+pub mod graphite;
+pub mod help;
+pub mod hex;
+pub mod levin;
+pub mod limit;
+pub mod logtail;
+pub mod metrics;
+pub mod pinning;
+pub mod probe;
+pub mod process;
+pub mod prometheus;
+pub mod protobuf;
+pub mod server;
+pub mod socks;
+pub mod systemd;
+pub mod transport;
+pub mod zmq;
@@ -0,0 +1,159 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use warp::{
+    Filter, Rejection, Reply,
+    http::{StatusCode, header::RETRY_AFTER},
+    reject::Reject,
+};
+
+use crate::server::remote_addr;
+
+// buckets of clients that have been quiet for long enough are dropped past this many clients
+const MAX_TRACKED_CLIENTS: usize = 1024;
+
+#[derive(Debug)]
+pub struct TooManyRequests {
+    retry_after_secs: u64,
+}
+
+impl Reject for TooManyRequests {}
+
+#[derive(Debug)]
+pub struct TooManyScrapes;
+
+impl Reject for TooManyScrapes {}
+
+#[derive(Debug)]
+pub struct BodyNotAllowed;
+
+impl Reject for BodyNotAllowed {}
+
+// held until the response is produced
+pub struct Permit {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+// token bucket per client address that holds up to a minute worth of requests
+#[derive(Debug)]
+struct RateLimiter {
+    per_minute: f64,
+    buckets: Mutex<HashMap<Option<IpAddr>, Bucket>>,
+}
+
+impl RateLimiter {
+    fn refill(&self, bucket: &mut Bucket, now: Instant) {
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.per_minute / 60.0).min(self.per_minute);
+        bucket.updated_at = now;
+    }
+
+    // the number of seconds until the next request is allowed on failure
+    fn check(&self, ip: Option<IpAddr>) -> Result<(), u64> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() >= MAX_TRACKED_CLIENTS && !buckets.contains_key(&ip) {
+            buckets.retain(|_, bucket| {
+                self.refill(bucket, now);
+                bucket.tokens < self.per_minute
+            });
+        }
+
+        let per_minute = self.per_minute;
+        let bucket = buckets.entry(ip).or_insert(Bucket { tokens: per_minute, updated_at: now });
+        self.refill(bucket, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) * 60.0 / per_minute).ceil() as u64)
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Limits {
+    concurrency: Option<Arc<Semaphore>>,
+    rate: Option<RateLimiter>,
+}
+
+impl Limits {
+    // 0 disables the respective limit
+    pub fn new(max_concurrent: u32, per_minute: u32) -> Limits {
+        Limits {
+            concurrency: Some(max_concurrent)
+                .filter(|max| *max > 0)
+                .map(|max| Arc::new(Semaphore::new(max as usize))),
+            rate: Some(per_minute).filter(|rate| *rate > 0).map(|rate| RateLimiter {
+                per_minute: rate as f64,
+                buckets: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    fn acquire(&self, remote_addr: Option<SocketAddr>) -> Result<Permit, Rejection> {
+        // requests without an address, e.g. on Unix sockets, share a single bucket
+        if let Some(rate) = &self.rate {
+            rate.check(remote_addr.map(|addr| addr.ip()))
+                .map_err(|retry_after_secs| warp::reject::custom(TooManyRequests { retry_after_secs }))?;
+        }
+
+        match &self.concurrency {
+            None => Ok(Permit { _permit: None }),
+            Some(semaphore) => semaphore.clone().try_acquire_owned()
+                .map(|permit| Permit { _permit: Some(permit) })
+                .map_err(|_| warp::reject::custom(TooManyScrapes)),
+        }
+    }
+}
+
+pub fn throttle(limits: Arc<Limits>) -> impl Filter<Extract = (Permit,), Error = Rejection> + Clone {
+    remote_addr()
+        .and_then(move |remote_addr: Option<SocketAddr>| {
+            let result = limits.acquire(remote_addr);
+            async move { result }
+        })
+}
+
+// requests that don't need a body get 413 when they come with one instead of it being read
+pub fn no_body() -> impl Filter<Extract = (), Error = Rejection> + Copy {
+    warp::header::optional::<u64>("content-length")
+        .and(warp::header::optional::<String>("transfer-encoding"))
+        .and_then(|content_length: Option<u64>, transfer_encoding: Option<String>| async move {
+            if content_length.unwrap_or(0) > 0 || transfer_encoding.is_some() {
+                Err(warp::reject::custom(BodyNotAllowed))
+            } else {
+                Ok(())
+            }
+        })
+        .untuple_one()
+}
+
+// rate limited clients are told when to come back
+pub async fn handle_rejection(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    if let Some(TooManyRequests { retry_after_secs }) = rejection.find::<TooManyRequests>() {
+        let reply = warp::reply::with_status("too many requests", StatusCode::TOO_MANY_REQUESTS);
+        let reply = warp::reply::with_header(reply, RETRY_AFTER, retry_after_secs.to_string());
+        return Ok(reply.into_response());
+    }
+    if rejection.find::<BodyNotAllowed>().is_some() {
+        let reply = warp::reply::with_status("request body not allowed", StatusCode::PAYLOAD_TOO_LARGE);
+        return Ok(reply.into_response());
+    }
+    if rejection.find::<TooManyScrapes>().is_some() {
+        let reply = "too many concurrent scrapes";
+        return Ok(warp::reply::with_status(reply, StatusCode::SERVICE_UNAVAILABLE).into_response());
+    }
+    Err(rejection)
+}
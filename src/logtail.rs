@@ -0,0 +1,110 @@
+use std::{
+    fs::{self, File, Metadata},
+    io::{self, Read, Seek, SeekFrom},
+    path::PathBuf,
+    time::Duration,
+};
+
+pub const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// a longer line is split, monerod only writes such at the highest log levels
+const MAX_LINE_SIZE: usize = 64 * 1024;
+
+// lines of monerod's log are tab-separated: time, thread, level, category, location and message
+pub const DEFAULT_LOG_PATTERNS: &[(&str, &str)] = &[
+    ("error", r"\tERROR\t"),
+    ("warning", r"\tWARNING\t"),
+    ("ban", r"(Host|Subnet) \S+ blocked"),
+    ("synced", r"Synced \d+/\d+"),
+    ("synchronized", r"SYNCHRONIZED OK"),
+    ("reorganize", r"REORGANIZE SUCCESS"),
+];
+
+// the same file while it has the same inode, a rotated log is replaced by another one
+#[cfg(unix)]
+fn file_id(metadata: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+// only a truncated log is told apart
+#[cfg(not(unix))]
+fn file_id(_metadata: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+#[derive(Debug)]
+struct OpenLog {
+    file: File,
+    id: Option<(u64, u64)>,
+    position: u64,
+}
+
+// a log followed like tail -F does: from its end when it's first opened, and from the start of
+// the files which replace it once it's rotated or truncated
+#[derive(Debug)]
+pub struct LogTail {
+    path: PathBuf,
+    log: Option<OpenLog>,
+    // whether opening it was tried already
+    opened: bool,
+    // of the last line, until it's complete
+    partial: Vec<u8>,
+}
+
+impl LogTail {
+    pub fn new(path: PathBuf) -> LogTail {
+        LogTail { path, log: None, opened: false, partial: Vec::new() }
+    }
+
+    // the complete lines written since the last call
+    pub fn read_lines(&mut self) -> io::Result<Vec<String>> {
+        let mut lines = Vec::new();
+        let metadata = fs::metadata(&self.path);
+        let replaced = match (&self.log, &metadata) {
+            (Some(log), Ok(metadata)) => file_id(metadata) != log.id || metadata.len() < log.position,
+            _ => false,
+        };
+
+        // the lines written before the log was rotated are still read off the old file
+        if let Some(log) = &mut self.log {
+            read_lines(log, &mut self.partial, &mut lines)?;
+        }
+        if replaced {
+            self.log = None;
+            if !self.partial.is_empty() {
+                lines.push(String::from_utf8_lossy(&self.partial).into_owned());
+                self.partial.clear();
+            }
+        }
+
+        if self.log.is_none() {
+            // a log created after the first try is new, so it's read from the start
+            let opened = std::mem::replace(&mut self.opened, true);
+            let metadata = metadata?;
+            let mut file = File::open(&self.path)?;
+            let position = if opened { 0 } else { metadata.len() };
+            file.seek(SeekFrom::Start(position))?;
+            let mut log = OpenLog { file, id: file_id(&metadata), position };
+            read_lines(&mut log, &mut self.partial, &mut lines)?;
+            self.log = Some(log);
+        }
+        Ok(lines)
+    }
+}
+
+fn read_lines(log: &mut OpenLog, partial: &mut Vec<u8>, lines: &mut Vec<String>) -> io::Result<()> {
+    let mut data = Vec::new();
+    log.position += log.file.read_to_end(&mut data)? as u64;
+
+    for byte in data {
+        if byte == b'\n' || partial.len() == MAX_LINE_SIZE {
+            lines.push(String::from_utf8_lossy(partial).trim_end_matches('\r').to_string());
+            partial.clear();
+        }
+        if byte != b'\n' {
+            partial.push(byte);
+        }
+    }
+    Ok(())
+}
@@ -1,18 +1,45 @@
-mod client;
-mod config;
-mod metrics;
-mod prometheus;
-
-use reqwest::{Certificate, ClientBuilder};
-use tracing::{debug, warn};
+use bytes::Bytes;
+use native_tls::{Certificate, Identity, TlsConnector};
+use regex::Regex;
+use reqwest::{
+    ClientBuilder, Proxy, Url,
+    header::{HeaderMap, HeaderName, HeaderValue, USER_AGENT},
+};
+use tracing::{debug, info, warn};
 use tracing_subscriber::{prelude::*, EnvFilter};
-use std::{env, error, fmt, fs, net::SocketAddr, pin::Pin, sync::Arc, time::Duration};
-use tokio::{net::lookup_host, select};
-use warp::{Filter, Future, http::StatusCode};
+use std::{
+    collections::HashMap, convert::Infallible, error, fmt, fs, future, sync::Arc, time::Duration,
+};
+use tokio::{select, signal::ctrl_c};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use warp::{
+    Filter, Reply,
+    http::{
+        Response, StatusCode,
+        header::{AGE, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_TYPE, VARY, WARNING},
+    },
+    path::FullPath,
+};
 
-use client::Client;
-use metrics::{Exporter, Publisher};
-use crate::config::{Config, ConfigLoadError, MonerodConfig, ServerConfig};
+use monerod_exporter::{
+    allowlist::{self, allowed},
+    auth::{Auth, BasicAuth, authorization, handle_rejection},
+    cli,
+    client::{Client, RetryPolicy},
+    compression::Encoding,
+    config::{Config, ConfigLoadError, MonerodConfig, ServerConfig, UNIX_SOCKET_PREFIX, url_name},
+    discovery::{self, NameserverResolver},
+    graphite,
+    limit::{self, Limits, Permit, no_body, throttle},
+    metrics::{CircuitBreaker, Exporter, MetricNaming, PeerSurvey, Publisher, ServedMetrics},
+    pinning::pinned_tls_config,
+    probe::{self, OnionProbe, P2P_PORT, PortChecker, TcpProbe, parse_address},
+    prometheus::Format,
+    server::{self, Routes},
+    transport::Transport,
+    systemd::{self, Notifier},
+};
 
 enum Error {
     Config(ConfigLoadError),
@@ -48,8 +75,6 @@ impl fmt::Debug for Error {
     }
 }
 
-type Server = dyn FnOnce(SocketAddr) -> Pin<Box<dyn Future<Output = ()>>>;
-
 fn init_tracing() {
     let filter_layer = EnvFilter::try_from_default_env()
         .or_else(|_| EnvFilter::try_new("info"))
@@ -62,94 +87,475 @@ fn init_tracing() {
         .init();
 }
 
-fn create_publisher(
-    refresh_interval: Duration,
-    block_spans: Vec<u32>,
-    config: MonerodConfig,
-) -> Result<Publisher, Box<dyn std::error::Error>> {
-    let mut http_client = ClientBuilder::new().timeout(config.timeout);
+// built anew when the DNS is refreshed
+fn create_http_client(monerod_config: &MonerodConfig) -> Result<reqwest::Client, Box<dyn error::Error>> {
+    let mut http_client = ClientBuilder::new().timeout(monerod_config.timeout);
+
+    if let Some(connect_timeout) = monerod_config.connect_timeout {
+        http_client = http_client.connect_timeout(connect_timeout);
+    }
 
-    if let Some(tls_cert_path) = config.tls_cert_path {
-        let cert_data = fs::read(tls_cert_path)?;
-        let cert = Certificate::from_pem(&cert_data)?;
-        http_client = http_client.add_root_certificate(cert);
+    http_client = http_client.pool_idle_timeout(monerod_config.pool_idle_timeout)
+        .tcp_keepalive(monerod_config.tcp_keepalive);
+    if let Some(max_idle) = monerod_config.pool_max_idle_per_host {
+        http_client = http_client.pool_max_idle_per_host(max_idle);
     }
 
-    if config.skip_tls_verification {
-        warn!("TLS verification disabled for Monero RPC client");
-        http_client = http_client
-            .danger_accept_invalid_hostnames(true)
-            .danger_accept_invalid_certs(true);
+    // monerod itself only speaks HTTP/1, over TLS HTTP/2 is negotiated with ALPN below
+    http_client = match monerod_config.http2 {
+        false => http_client.http1_only(),
+        true if monerod_config.base_url.starts_with("http://") => http_client.http2_prior_knowledge(),
+        true => http_client,
+    };
+
+    if let Some(nameserver) = monerod_config.nameserver {
+        http_client = http_client.dns_resolver(Arc::new(NameserverResolver { nameserver }));
+    }
+
+    // reqwest takes the proxy from the environment unless it's given one, socks5h:// included
+    match &monerod_config.proxy {
+        Some(proxy) => http_client = http_client.proxy(Proxy::all(proxy.as_str())?),
+        None if !monerod_config.env_proxy => http_client = http_client.no_proxy(),
+        None => {},
+    }
+
+    let identity = match (&monerod_config.tls_client_cert_path, &monerod_config.tls_client_key_path) {
+        (Some(cert_path), Some(key_path)) => Some((fs::read(cert_path)?, fs::read(key_path)?)),
+        _ => None,
+    };
+
+    // the key of the certificate is checked during the handshake instead of its chain,
+    // which native-tls can't do
+    if !monerod_config.tls_pinned_keys.is_empty() {
+        let identity = identity.as_ref().map(|(cert, key)| (cert.as_slice(), key.as_slice()));
+        let mut tls = pinned_tls_config(monerod_config.tls_pinned_keys.clone(), identity)?;
+        if monerod_config.http2 {
+            tls.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        }
+        http_client = http_client.use_preconfigured_tls(tls);
+    } else {
+        // the identities reqwest takes with native-tls are PKCS #12 only,
+        // so the connector is set up here
+        let mut tls = TlsConnector::builder();
+
+        if let Some(tls_cert_path) = &monerod_config.tls_cert_path {
+            let cert_data = fs::read(tls_cert_path)?;
+            let cert = Certificate::from_pem(&cert_data)?;
+            tls.add_root_certificate(cert);
+        }
+
+        if let Some((cert_data, key_data)) = &identity {
+            tls.identity(Identity::from_pkcs8(cert_data, key_data)?);
+        }
+
+        if monerod_config.skip_tls_verification {
+            warn!("TLS verification disabled for Monero RPC client");
+            tls.danger_accept_invalid_hostnames(true)
+                .danger_accept_invalid_certs(true);
+        }
+
+        if monerod_config.http2 {
+            tls.request_alpns(&["h2", "http/1.1"]);
+        }
+
+        http_client = http_client.use_preconfigured_tls(tls.build()?);
+    }
+
+    // the connection is still made to the host of the URL, but as the server name
+    if let Some(server_name) = &monerod_config.tls_server_name {
+        let url = Url::parse(&monerod_config.base_url)?;
+        http_client = http_client.resolve_to_addrs(server_name, &url.socket_addrs(|| None)?);
+    }
+
+    Ok(http_client.build()?)
+}
+
+fn create_exporter(
+    config: &Config,
+    monerod_config: &MonerodConfig,
+) -> Result<Exporter, Box<dyn std::error::Error>> {
+    let mut base_url = monerod_config.base_url.clone();
+    if let Some(server_name) = &monerod_config.tls_server_name {
+        let mut url = Url::parse(&base_url)?;
+        url.set_host(Some(server_name))?;
+        base_url = url.as_str().trim_end_matches('/').to_string();
+    }
+
+    // both were validated with the config
+    let mut headers = HeaderMap::new();
+    if let Some(user_agent) = &monerod_config.user_agent {
+        headers.insert(USER_AGENT, HeaderValue::from_str(user_agent)?);
+    }
+    for (name, value) in &monerod_config.headers {
+        headers.insert(HeaderName::from_bytes(name.as_bytes())?, HeaderValue::from_str(value)?);
+    }
+
+    // the RPC of daemons on the same host may be proxied to a Unix socket
+    let transport = match monerod_config.base_url.strip_prefix(UNIX_SOCKET_PREFIX) {
+        Some(path) => Transport::unix(path, monerod_config.timeout)?,
+        None => create_http_client(monerod_config)?.into(),
+    };
+    let mut client = Client::new(
+        transport,
+        base_url,
+        monerod_config.rpc_login.clone(),
+        monerod_config.max_concurrent_calls,
+        monerod_config.latency_buckets.clone(),
+        RetryPolicy {
+            retries: monerod_config.retries,
+            delay: monerod_config.retry_delay,
+            jitter: monerod_config.retry_jitter,
+        },
+    )
+    .with_method_timeouts(monerod_config.method_timeouts.clone())
+    .with_max_response_size(monerod_config.max_response_size)
+    .with_compression(monerod_config.accept_compression)
+    .with_headers(headers);
+    match monerod_config.dns_refresh_interval {
+        Some(interval) if !monerod_config.base_url.starts_with(UNIX_SOCKET_PREFIX) => {
+            let monerod_config = monerod_config.clone();
+            client = client.with_transport_refresh(interval, move || {
+                Ok(create_http_client(&monerod_config)?.into())
+            });
+        },
+        _ => {},
+    }
+    let mut exporter = Exporter::new(
+        client,
+        monerod_config.name.clone(),
+        // discovery may find several targets
+        config.monerod.len() > 1 || config.discovery.is_some(),
+        MetricNaming::new(config),
+        config.block_spans.clone(),
+        config.empty_spans,
+        monerod_config.disabled_collectors.clone(),
+    );
+    if let Some(zmq_url) = &monerod_config.zmq_url {
+        exporter = exporter.with_zmq_url(zmq_url.clone());
+    }
+    if let Some(p2p_address) = &monerod_config.p2p_address {
+        let timeout = monerod_config.connect_timeout.unwrap_or(monerod_config.timeout);
+        // validated with the config
+        let probe = TcpProbe::new(p2p_address, P2P_PORT, timeout).ok_or("invalid P2P address")?;
+        exporter = exporter.with_p2p_probe(probe);
+        if monerod_config.p2p_handshake {
+            exporter = exporter.with_p2p_handshake();
+        }
+        if monerod_config.p2p_survey_peers > 0 {
+            exporter = exporter.with_peer_survey(PeerSurvey {
+                peers: monerod_config.p2p_survey_peers,
+                interval: monerod_config.p2p_survey_interval,
+            });
+        }
+    }
+    // the proxy is set along with the address, both validated with the config
+    let onion = (&monerod_config.onion_address, &monerod_config.onion_proxy);
+    if let (Some(onion_address), Some(onion_proxy)) = onion {
+        let onion_proxy = Url::parse(onion_proxy)?;
+        let probe = OnionProbe::new(onion_address, P2P_PORT, &onion_proxy, monerod_config.onion_timeout)
+            .ok_or("invalid onion address or proxy")?;
+        exporter = exporter.with_onion_probe(probe, monerod_config.onion_check_interval);
+    }
+    if let Some(port_checker) = &monerod_config.port_checker {
+        let port_checker = PortChecker::new(port_checker).ok_or("invalid port checker URL")?;
+        let public_ports = monerod_config.public_ports.iter()
+            .filter_map(|address| Some((parse_address(address, 0)?.1, address.clone())))
+            .collect();
+        exporter = exporter.with_port_checker(
+            port_checker,
+            public_ports,
+            monerod_config.port_check_interval,
+        );
+    }
+    if let Some(log_path) = &monerod_config.log_path {
+        let log_patterns = monerod_config.log_patterns.iter()
+            .map(|(name, pattern)| Ok((name.clone(), Regex::new(pattern)?)))
+            .collect::<Result<_, regex::Error>>()?;
+        exporter = exporter.with_log_tail(log_path.clone(), log_patterns);
     }
+    if monerod_config.circuit_breaker_failures > 0 {
+        exporter = exporter.with_circuit_breaker(CircuitBreaker {
+            failures: monerod_config.circuit_breaker_failures,
+            cooldown: monerod_config.circuit_breaker_cooldown,
+        });
+    }
+
+    Ok(exporter)
+}
+
+fn create_publisher(config: &Config) -> Result<Publisher, Box<dyn std::error::Error>> {
+    let exporters = config.monerod.iter()
+        .map(|monerod_config| create_exporter(config, monerod_config))
+        .collect::<Result<Vec<_>, _>>()?;
 
-    let http_client = http_client.build()?;
-    let client = Client::new(http_client, config.base_url);
-    let exporter = Exporter::new(client, block_spans);
-    let publisher = Publisher::new(exporter, refresh_interval);
+    // only get_info is called on reference nodes, so public ones with restricted RPC do
+    let http_client = ClientBuilder::new().timeout(config.reference_timeout).build()?;
+    let references = config.reference_nodes.iter()
+        .map(|url| {
+            let client = Client::new(
+                http_client.clone(),
+                url.clone(),
+                None,
+                0,
+                Vec::new(),
+                RetryPolicy::default(),
+            );
+            (url_name(url), client)
+        })
+        .collect();
 
-    Ok(publisher)
+    Ok(Publisher::new(exporters, references, config))
 }
 
-fn create_server(
+fn create_routes(
     publisher: Arc<Publisher>,
-    config: ServerConfig,
-) -> Result<Box<Server>, Box<dyn error::Error>> {
-    let filter = warp::any()
-        .map(move || match publisher.get_metrics() {
-            None => warp::reply::with_status(String::new(), StatusCode::SERVICE_UNAVAILABLE),
-            Some(metrics) => warp::reply::with_status(metrics, StatusCode::OK),
+    config: &ServerConfig,
+) -> Result<Routes, Box<dyn error::Error>> {
+    // paths are matched before methods, so that unknown paths get 404 and other methods 405
+    // the process is alive as long as it answers
+    let health = warp::path!("health")
+        .and(warp::get())
+        .map(|| "OK");
+
+    let ready_publisher = publisher.clone();
+    let ready = warp::path!("ready")
+        .and(warp::get())
+        .map(move || {
+            if ready_publisher.is_ready() {
+                warp::reply::with_status("OK", StatusCode::OK)
+            } else {
+                warp::reply::with_status("not ready", StatusCode::SERVICE_UNAVAILABLE)
+            }
         });
 
-    Ok(Box::new(move |socket_addr| {
-        if config.tls_key_path.is_some() {
-            let mut server = warp::serve(filter).tls();
-            if let Some(path) = config.tls_key_path {
-                server = server.key_path(path);
+    let bearer_token = match &config.bearer_token_path {
+        None => None,
+        Some(path) => Some(fs::read_to_string(path)?.trim().to_string()),
+    };
+    if bearer_token.as_deref() == Some("") {
+        return Err("bearer token file is empty".into());
+    }
+
+    let auth = Arc::new(Auth {
+        basic: config.basic_auth.as_ref().map(BasicAuth::new),
+        bearer_token,
+    });
+
+    let auth_filter = authorization(auth);
+    let allowed_filter = allowed(Arc::new(config.allowed_cidrs.clone()));
+    // limits are checked before auth on every route that has it, so that spamming clients
+    // don't get to make bcrypt busy
+    let limits = Arc::new(Limits::new(config.max_concurrent_scrapes, config.rate_limit_per_minute));
+
+    // gets the same access control as the metrics
+    let status_publisher = publisher.clone();
+    let status = warp::path!("status")
+        .and(warp::get())
+        .and(allowed_filter.clone())
+        .and(throttle(limits.clone()))
+        .and(auth_filter.clone())
+        .map(move |_permit: Permit| warp::reply::json(&status_publisher.status()));
+
+    // anyone allowed to could have the exporter connect anywhere, so it's off unless turned on
+    let port_checks = config.port_checks;
+    let check = warp::path!("check")
+        .and(warp::get())
+        .and_then(move || async move {
+            match port_checks {
+                true => Ok(()),
+                false => Err(warp::reject::not_found()),
             }
-            if let Some(path) = config.tls_cert_path {
-                server = server.cert_path(path);
+        })
+        .untuple_one()
+        .and(allowed_filter.clone())
+        .and(throttle(limits.clone()))
+        .and(auth_filter.clone())
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(|permit: Permit, query: HashMap<String, String>| async move {
+            let check = match query.get("address") {
+                Some(address) => probe::check_port(address).await,
+                None => None,
+            };
+            drop(permit);
+            let reply = match check {
+                Some(check) => warp::reply::json(&check).into_response(),
+                None => {
+                    warp::reply::with_status("address has to be host:port", StatusCode::BAD_REQUEST)
+                        .into_response()
+                },
+            };
+            Ok::<_, Infallible>(reply)
+        });
+
+    let metrics = exact_path(config.metrics_path.clone())
+        .and(warp::get())
+        .and(no_body())
+        .and(allowed_filter)
+        .and(throttle(limits))
+        .and(auth_filter)
+        .and(warp::header::optional::<String>("accept"))
+        .and(warp::header::optional::<String>("accept-encoding"))
+        .and(warp::header::optional::<String>("x-prometheus-scrape-timeout-seconds"))
+        .and_then(move |
+            permit: Permit,
+            accept: Option<String>,
+            accept_encoding: Option<String>,
+            scrape_timeout: Option<String>,
+        | {
+            let publisher = publisher.clone();
+            async move {
+                let format = Format::negotiate(accept.as_deref());
+                let encoding = Encoding::negotiate(accept_encoding.as_deref());
+                // an invalid timeout is ignored rather than failing the scrape
+                let scrape_timeout = scrape_timeout
+                    .and_then(|timeout| timeout.parse::<f64>().ok())
+                    .filter(|timeout| timeout.is_finite() && *timeout > 0.0)
+                    .map(Duration::from_secs_f64);
+                let metrics = publisher.scrape(format, encoding, scrape_timeout).await;
+                // the scrape counts against the limits until its metrics are ready
+                drop(permit);
+                Ok::<_, Infallible>(metrics_response(metrics, format, encoding))
             }
-            Box::pin(server.run(socket_addr))
-        } else {
-            Box::pin(warp::serve(filter).run(socket_addr))
+        });
+
+    let routes = health.or(ready).or(status).or(check).or(metrics)
+        .recover(handle_rejection)
+        .recover(limit::handle_rejection)
+        .recover(allowlist::handle_rejection)
+        .map(|reply| Box::new(reply) as Box<dyn Reply>)
+        .boxed();
+
+    Ok(routes)
+}
+
+fn metrics_response(
+    metrics: Option<ServedMetrics>,
+    format: Format,
+    encoding: Encoding,
+) -> Result<Response<Bytes>, warp::http::Error> {
+    let response = Response::builder()
+        .header(CONTENT_TYPE, format.content_type())
+        .header(VARY, "Accept, Accept-Encoding");
+    let metrics = match metrics {
+        None => return response.status(StatusCode::SERVICE_UNAVAILABLE).body(Bytes::new()),
+        Some(metrics) => metrics,
+    };
+
+    let mut response = response
+        .header(AGE, metrics.age.as_secs())
+        .header(CACHE_CONTROL, format!("max-age={}", metrics.max_age.as_secs()));
+    if metrics.stale {
+        response = response.header(WARNING, "110 - \"Response is Stale\"");
+    }
+    if let Some(content_encoding) = encoding.content_encoding() {
+        response = response.header(CONTENT_ENCODING, content_encoding);
+    }
+    response.body(metrics.body)
+}
+
+// like path!, but for a path only known at runtime
+fn exact_path(path: String) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    let path = Arc::new(path);
+    warp::path::full()
+        .and_then(move |full_path: FullPath| {
+            let matches = full_path.as_str() == path.as_str();
+            async move {
+                if matches {
+                    Ok(())
+                } else {
+                    Err(warp::reject::not_found())
+                }
+            }
+        })
+        .untuple_one()
+}
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            },
+            Err(e) => {
+                warn!("failed to listen for SIGTERM: {}", e);
+                future::pending::<()>().await;
+            },
         }
-    }))
+    };
+    #[cfg(not(unix))]
+    let terminate = future::pending::<()>();
+
+    select! {
+        _ = ctrl_c() => {},
+        _ = terminate => {},
+    }
+    info!("shutting down");
 }
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), Error> {
     init_tracing();
 
-    let args = env::args().collect::<Vec<_>>();
-    let args = args.iter().map(|s| s.as_ref()).collect::<Vec<&str>>();
+    let matches = cli::app().get_matches();
 
-    let config_path = match args.as_slice() {
-        [_, "-c", path] => Some(String::from(*path)),
-        _ => dirs::config_dir().and_then(|mut p| {
+    let config_path = match matches.value_of("config") {
+        Some(path) => Some(String::from(path)),
+        None => dirs::config_dir().and_then(|mut p| {
             p.push("monerod-exporter.toml");
             p.to_str().map(String::from)
         }),
     };
 
-    let config = Config::load(config_path.as_deref())
+    let config = Config::load(config_path.as_deref(), &cli::overrides(&matches))
         .map_err(Error::Config)?;
 
     debug!("config: {:?}", config);
 
-    let publisher = create_publisher(config.refresh_interval, config.block_spans, config.monerod)
+    let publisher = create_publisher(&config)
         .map_err(Error::Publisher)?;
     let publisher = Arc::new(publisher);
 
-    let socket_addr = lookup_host(&config.server.host)
-        .await.map_err(|e| Error::Server(e.into()))?
-        .next().ok_or(Error::Server("hostname lookup failed".into()))?;
+    let (listener, activated) = server::bind(&config.server)
+        .await.map_err(Error::Server)?;
+    // a socket passed by systemd is systemd's to clean up
+    let unix_socket_path = config.server.host.strip_prefix(UNIX_SOCKET_PREFIX)
+        .filter(|_| !activated)
+        .map(String::from);
 
-    let server = create_server(publisher.clone(), config.server)
+    let routes = create_routes(publisher.clone(), &config.server)
+        .map_err(Error::Server)?;
+    let tls_config = server::tls_config(&config.server)
         .map_err(Error::Server)?;
+    let server = server::serve(
+        listener,
+        routes,
+        tls_config,
+        &config.server,
+        Box::pin(shutdown_signal()),
+    );
 
+    // the refresh loop is dropped once the server has shut down
     select! {
         _ = publisher.run() => {},
-        _ = server(socket_addr) => {},
+        _ = server => {},
+        _ = systemd::run(publisher.clone(), config.systemd_ready) => {},
+        _ = graphite::run(publisher.clone(), config.graphite.as_ref()) => {},
+        _ = discovery::run(
+            publisher.clone(),
+            config.discovery.as_ref(),
+            |monerod_config| create_exporter(&config, monerod_config),
+        ) => {},
+    }
+
+    if let Some(notifier) = Notifier::from_env() {
+        notifier.notify("STOPPING=1");
+    }
+
+    if let Some(path) = unix_socket_path {
+        if let Err(e) = fs::remove_file(&path) {
+            warn!("failed to remove Unix socket {}: {}", path, e);
+        }
     }
 
     Ok(())
@@ -1,23 +1,487 @@
-use std::{fmt, sync::RwLock, time::Duration};
-use tokio::{time::interval, try_join};
-use tracing::{error, info, instrument};
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
+    fmt, fs, future::{self, Future}, io,
+    net::SocketAddr,
+    pin::Pin,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock, atomic::{AtomicBool, AtomicU64, Ordering}},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use arc_swap::{ArcSwap, ArcSwapOption};
+use bytes::Bytes;
+use regex::Regex;
+use humantime::{format_duration, format_rfc3339_seconds};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use futures_util::{FutureExt, future::join_all};
+use tokio::{
+    sync::{Mutex as AsyncMutex, Notify},
+    join, select,
+    time::{Instant as TokioInstant, interval, interval_at, sleep, timeout_at},
+};
+use tracing::{error, info, info_span, instrument, warn, Instrument};
 
 use crate::{
-    client::{BlockHeader, BlockHeadersRangeRequest, Client, ClientError},
-    prometheus::{Metric, render_metrics},
+    client::{
+        BlockHeader, BlockHeadersRangeRequest, BlockHeadersRangeResponse, Client, ClientError,
+        InfoResponse, PruneBlockchainRequest, TransactionPoolStatsResponse, TransactionsRequest,
+    },
+    compression::{Encoding, compress},
+    config::Config,
+    expression::Expression,
+    help::{get_exporter_help, get_node_help},
+    levin::{self, Handshake},
+    logtail::{self, LogTail},
+    probe::{self, OnionProbe, PortCheck, PortChecker, ProbeError, TcpProbe},
+    process::process_metrics,
+    prometheus::{
+        DEFAULT_BUCKETS, Family, Format, Histogram, Labels, Metric, Registry, RewriteRule,
+    },
+    zmq::{self, Event, TOPIC_CHAIN_MAIN, TOPIC_TXPOOL_ADD},
 };
 
+const ATOMIC_UNITS_PER_XMR: f64 = 1e12;
+
+// block timestamps are those of the miners, so the delays are rough
+const PROPAGATION_DELAY_BUCKETS: [f64; 10] =
+    [1.0, 2.5, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0, 300.0, 600.0];
+
+// blocks whose arrival over ZMQ is remembered, far more than a refresh sees
+const MAX_ARRIVALS: usize = 100;
+
+// of connecting to a surveyed peer and of the handshake, peers far away take their time
+const SURVEY_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Unit {
+    None,
+    Bytes,
+    Seconds,
+    Timestamp,
+    Ratio,
+    Atomic,
+}
+
+// naming layer applied to all node and network metrics
 #[derive(Clone, Debug)]
+pub struct MetricNaming {
+    pub namespace: String,
+    // enforce unit suffixes in metric names
+    pub normalize: bool,
+    // export amounts in XMR instead of atomic units
+    pub xmr_units: bool,
+}
+
+impl MetricNaming {
+    pub fn new(config: &Config) -> MetricNaming {
+        MetricNaming {
+            namespace: config.namespace.clone(),
+            normalize: config.normalize_names,
+            xmr_units: config.xmr_units,
+        }
+    }
+
+    fn name(&self, name: &str, unit: Unit, counter: bool) -> String {
+        let suffix = match unit {
+            Unit::None => None,
+            Unit::Bytes => Some("bytes"),
+            Unit::Seconds => Some("seconds"),
+            Unit::Timestamp => Some("timestamp_seconds"),
+            Unit::Ratio => Some("ratio"),
+            Unit::Atomic if self.xmr_units => Some("xmr"),
+            Unit::Atomic => Some("atomic_units"),
+        };
+
+        match suffix {
+            Some(suffix) if self.normalize => {
+                // the _total of counters stays last, e.g. txpool_received_bytes_total
+                let (name, total) = match name.strip_suffix("_total") {
+                    Some(name) if counter => (name, "_total"),
+                    _ => (name, ""),
+                };
+                // move the unit to the end, e.g. txpool_bytes_max -> txpool_max_bytes
+                let base = name.split('_')
+                    .filter(|part| !suffix.split('_').any(|s| s == *part))
+                    .collect::<Vec<_>>()
+                    .join("_");
+                format!("{}_{}_{}{}", self.namespace, base, suffix, total)
+            },
+            _ => format!("{}_{}", self.namespace, name),
+        }
+    }
+
+    fn value(&self, value: f64, unit: Unit) -> f64 {
+        match unit {
+            Unit::Atomic if self.xmr_units => value / ATOMIC_UNITS_PER_XMR,
+            _ => value,
+        }
+    }
+}
+
+// every node and network metric family with its unit and label names
+const FAMILIES: &[(&str, Unit, &[&str])] = &[
+    ("node_info", Unit::None, &["version", "nettype", "pruned"]),
+    ("node_up", Unit::None, &[]),
+    ("node_circuit_open", Unit::None, &[]),
+    ("node_busy", Unit::None, &[]),
+    ("node_database_size", Unit::Bytes, &[]),
+    ("node_free_space", Unit::Bytes, &[]),
+    ("node_grey_peerlist_size", Unit::None, &[]),
+    ("node_incoming_connections_count", Unit::None, &[]),
+    ("node_offline", Unit::None, &[]),
+    ("node_outgoing_connections_count", Unit::None, &[]),
+    ("node_rpc_connections_count", Unit::None, &[]),
+    ("node_synchronized", Unit::None, &[]),
+    ("node_tx_pool_size", Unit::None, &[]),
+    ("node_white_peerlist_size", Unit::None, &[]),
+    ("network_block_size_limit", Unit::Bytes, &[]),
+    ("network_block_size_median", Unit::Bytes, &[]),
+    ("network_block_weight_limit", Unit::None, &[]),
+    ("network_block_weight_median", Unit::None, &[]),
+    ("network_cumulative_difficulty", Unit::None, &[]),
+    ("network_difficulty", Unit::None, &[]),
+    ("network_height", Unit::None, &[]),
+    ("network_target", Unit::Seconds, &[]),
+    ("network_target_height", Unit::None, &[]),
+    ("network_tx_count", Unit::None, &[]),
+    ("txpool_bytes_max", Unit::Bytes, &[]),
+    ("txpool_bytes_med", Unit::Bytes, &[]),
+    ("txpool_bytes_min", Unit::Bytes, &[]),
+    ("txpool_bytes_total", Unit::Bytes, &[]),
+    ("txpool_double_spends", Unit::None, &[]),
+    ("txpool_txs_failing", Unit::None, &[]),
+    ("txpool_txs_not_relayed", Unit::None, &[]),
+    ("txpool_oldest_tx", Unit::Timestamp, &[]),
+    ("txpool_oldest_tx_age_seconds", Unit::Seconds, &[]),
+    ("txpool_txs_above_10min", Unit::None, &[]),
+    ("txpool_txs_total", Unit::None, &[]),
+    ("blocks_observed", Unit::None, &["block_count"]),
+    ("blocks_avg_txes", Unit::None, &["block_count"]),
+    ("blocks_max_txes", Unit::None, &["block_count"]),
+    ("blocks_avg_reward", Unit::Atomic, &["block_count"]),
+    ("blocks_max_reward", Unit::Atomic, &["block_count"]),
+    ("blocks_avg_size", Unit::Bytes, &["block_count"]),
+    ("blocks_max_size", Unit::Bytes, &["block_count"]),
+    ("blocks_difficulty_change", Unit::Ratio, &["block_count"]),
+    ("blocks_txes", Unit::None, &["block_count", "quantile"]),
+    ("blocks_reward", Unit::Atomic, &["block_count", "quantile"]),
+    ("blocks_size", Unit::Bytes, &["block_count", "quantile"]),
+    ("node_reference_lag", Unit::None, &["reference"]),
+    ("reference_height", Unit::None, &["reference"]),
+    ("cluster_height_spread", Unit::None, &[]),
+    ("cluster_blocks_behind", Unit::None, &["target"]),
+    ("cluster_forked", Unit::None, &["target"]),
+    ("cluster_forked_nodes", Unit::None, &[]),
+    ("node_zmq_connected", Unit::None, &[]),
+    ("p2p_reachable", Unit::None, &[]),
+    ("p2p_connect_duration_seconds", Unit::Seconds, &[]),
+    ("p2p_handshake_succeeded", Unit::None, &[]),
+    ("p2p_handshake_duration_seconds", Unit::Seconds, &[]),
+    ("p2p_top_height", Unit::None, &[]),
+    ("p2p_top_version", Unit::None, &[]),
+    ("p2p_survey_peers", Unit::None, &[]),
+    ("p2p_survey_handshakes", Unit::None, &[]),
+    ("p2p_survey_top_height", Unit::None, &["quantile"]),
+    ("p2p_survey_handshake_duration_seconds", Unit::Seconds, &["quantile"]),
+    ("p2p_survey_top_version_peers", Unit::None, &["top_version"]),
+    ("onion_reachable", Unit::None, &[]),
+    ("onion_connect_duration_seconds", Unit::Seconds, &[]),
+    ("node_port_reachable", Unit::None, &["port"]),
+    ("log_readable", Unit::None, &[]),
+];
+
+// the node metric families which are counters, of the events published over ZMQ and the pool
+const COUNTERS: &[(&str, Unit, &[&str])] = &[
+    ("blocks_published_total", Unit::None, &[]),
+    ("txpool_txs_received_total", Unit::None, &[]),
+    ("txpool_received_bytes_total", Unit::Bytes, &[]),
+    ("txpool_txs_added_total", Unit::None, &[]),
+    ("txpool_txs_mined_total", Unit::None, &[]),
+    ("txpool_txs_dropped_total", Unit::None, &[]),
+    ("txpool_double_spends_detected_total", Unit::None, &[]),
+    ("log_lines_total", Unit::None, &["pattern"]),
+];
+
+// families declared once at startup, only their samples are replaced on every refresh
+#[derive(Debug)]
+struct NodeFamilies {
+    naming: MetricNaming,
+    families: BTreeMap<&'static str, (Unit, Family)>,
+}
+
+impl NodeFamilies {
+    fn new(naming: MetricNaming) -> NodeFamilies {
+        let gauges = FAMILIES.iter().map(|family| (family, false));
+        let counters = COUNTERS.iter().map(|family| (family, true));
+        let families = gauges.chain(counters)
+            .map(|((name, unit, label_names), counter)| {
+                let family = match counter {
+                    false => Family::gauge(naming.name(name, *unit, false), label_names),
+                    true => Family::counter(naming.name(name, *unit, true), label_names),
+                };
+                let family = match get_node_help(name) {
+                    Some(help) => family.with_help(help),
+                    None => {
+                        warn!("metric {} has no description", name);
+                        family
+                    },
+                };
+                (*name, (*unit, family))
+            })
+            .collect();
+
+        NodeFamilies { naming, families }
+    }
+
+    fn clear(&mut self) {
+        for (_, family) in self.families.values_mut() {
+            family.clear();
+        }
+    }
+
+    fn set<V: AsRef<str>>(&mut self, name: &str, label_values: &[V], value: f64) {
+        match self.families.get_mut(name) {
+            Some((unit, family)) => family.set(label_values, self.naming.value(value, *unit)),
+            None => error!("metric {} is not declared", name),
+        }
+    }
+
+    fn set_value(&mut self, name: &str, value: f64) {
+        self.set::<&str>(name, &[], value);
+    }
+
+    fn metric(&self, name: &str) -> Option<Metric> {
+        self.families.get(name)
+            .filter(|(_, family)| !family.is_empty())
+            .map(|(_, family)| family.to_metric())
+    }
+
+    // families without samples in the last refresh are left out
+    fn metrics(&self) -> Vec<Metric> {
+        self.families.values()
+            .filter(|(_, family)| !family.is_empty())
+            .map(|(_, family)| family.to_metric())
+            .collect()
+    }
+}
+
+#[derive(Debug)]
 pub struct Exporter {
     client: Client,
+    name: String,
+    // the target label, only set when there are several targets
+    target_labels: BTreeMap<String, String>,
+    families: Mutex<NodeFamilies>,
+    // headers of the largest block span by height, kept across refreshes
+    block_headers: Mutex<BTreeMap<u64, BlockHeader>>,
+    // height and top block hash of the last export
+    tip: Mutex<Option<(u64, String)>>,
     max_block_span: u32,
     block_spans: Vec<u32>,
+    empty_spans: EmptySpanPolicy,
+    disabled_collectors: Vec<String>,
+    custom_collectors: CollectorRegistry,
+    circuit_breaker: Option<CircuitBreaker>,
+    circuit: Mutex<CircuitState>,
+    zmq_url: Option<String>,
+    // the subscription is started once the exporter is shared, and ends once it's dropped
+    subscribed: AtomicBool,
+    events: Mutex<ObservedEvents>,
+    pool_churn: Mutex<PoolChurn>,
+    p2p_probe: Option<TcpProbe>,
+    // whether the last probe connected, so that only changes are logged
+    p2p_reachable: Mutex<Option<bool>>,
+    p2p_handshake: bool,
+    // whether the last handshake succeeded, likewise
+    p2p_handshaken: Mutex<Option<bool>>,
+    peer_survey: Option<PeerSurvey>,
+    survey: Mutex<SurveyState>,
+    onion_probe: Option<OnionProbe>,
+    onion_check_interval: Duration,
+    // the checks are started once the exporter is shared, like the subscription
+    onion_checked: AtomicBool,
+    // none until the first check is done
+    onion: Mutex<Option<Result<Duration, ProbeError>>>,
+    port_checker: Option<PortChecker>,
+    public_ports: Vec<(u16, String)>,
+    port_check_interval: Duration,
+    ports_checked: AtomicBool,
+    // whether the ports were reachable by the last checks, none while the checker failed
+    port_checks: Mutex<BTreeMap<u16, Option<bool>>>,
+    log_path: Option<PathBuf>,
+    log_patterns: Vec<(String, Regex)>,
+    log_tailed: AtomicBool,
+    // whether the log could be read the last time, none until it's been tried
+    log_readable: Mutex<Option<bool>>,
+    // of the lines matching each pattern since the exporter started
+    log_lines: Mutex<BTreeMap<String, u64>>,
+    propagation_delay_name: String,
+    // none until a new block was observed
+    propagation_delays: Mutex<Option<Histogram>>,
+}
+
+// what the ZMQ subscription observed, kept across refreshes
+#[derive(Debug, Default)]
+struct ObservedEvents {
+    connected: bool,
+    blocks: u64,
+    txs: u64,
+    tx_bytes: u64,
+    // when the blocks were published by their hashes, the latest last
+    arrivals: VecDeque<(String, SystemTime)>,
+}
+
+// the transactions in the pool at the last refresh and what became of the others since
+#[derive(Debug, Default)]
+struct PoolChurn {
+    // none until the pool was first seen
+    hashes: Option<HashSet<String>>,
+    added: u64,
+    mined: u64,
+    dropped: u64,
+    double_spends: u64,
+    // none until the pool stats were first got
+    last_double_spends: Option<u64>,
+}
+
+// the pool of a refresh, with the transactions which left it since the last one
+#[derive(Debug)]
+struct PoolChanges {
+    hashes: HashSet<String>,
+    mined: u64,
+    dropped: u64,
+    untrusted: bool,
+}
+
+// the responses of the collectors which are only called once the node is synchronized,
+// none if it isn't or they're disabled
+#[derive(Default)]
+struct SyncedResponses {
+    block_headers: Option<Result<BlockHeadersRangeResponse, ClientError>>,
+    tx_pool_stats: Option<Result<TransactionPoolStatsResponse, ClientError>>,
+    pool_changes: Option<Result<PoolChanges, ClientError>>,
+}
+
+impl PoolChurn {
+    fn apply(&mut self, changes: PoolChanges) {
+        if let Some(hashes) = &self.hashes {
+            self.added += changes.hashes.difference(hashes).count() as u64;
+        }
+        self.mined += changes.mined;
+        self.dropped += changes.dropped;
+        self.hashes = Some(changes.hashes);
+    }
+
+    // the pool only tells how many of its transactions are double spends,
+    // so the ones which come and go between refreshes aren't counted
+    fn observe_double_spends(&mut self, double_spends: u64) {
+        if let Some(last_double_spends) = self.last_double_spends {
+            self.double_spends += double_spends.saturating_sub(last_double_spends);
+        }
+        self.last_double_spends = Some(double_spends);
+    }
+
+    fn set_metrics(&self, families: &mut NodeFamilies) {
+        if self.hashes.is_some() {
+            families.set_value("txpool_txs_added_total", self.added as f64);
+            families.set_value("txpool_txs_mined_total", self.mined as f64);
+            families.set_value("txpool_txs_dropped_total", self.dropped as f64);
+        }
+        if self.last_double_spends.is_some() {
+            families.set_value("txpool_double_spends_detected_total", self.double_spends as f64);
+        }
+    }
+}
+
+// the outcome of a probe of the P2P port, with the handshake when turned on and connected
+#[derive(Debug)]
+struct P2pProbe {
+    connected: Result<Duration, ProbeError>,
+    handshake: Option<Result<(Handshake, Duration), ProbeError>>,
+}
+
+// peers of the node sampled from its handshake, which are handshaken with every interval
+#[derive(Clone, Copy, Debug)]
+pub struct PeerSurvey {
+    pub peers: u32,
+    pub interval: Duration,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct SurveyedPeer {
+    top_height: u64,
+    top_version: u8,
+    handshake_duration: Duration,
+}
+
+// the last survey, exported until the next one
+#[derive(Debug, Default)]
+struct SurveyState {
+    // none until the first survey
+    surveyed_at: Option<Instant>,
+    peers: usize,
+    handshaken: Vec<SurveyedPeer>,
+}
+
+impl SurveyState {
+    fn is_due(&self, interval: Duration) -> bool {
+        !matches!(self.surveyed_at, Some(surveyed_at) if surveyed_at.elapsed() < interval)
+    }
+
+    fn set_metrics(&self, families: &mut NodeFamilies) {
+        if self.surveyed_at.is_none() {
+            return;
+        }
+        families.set_value("p2p_survey_peers", self.peers as f64);
+        families.set_value("p2p_survey_handshakes", self.handshaken.len() as f64);
+        if self.handshaken.is_empty() {
+            return;
+        }
+
+        let heights = Exporter::get_quantiles(self.handshaken.iter().map(|p| p.top_height as f64));
+        let durations = Exporter::get_quantiles(
+            self.handshaken.iter().map(|p| p.handshake_duration.as_secs_f64()));
+        for (i, q) in QUANTILES.iter().enumerate() {
+            let q = q.to_string();
+            families.set("p2p_survey_top_height", &[&q], heights[i]);
+            families.set("p2p_survey_handshake_duration_seconds", &[&q], durations[i]);
+        }
+
+        let mut top_versions = BTreeMap::new();
+        for peer in &self.handshaken {
+            *top_versions.entry(peer.top_version).or_insert(0) += 1;
+        }
+        for (top_version, peers) in top_versions {
+            families.set("p2p_survey_top_version_peers", &[top_version.to_string()], peers as f64);
+        }
+    }
+}
+
+// a target which failed this many exports in a row isn't called until the cooldown is over,
+// so that timeouts don't pile up on a daemon which is resyncing or compacting its database
+#[derive(Clone, Copy, Debug)]
+pub struct CircuitBreaker {
+    pub failures: u32,
+    pub cooldown: Duration,
+}
+
+#[derive(Debug, Default)]
+struct CircuitState {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl CircuitState {
+    fn is_open(&self) -> bool {
+        self.open_until.map(|open_until| Instant::now() < open_until).unwrap_or(false)
+    }
 }
 
 #[derive(Debug)]
 pub enum ExportError {
     Client(ClientError),
-    Renderer(fmt::Error),
+    Stalled,
+    ScrapeTimeout,
     Untrusted,
 }
 
@@ -27,26 +491,102 @@ impl fmt::Display for ExportError {
             ExportError::Client(e) => {
                 write!(f, "monero RPC client error: {}", e)
             },
-            ExportError::Renderer(e) => {
-                write!(f, "rendering error: {}", e)
-            },
+            ExportError::Stalled => f.write_str("export stalled and was aborted by the watchdog"),
+            ExportError::ScrapeTimeout => f.write_str("export didn't finish within the scrape timeout"),
             ExportError::Untrusted => f.write_str("received an untrusted response from node"),
         }
     }
 }
 
+impl ExportError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ExportError::Client(e) => e.kind(),
+            ExportError::Stalled => "stalled",
+            ExportError::ScrapeTimeout => "scrape_timeout",
+            ExportError::Untrusted => "untrusted",
+        }
+    }
+
+    // the message without credentials of the daemon URL
+    pub fn redacted(&self) -> String {
+        match self {
+            ExportError::Client(e) => format!("monero RPC client error: {}", e.redacted()),
+            e => e.to_string(),
+        }
+    }
+}
+
+const QUANTILES: [f64; 3] = [0.5, 0.9, 0.99];
+
+// the collectors which can be disabled, the node info can't since it tells whether the node is up
+pub const COLLECTORS: &[&str] = &["pruning", "txpool", "churn", "blocks"];
+
+pub type CollectFuture<'a> = Pin<Box<dyn Future<Output = Result<Vec<Metric>, ClientError>> + Send + 'a>>;
+
+// a collector of metrics besides the built-in ones, run on every target along with them;
+// its metrics get the target label and its failure only leaves them out
+pub trait Collector: Send + Sync {
+    // the collector label of monerod_exporter_collector_error, also used to disable it
+    fn name(&self) -> &str;
+
+    fn collect<'a>(&'a self, client: &'a Client) -> CollectFuture<'a>;
+}
+
+// the custom collectors of the exporters
+#[derive(Clone, Default)]
+pub struct CollectorRegistry {
+    collectors: Vec<Arc<dyn Collector>>,
+}
+
+impl CollectorRegistry {
+    pub fn register<C: Collector + 'static>(&mut self, collector: C) {
+        self.collectors.push(Arc::new(collector));
+    }
+}
+
+impl fmt::Debug for CollectorRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.collectors.iter().map(|collector| collector.name())).finish()
+    }
+}
+
+// what to export for a block span without any non-orphan blocks, where averages,
+// quantiles and the difficulty change are undefined
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EmptySpanPolicy {
+    // leave the samples of the span out
+    Omit,
+    // export 0, blocks_observed tells it apart from a real 0
+    Zero,
+}
+
 #[derive(Clone, Debug, Default)]
 struct BlocksMetrics {
+    observed: f64,
     avg_txes: f64,
     max_txes: f64,
     avg_reward: f64,
     max_reward: f64,
     avg_size: f64,
     max_size: f64,
+    difficulty_change: f64,
+    txes_quantiles: [f64; QUANTILES.len()],
+    reward_quantiles: [f64; QUANTILES.len()],
+    size_quantiles: [f64; QUANTILES.len()],
 }
 
 impl Exporter {
-    pub fn new(client: Client, block_spans: Vec<u32>) -> Exporter {
+    pub fn new(
+        client: Client,
+        name: String,
+        labeled: bool,
+        naming: MetricNaming,
+        block_spans: Vec<u32>,
+        empty_spans: EmptySpanPolicy,
+        disabled_collectors: Vec<String>,
+    ) -> Exporter {
         let block_spans =
             if block_spans.is_empty() {
                 vec![1]
@@ -56,11 +596,494 @@ impl Exporter {
 
         let max_block_span = block_spans.iter().max().cloned().unwrap_or(1);
 
+        let mut target_labels = BTreeMap::new();
+        if labeled {
+            target_labels.insert("target".to_string(), name.clone());
+        }
+
         Exporter {
             client,
+            name,
+            target_labels,
+            propagation_delay_name: naming.name("block_propagation_delay_seconds", Unit::Seconds, false),
+            families: Mutex::new(NodeFamilies::new(naming)),
+            block_headers: Mutex::new(BTreeMap::new()),
+            tip: Mutex::new(None),
             max_block_span,
             block_spans,
+            empty_spans,
+            disabled_collectors,
+            custom_collectors: CollectorRegistry::default(),
+            circuit_breaker: None,
+            circuit: Mutex::new(CircuitState::default()),
+            zmq_url: None,
+            subscribed: AtomicBool::new(false),
+            events: Mutex::new(ObservedEvents::default()),
+            pool_churn: Mutex::new(PoolChurn::default()),
+            p2p_probe: None,
+            p2p_reachable: Mutex::new(None),
+            p2p_handshake: false,
+            p2p_handshaken: Mutex::new(None),
+            peer_survey: None,
+            survey: Mutex::new(SurveyState::default()),
+            onion_probe: None,
+            onion_check_interval: Duration::from_secs(5 * 60),
+            onion_checked: AtomicBool::new(false),
+            onion: Mutex::new(None),
+            port_checker: None,
+            public_ports: Vec::new(),
+            port_check_interval: Duration::from_secs(60),
+            ports_checked: AtomicBool::new(false),
+            port_checks: Mutex::new(BTreeMap::new()),
+            log_path: None,
+            log_patterns: Vec::new(),
+            log_tailed: AtomicBool::new(false),
+            log_readable: Mutex::new(None),
+            log_lines: Mutex::new(BTreeMap::new()),
+            propagation_delays: Mutex::new(None),
+        }
+    }
+
+    pub fn with_circuit_breaker(mut self, circuit_breaker: CircuitBreaker) -> Exporter {
+        self.circuit_breaker = Some(circuit_breaker);
+        self
+    }
+
+    pub fn with_custom_collectors(mut self, registry: &CollectorRegistry) -> Exporter {
+        self.custom_collectors = registry.clone();
+        self
+    }
+
+    pub fn with_zmq_url(mut self, zmq_url: String) -> Exporter {
+        self.zmq_url = Some(zmq_url);
+        self
+    }
+
+    pub fn with_p2p_probe(mut self, p2p_probe: TcpProbe) -> Exporter {
+        self.p2p_probe = Some(p2p_probe);
+        self
+    }
+
+    pub fn with_p2p_handshake(mut self) -> Exporter {
+        self.p2p_handshake = true;
+        self
+    }
+
+    // the peers come from the handshake, which the survey turns on
+    pub fn with_peer_survey(mut self, peer_survey: PeerSurvey) -> Exporter {
+        self.p2p_handshake = true;
+        self.peer_survey = Some(peer_survey);
+        self
+    }
+
+    pub fn with_onion_probe(mut self, onion_probe: OnionProbe, check_interval: Duration) -> Exporter {
+        self.onion_probe = Some(onion_probe);
+        self.onion_check_interval = check_interval;
+        self
+    }
+
+    pub fn with_port_checker(
+        mut self,
+        port_checker: PortChecker,
+        public_ports: Vec<(u16, String)>,
+        check_interval: Duration,
+    ) -> Exporter {
+        self.port_checker = Some(port_checker);
+        self.public_ports = public_ports;
+        self.port_check_interval = check_interval;
+        self
+    }
+
+    // a line is counted once for every pattern it matches
+    pub fn with_log_tail(mut self, log_path: PathBuf, log_patterns: Vec<(String, Regex)>) -> Exporter {
+        self.log_lines = Mutex::new(log_patterns.iter().map(|(name, _)| (name.clone(), 0)).collect());
+        self.log_path = Some(log_path);
+        self.log_patterns = log_patterns;
+        self
+    }
+
+    // what runs besides the refreshes, once the exporter is shared
+    fn start(self: &Arc<Self>, new_block: &Arc<Notify>) {
+        self.subscribe(new_block);
+        self.check_onion();
+        self.check_ports();
+        self.tail_log();
+    }
+
+    // the subscription only holds a weak reference, so that it ends with the target;
+    // new blocks are notified once observed
+    fn subscribe(self: &Arc<Self>, new_block: &Arc<Notify>) {
+        let zmq_url = match &self.zmq_url {
+            Some(zmq_url) => zmq_url.clone(),
+            None => return,
+        };
+        if self.subscribed.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let exporter = Arc::downgrade(self);
+        let new_block = new_block.clone();
+        tokio::spawn(zmq::run(zmq_url, &[TOPIC_CHAIN_MAIN, TOPIC_TXPOOL_ADD], move |event| {
+            match exporter.upgrade() {
+                Some(exporter) => {
+                    let is_block = matches!(event, Event::ChainMain(_));
+                    exporter.observe(event);
+                    if is_block {
+                        new_block.notify_one();
+                    }
+                    true
+                },
+                None => false,
+            }
+        }));
+    }
+
+    // connecting to an onion service takes too long for a refresh to wait for it,
+    // so it's checked in the background and the last check is exported
+    fn check_onion(self: &Arc<Self>) {
+        let onion_probe = match &self.onion_probe {
+            Some(onion_probe) => onion_probe.clone(),
+            None => return,
+        };
+        if self.onion_checked.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let exporter = Arc::downgrade(self);
+        let check_interval = self.onion_check_interval;
+        tokio::spawn(async move {
+            loop {
+                let result = onion_probe.connect().await;
+                match exporter.upgrade() {
+                    Some(exporter) => exporter.observe_onion(&onion_probe, result),
+                    None => return,
+                }
+                sleep(check_interval).await;
+            }
+        });
+    }
+
+    fn observe_onion(&self, onion_probe: &OnionProbe, result: Result<Duration, ProbeError>) {
+        let mut onion = self.onion.lock().unwrap();
+        let address = onion_probe.address();
+        match (&result, onion.as_ref().map(Result::is_ok)) {
+            (Err(e), Some(true)) | (Err(e), None) => {
+                warn!("onion service {} of {} is unreachable: {}", address, self.name, e);
+            },
+            (Ok(_), Some(false)) => {
+                info!("onion service {} of {} is reachable again", address, self.name)
+            },
+            _ => {},
+        }
+        *onion = Some(result);
+    }
+
+    // the checker only responds once it's done, which may take a while for a dropped port
+    fn check_ports(self: &Arc<Self>) {
+        let port_checker = match &self.port_checker {
+            Some(port_checker) => port_checker.clone(),
+            None => return,
+        };
+        if self.ports_checked.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let exporter = Arc::downgrade(self);
+        let public_ports = self.public_ports.clone();
+        let check_interval = self.port_check_interval;
+        tokio::spawn(async move {
+            loop {
+                let checks = public_ports.iter().map(|(_, address)| port_checker.check(address));
+                let checks = join_all(checks).await;
+                match exporter.upgrade() {
+                    Some(exporter) => {
+                        for ((port, address), check) in public_ports.iter().zip(checks) {
+                            exporter.observe_port(*port, address, check);
+                        }
+                    },
+                    None => return,
+                }
+                sleep(check_interval).await;
+            }
+        });
+    }
+
+    fn observe_port(&self, port: u16, address: &str, check: Result<PortCheck, ProbeError>) {
+        let mut port_checks = self.port_checks.lock().unwrap();
+        let last = port_checks.get(&port).cloned();
+        let reachable = match check {
+            Ok(PortCheck { reachable: false, error }) => {
+                if last.flatten() != Some(false) {
+                    let error = error.unwrap_or_default();
+                    warn!("port {} of {} is unreachable from outside: {}", address, self.name, error);
+                }
+                Some(false)
+            },
+            Ok(PortCheck { reachable: true, .. }) => {
+                if last.flatten() == Some(false) {
+                    info!("port {} of {} is reachable from outside again", address, self.name);
+                }
+                Some(true)
+            },
+            Err(e) => {
+                if last != Some(None) {
+                    warn!("failed to check port {} of {}: {}", address, self.name, e);
+                }
+                None
+            },
+        };
+        port_checks.insert(port, reachable);
+    }
+
+    // the log is polled, reading files blocks so it's done off the runtime
+    fn tail_log(self: &Arc<Self>) {
+        let log_path = match &self.log_path {
+            Some(log_path) => log_path.clone(),
+            None => return,
+        };
+        if self.log_tailed.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let exporter = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut log_tail = LogTail::new(log_path);
+            loop {
+                let read = tokio::task::spawn_blocking(move || {
+                    let lines = log_tail.read_lines();
+                    (log_tail, lines)
+                });
+                let lines = match read.await {
+                    Ok((returned, lines)) => {
+                        log_tail = returned;
+                        lines
+                    },
+                    Err(_) => return,
+                };
+                match exporter.upgrade() {
+                    Some(exporter) => exporter.observe_log(lines),
+                    None => return,
+                }
+                sleep(logtail::POLL_INTERVAL).await;
+            }
+        });
+    }
+
+    fn observe_log(&self, lines: io::Result<Vec<String>>) {
+        let mut log_readable = self.log_readable.lock().unwrap();
+        let log_path = self.log_path.as_deref().unwrap_or_else(|| Path::new("")).display();
+        let lines = match lines {
+            Ok(lines) => {
+                if *log_readable == Some(false) {
+                    info!("log {} of {} is readable again", log_path, self.name);
+                }
+                *log_readable = Some(true);
+                lines
+            },
+            Err(e) => {
+                if *log_readable != Some(false) {
+                    warn!("failed to read log {} of {}: {}", log_path, self.name, e);
+                }
+                *log_readable = Some(false);
+                return;
+            },
+        };
+        let mut log_lines = self.log_lines.lock().unwrap();
+        for line in &lines {
+            for (name, pattern) in &self.log_patterns {
+                if pattern.is_match(line) {
+                    *log_lines.entry(name.clone()).or_default() += 1;
+                }
+            }
+        }
+    }
+
+    fn set_log_metrics(&self, families: &mut NodeFamilies) {
+        if self.log_path.is_none() {
+            return;
+        }
+        if let Some(log_readable) = *self.log_readable.lock().unwrap() {
+            families.set_value("log_readable", log_readable as u8 as f64);
+        }
+        for (name, lines) in self.log_lines.lock().unwrap().iter() {
+            families.set("log_lines_total", &[name], *lines as f64);
+        }
+    }
+
+    fn set_port_metrics(&self, families: &mut NodeFamilies) {
+        for (port, reachable) in self.port_checks.lock().unwrap().iter() {
+            if let Some(reachable) = reachable {
+                families.set("node_port_reachable", &[port.to_string()], *reachable as u8 as f64);
+            }
+        }
+    }
+
+    fn set_onion_metrics(&self, families: &mut NodeFamilies) {
+        let onion = self.onion.lock().unwrap();
+        if let Some(result) = onion.as_ref() {
+            families.set_value("onion_reachable", result.is_ok() as u8 as f64);
+            if let Ok(duration) = result {
+                families.set_value("onion_connect_duration_seconds", duration.as_secs_f64());
+            }
+        }
+    }
+
+    fn observe(&self, event: Event) {
+        let mut events = self.events.lock().unwrap();
+        match event {
+            Event::Connected => events.connected = true,
+            Event::Disconnected => events.connected = false,
+            Event::ChainMain(chain_main) => {
+                events.blocks += chain_main.ids.len() as u64;
+                let now = SystemTime::now();
+                for id in &chain_main.ids {
+                    if events.arrivals.len() == MAX_ARRIVALS {
+                        events.arrivals.pop_front();
+                    }
+                    events.arrivals.push_back((id.clone(), now));
+                }
+                // the tip the node switched to, until the next export
+                if let Some(top_block_hash) = chain_main.ids.last() {
+                    *self.tip.lock().unwrap() = Some((chain_main.height(), top_block_hash.clone()));
+                }
+            },
+            Event::TxpoolAdd(txs) => {
+                events.txs += txs.len() as u64;
+                events.tx_bytes += txs.iter().map(|tx| tx.blob_size).sum::<u64>();
+            },
+        }
+    }
+
+    fn set_event_metrics(&self, families: &mut NodeFamilies) {
+        if self.zmq_url.is_none() {
+            return;
+        }
+        let events = self.events.lock().unwrap();
+        families.set_value("node_zmq_connected", events.connected as u8 as f64);
+        families.set_value("blocks_published_total", events.blocks as f64);
+        families.set_value("txpool_txs_received_total", events.txs as f64);
+        families.set_value("txpool_received_bytes_total", events.tx_bytes as f64);
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn counters(&self) -> TargetCounters {
+        let events = self.events.lock().unwrap();
+        let pool_churn = self.pool_churn.lock().unwrap();
+        TargetCounters {
+            blocks_published: events.blocks,
+            txs_received: events.txs,
+            tx_bytes_received: events.tx_bytes,
+            txs_added: pool_churn.added,
+            txs_mined: pool_churn.mined,
+            txs_dropped: pool_churn.dropped,
+            double_spends: pool_churn.double_spends,
+        }
+    }
+
+    // counted on from the last ones of a previous run
+    fn restore_counters(&self, counters: &TargetCounters) {
+        let mut events = self.events.lock().unwrap();
+        events.blocks += counters.blocks_published;
+        events.txs += counters.txs_received;
+        events.tx_bytes += counters.tx_bytes_received;
+        let mut pool_churn = self.pool_churn.lock().unwrap();
+        pool_churn.added += counters.txs_added;
+        pool_churn.mined += counters.txs_mined;
+        pool_churn.dropped += counters.txs_dropped;
+        pool_churn.double_spends += counters.double_spends;
+    }
+
+    // the only metrics served when an export fails
+    pub fn node_down_metrics(&self) -> Vec<Metric> {
+        let mut families = self.families.lock().unwrap();
+        families.clear();
+        families.set_value("node_up", 0.0);
+        if self.circuit_breaker.is_some() {
+            let open = self.circuit.lock().unwrap().is_open();
+            families.set_value("node_circuit_open", open as u8 as f64);
+        }
+        // the log may tell why
+        self.set_log_metrics(&mut families);
+        self.with_target(families.metrics())
+    }
+
+    // a busy node responds, but without anything to export until it's done
+    fn node_busy_metrics(&self) -> Vec<Metric> {
+        // it's left out of the comparisons with other nodes meanwhile
+        *self.tip.lock().unwrap() = None;
+
+        let mut families = self.families.lock().unwrap();
+        families.clear();
+        families.set_value("node_up", 1.0);
+        families.set_value("node_busy", 1.0);
+        if self.circuit_breaker.is_some() {
+            families.set_value("node_circuit_open", 0.0);
+        }
+        self.set_event_metrics(&mut families);
+        self.set_log_metrics(&mut families);
+        self.with_target(families.metrics())
+    }
+
+    fn with_target(&self, metrics: Vec<Metric>) -> Vec<Metric> {
+        metrics.into_iter().map(|metric| metric.with_const_labels(&self.target_labels)).collect()
+    }
+
+    // hash of the block at the given height, known for the top block and the cached headers
+    fn block_hash(&self, height: u64) -> Option<String> {
+        if let Some((tip_height, hash)) = &*self.tip.lock().unwrap() {
+            if *tip_height == height + 1 && !hash.is_empty() {
+                return Some(hash.clone());
+            }
+        }
+        self.block_headers.lock().unwrap().get(&height).map(|header| header.hash.clone())
+    }
+
+    // blocks the target is behind every reference node, negative when it's ahead
+    fn reference_metrics(&self, references: &[(String, u64)]) -> Vec<Metric> {
+        let height = match &*self.tip.lock().unwrap() {
+            Some((height, _)) => *height,
+            None => return Vec::new(),
+        };
+
+        let mut families = self.families.lock().unwrap();
+        for (name, reference_height) in references {
+            families.set("node_reference_lag", &[name], *reference_height as f64 - height as f64);
+        }
+        self.with_target(families.metric("node_reference_lag").into_iter().collect())
+    }
+
+    pub fn latency_metric(&self) -> Metric {
+        self.client.latency_metric().with_const_labels(&self.target_labels)
+    }
+
+    // "oldest" is 0 when the pool is empty
+    fn get_age_seconds(timestamp: u64) -> f64 {
+        if timestamp == 0 {
+            return 0.0;
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        now.saturating_sub(timestamp) as f64
+    }
+
+    // nearest-rank quantiles of the given values
+    fn get_quantiles<I: Iterator<Item = f64>>(values: I) -> [f64; QUANTILES.len()] {
+        let mut values = values.collect::<Vec<_>>();
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        let mut quantiles = [f64::NAN; QUANTILES.len()];
+        if values.is_empty() {
+            return quantiles;
+        }
+
+        for (quantile, q) in quantiles.iter_mut().zip(QUANTILES.iter()) {
+            let rank = (q * values.len() as f64).ceil() as usize;
+            *quantile = values[rank.max(1) - 1];
         }
+        quantiles
     }
 
     fn get_blocks_metrics(headers: &[BlockHeader], count: u32) -> BlocksMetrics {
@@ -78,152 +1101,1557 @@ impl Exporter {
                 max_reward: acc.max_reward.max(block.reward as f64),
                 avg_size: acc.avg_size + block.block_size as f64,
                 max_size: acc.max_size.max(block.block_size as f64),
+                ..acc
             });
 
+        // headers are ordered by height, the ratio is undefined if the oldest has no difficulty
+        let difficulty_change = match (non_orphan_blocks.first(), non_orphan_blocks.last()) {
+            (Some(oldest), Some(newest)) if oldest.difficulty > 0 => {
+                newest.difficulty as f64 / oldest.difficulty as f64
+            },
+            _ => f64::NAN,
+        };
+
         BlocksMetrics {
+            observed: non_orphan_blocks.len() as f64,
             avg_txes: blocks_metrics.avg_txes / non_orphan_blocks.len() as f64,
             avg_reward: blocks_metrics.avg_reward / non_orphan_blocks.len() as f64,
             avg_size: blocks_metrics.avg_size / non_orphan_blocks.len() as f64,
+            difficulty_change,
+            txes_quantiles: Exporter::get_quantiles(
+                non_orphan_blocks.iter().map(|b| b.num_txes as f64)),
+            reward_quantiles: Exporter::get_quantiles(
+                non_orphan_blocks.iter().map(|b| b.reward as f64)),
+            size_quantiles: Exporter::get_quantiles(
+                non_orphan_blocks.iter().map(|b| b.block_size as f64)),
             ..blocks_metrics
         }
     }
 
-    #[instrument(name = "export_metrics", skip(self))]
-    pub async fn export(&self) -> Result<String, ExportError> {
-        let info = self.client.get_info().await.map_err(ExportError::Client)?;
-
-        // assuming all other responses will have the same value for "untrusted"
-        if info.untrusted {
-            return Err(ExportError::Untrusted);
+    // the calls of a disabled collector aren't made at all
+    async fn call_collector<F: Future>(&self, name: &str, call: F) -> Option<F::Output> {
+        if self.disabled_collectors.iter().any(|disabled| disabled == name) {
+            None
+        } else {
+            Some(call.await)
         }
+    }
 
-        let mut metrics = Vec::with_capacity(100);
-
-        let mut push_metric = |name: &str, value| {
-            metrics.push(Metric::new_gauge(name, value));
-        };
-
-        // Node metrics
-        push_metric("monero_node_database_size", info.database_size as f64);
-        push_metric("monero_node_free_space", info.free_space as f64);
-        push_metric("monero_node_grey_peerlist_size", info.grey_peerlist_size as f64);
-        push_metric("monero_node_incoming_connections_count", info.incoming_connections_count as f64);
-        push_metric("monero_node_offline", info.offline as u8 as f64);
-        push_metric("monero_node_outgoing_connections_count", info.outgoing_connections_count as f64);
-        push_metric("monero_node_rpc_connections_count", info.rpc_connections_count as f64);
-        push_metric("monero_node_synchronized", info.synchronized as u8 as f64);
-        push_metric("monero_node_white_peerlist_size", info.white_peerlist_size as f64);
-
-        if !info.synchronized {
-            info!("node is not synchronized yet - skipped exporting tx pool and network metrics");
-
-            let mut s = String::new();
-            return render_metrics(metrics.iter(), &mut s)
-                .map(|_| s)
-                .map_err(ExportError::Renderer);
-        }
-
-        let block_headers_req = BlockHeadersRangeRequest {
-            start_height: info.height.checked_sub(self.max_block_span.into()).unwrap_or(0),
-            end_height: info.height.checked_sub(1).unwrap_or(0),
-        };
-        let (tx_pool_stats, block_headers) = try_join!(
-            self.client.get_transaction_pool_stats(),
-            self.client.get_block_headers_range(block_headers_req),
-        ).map_err(ExportError::Client)?;
-
-        let pool_stats = tx_pool_stats.pool_stats;
-        let block_headers = block_headers.headers;
-
-        // Node metrics - transaction pool
-        push_metric("monero_txpool_bytes_max", pool_stats.bytes_max as f64);
-        push_metric("monero_txpool_bytes_med", pool_stats.bytes_med as f64);
-        push_metric("monero_txpool_bytes_min", pool_stats.bytes_min as f64);
-        push_metric("monero_txpool_bytes_total", pool_stats.bytes_total as f64);
-        push_metric("monero_txpool_double_spends", pool_stats.num_double_spends as f64);
-        push_metric("monero_txpool_txs_failing", pool_stats.num_failing as f64);
-        push_metric("monero_txpool_txs_not_relayed", pool_stats.num_not_relayed as f64);
-        push_metric("monero_txpool_oldest_tx", pool_stats.oldest as f64);
-        push_metric("monero_txpool_txs_above_10min", pool_stats.num_10m as f64);
-        push_metric("monero_txpool_txs_total", pool_stats.txs_total as f64);
-
-        // Network metrics
-        push_metric("monero_network_block_size_limit", info.block_size_limit as f64);
-        push_metric("monero_network_block_size_median", info.block_size_median as f64);
-        push_metric("monero_network_block_weight_limit", info.block_weight_limit as f64);
-        push_metric("monero_network_block_weight_median", info.block_weight_median as f64);
-        push_metric("monero_network_cumulative_difficulty", info.cumulative_difficulty as f64);
-        push_metric("monero_network_difficulty", info.difficulty as f64);
-        push_metric("monero_network_height", info.height as f64);
-        push_metric("monero_network_target", info.target as f64);
-        push_metric("monero_network_target_height", info.target_height as f64);
-        push_metric("monero_network_tx_count", info.tx_count as f64);
-
-        let blocks_metrics = self.block_spans.iter()
-            .map(|count| (count.to_string(), Exporter::get_blocks_metrics(&block_headers, *count)))
-            .collect::<Vec<_>>();
+    // the response of an optional collector, whose failure only leaves out its own metrics
+    fn collect<'a, R>(
+        &self,
+        collectors: &mut Vec<(&'a str, bool)>,
+        name: &'a str,
+        result: Result<R, ClientError>,
+        untrusted: fn(&R) -> bool,
+    ) -> Option<R> {
+        let result = result.map_err(ExportError::Client).and_then(|response| {
+            if untrusted(&response) {
+                Err(ExportError::Untrusted)
+            } else {
+                Ok(response)
+            }
+        });
 
-        let mut push_blocks_metric = |name: &str, metric_selector: fn(BlocksMetrics) -> f64| {
-            let values = blocks_metrics.clone().into_iter()
-                .map(|(count, m)| (count, metric_selector(m)));
+        collectors.push((name, result.is_err()));
+        match result {
+            Ok(response) => Some(response),
+            Err(e) => {
+                warn!("collector {} of {} failed: {}", name, self.name, e);
+                None
+            },
+        }
+    }
 
-            metrics.push(Metric::new_gauge_with_label_values(name, "block_count", values));
-        };
+    fn with_collectors(
+        &self,
+        mut metrics: Vec<Metric>,
+        custom_metrics: Vec<Metric>,
+        collectors: &[(&str, bool)],
+    ) -> Vec<Metric> {
+        metrics.extend(custom_metrics);
+        if let Some(propagation_delays) = &*self.propagation_delays.lock().unwrap() {
+            let metric = Metric::new_histogram(&*self.propagation_delay_name, propagation_delays);
+            metrics.push(match get_node_help("block_propagation_delay_seconds") {
+                Some(help) => metric.with_help(help),
+                None => metric,
+            });
+        }
+        metrics.push(Metric::new_gauge_with_labels(
+            "monerod_exporter_collector_error",
+            collectors.iter().map(|(name, failed)| {
+                (vec![("collector".to_string(), name.to_string())], *failed as u8 as f64)
+            }),
+        ));
+        self.with_target(metrics)
+    }
+
+    // block headers are requested as soon as the height is known
+    // the blocks and the pool aren't exported while the node is syncing, so they're only
+    // requested along with each other once it's done
+    async fn get_info_and_synced(&self) -> Result<(InfoResponse, SyncedResponses), ClientError> {
+        let info = self.client.get_info().await?;
+        if info.untrusted || !info.synchronized {
+            return Ok((info, SyncedResponses::default()));
+        }
+
+        let (block_headers, tx_pool_stats, pool_changes) = join!(
+            self.call_collector("blocks", self.get_block_headers(
+                info.height.saturating_sub(self.max_block_span.into()),
+                info.height.saturating_sub(1),
+            )),
+            self.call_collector("txpool", self.client.get_transaction_pool_stats()),
+            self.call_collector("churn", self.get_pool_changes()),
+        );
+        Ok((info, SyncedResponses { block_headers, tx_pool_stats, pool_changes }))
+    }
+
+    // only the headers above the cached tip are requested, along with the tip itself,
+    // which no longer matches after a reorg
+    async fn get_block_headers(
+        &self,
+        start_height: u64,
+        end_height: u64,
+    ) -> Result<BlockHeadersRangeResponse, ClientError> {
+        let tip = self.block_headers.lock().unwrap().iter().next_back()
+            .map(|(height, header)| (*height, header.hash.clone()))
+            .filter(|(height, _)| *height >= start_height && *height <= end_height);
+
+        let full_range = BlockHeadersRangeRequest { start_height, end_height };
+        let mut response = match &tip {
+            None => self.client.get_block_headers_range(full_range).await?,
+            Some((tip_height, tip_hash)) => {
+                let new_range = BlockHeadersRangeRequest { start_height: *tip_height, end_height };
+                let response = self.client.get_block_headers_range(new_range).await?;
+                match response.headers.first() {
+                    Some(header) if response.untrusted || header.hash == *tip_hash => response,
+                    _ => {
+                        info!("block {} was reorganized, requesting the headers again", tip_height);
+                        self.client.get_block_headers_range(full_range).await?
+                    },
+                }
+            },
+        };
+        if response.untrusted {
+            return Ok(response);
+        }
+
+        let mut block_headers = self.block_headers.lock().unwrap();
+        // the first headers are of blocks which were there before the exporter
+        if !block_headers.is_empty() {
+            let new_headers = response.headers.iter()
+                .filter(|header| !matches!(
+                    block_headers.get(&header.height),
+                    Some(cached) if cached.hash == header.hash,
+                ))
+                .collect::<Vec<_>>();
+            self.observe_propagation_delays(&new_headers, end_height);
+        }
+        block_headers.extend(response.headers.drain(..).map(|header| (header.height, header)));
+        // headers which fell out of the span or are above a lower tip after a reorg
+        *block_headers = block_headers.split_off(&start_height);
+        block_headers.split_off(&(end_height + 1));
+        response.headers = block_headers.values().cloned().collect();
+        Ok(response)
+    }
+
+    // without a handshake the connection is closed right away, monerod drops peers that don't
+    // handshake anyway; the network of the handshake is the one the node reported
+    async fn probe_p2p(&self, nettype: &str) -> Option<P2pProbe> {
+        let p2p_probe = self.p2p_probe.as_ref()?;
+        let address = p2p_probe.address();
+        let (stream, connected) = match p2p_probe.connect().await {
+            Ok((stream, duration)) => (Some(stream), Ok(duration)),
+            Err(e) => (None, Err(e)),
+        };
+        {
+            let mut p2p_reachable = self.p2p_reachable.lock().unwrap();
+            match (&connected, *p2p_reachable) {
+                (Err(e), Some(true)) | (Err(e), None) => {
+                    warn!("P2P port {} of {} is unreachable: {}", address, self.name, e);
+                },
+                (Ok(_), Some(false)) => {
+                    info!("P2P port {} of {} is reachable again", address, self.name)
+                },
+                _ => {},
+            }
+            *p2p_reachable = Some(connected.is_ok());
+        }
+
+        let network_id = levin::network_id(nettype).filter(|_| self.p2p_handshake);
+        let (mut stream, network_id) = match (stream, network_id) {
+            (Some(stream), Some(network_id)) => (stream, network_id),
+            _ => return Some(P2pProbe { connected, handshake: None }),
+        };
+        let handshake = p2p_probe.handshake(&mut stream, network_id).await;
+        drop(stream);
+        {
+            let mut p2p_handshaken = self.p2p_handshaken.lock().unwrap();
+            match (&handshake, *p2p_handshaken) {
+                (Err(e), Some(true)) | (Err(e), None) => {
+                    warn!("P2P handshake with {} of {} failed: {}", address, self.name, e);
+                },
+                (Ok(_), Some(false)) => {
+                    info!("P2P handshake with {} of {} succeeded again", address, self.name)
+                },
+                _ => {},
+            }
+            *p2p_handshaken = Some(handshake.is_ok());
+        }
+
+        if let (Some(peer_survey), Ok((handshake, _))) = (self.peer_survey, &handshake) {
+            if self.survey.lock().unwrap().is_due(peer_survey.interval) {
+                self.survey_peers(handshake.peers.clone(), network_id, peer_survey.peers).await;
+            }
+        }
+        Some(P2pProbe { connected, handshake: Some(handshake) })
+    }
+
+    // every sampled peer at once, those which don't handshake in time are left out
+    async fn survey_peers(&self, peers: Vec<SocketAddr>, network_id: [u8; 16], count: u32) {
+        let peers = probe::sample(peers, count as usize);
+        let handshakes = join_all(peers.iter().map(|peer| async move {
+            let probe = TcpProbe::for_peer(*peer, SURVEY_TIMEOUT);
+            let (mut stream, _) = probe.connect().await?;
+            probe.handshake(&mut stream, network_id).await
+        }))
+        .await;
+
+        let handshaken = handshakes.into_iter()
+            .filter_map(Result::ok)
+            .map(|(handshake, duration)| SurveyedPeer {
+                top_height: handshake.current_height,
+                top_version: handshake.top_version,
+                handshake_duration: duration,
+            })
+            .collect::<Vec<_>>();
+        info!("{} of {} surveyed peers of {} handshaken", handshaken.len(), peers.len(), self.name);
+        *self.survey.lock().unwrap() = SurveyState {
+            surveyed_at: Some(Instant::now()),
+            peers: peers.len(),
+            handshaken,
+        };
+    }
+
+    // the transactions which left the pool since the last refresh were either mined,
+    // or dropped once they expired or a double spend of theirs was mined
+    async fn get_pool_changes(&self) -> Result<PoolChanges, ClientError> {
+        let response = self.client.get_transaction_pool_hashes().await?;
+        let hashes = response.tx_hashes.into_iter().collect::<HashSet<_>>();
+        let removed = match &self.pool_churn.lock().unwrap().hashes {
+            Some(last_hashes) => last_hashes.difference(&hashes).cloned().collect::<Vec<_>>(),
+            None => Vec::new(),
+        };
+        if response.untrusted || removed.is_empty() {
+            return Ok(PoolChanges { hashes, mined: 0, dropped: 0, untrusted: response.untrusted });
+        }
+
+        let txs = self.client.get_transactions(TransactionsRequest { txs_hashes: removed }).await?;
+        Ok(PoolChanges {
+            hashes,
+            // back in the pool after a reorg otherwise
+            mined: txs.txs.iter().filter(|tx| !tx.in_pool).count() as u64,
+            dropped: txs.missed_tx.len() as u64,
+            untrusted: txs.untrusted,
+        })
+    }
+
+    // the node learned about a block when it published it over ZMQ, otherwise the top block
+    // is taken for learned about now and the ones below it at unknown times since the last refresh
+    fn observe_propagation_delays(&self, headers: &[&BlockHeader], top_height: u64) {
+        let now = SystemTime::now();
+        let events = self.events.lock().unwrap();
+        let mut propagation_delays = self.propagation_delays.lock().unwrap();
+        for header in headers {
+            let published_at = events.arrivals.iter().rev()
+                .find(|(id, _)| *id == header.hash)
+                .map(|(_, published_at)| *published_at);
+            let learned_at = match published_at {
+                Some(published_at) => published_at,
+                None if header.height == top_height => now,
+                None => continue,
+            };
+            // miners' clocks may be ahead
+            let delay = learned_at.duration_since(UNIX_EPOCH + Duration::from_secs(header.timestamp))
+                .unwrap_or_default();
+            propagation_delays.get_or_insert_with(|| Histogram::new(&PROPAGATION_DELAY_BUCKETS))
+                .observe(delay.as_secs_f64());
+        }
+    }
+
+    #[instrument(name = "export_metrics", skip(self))]
+    pub async fn export(&self) -> Result<Vec<Metric>, ExportError> {
+        let circuit_breaker = match self.circuit_breaker {
+            None => return self.export_node().await,
+            Some(circuit_breaker) => circuit_breaker,
+        };
+        // exported as down meanwhile, like when it fails, and left out of the comparisons
+        if self.circuit.lock().unwrap().is_open() {
+            *self.tip.lock().unwrap() = None;
+            return Ok(self.node_down_metrics());
+        }
+
+        let result = self.export_node().await;
+
+        // once the cooldown is over, a single export decides whether the circuit stays open
+        let mut circuit = self.circuit.lock().unwrap();
+        match &result {
+            Ok(_) => {
+                if circuit.open_until.take().is_some() {
+                    info!("{} responded again, calling it again on every refresh", self.name);
+                }
+                circuit.consecutive_failures = 0;
+            },
+            Err(_) => {
+                circuit.consecutive_failures = circuit.consecutive_failures.saturating_add(1);
+                if circuit.consecutive_failures >= circuit_breaker.failures {
+                    warn!(
+                        "{} failed {} times in a row, not calling it for {}",
+                        self.name,
+                        circuit.consecutive_failures,
+                        format_duration(circuit_breaker.cooldown),
+                    );
+                    circuit.open_until = Some(Instant::now() + circuit_breaker.cooldown);
+                }
+            },
+        }
+        result
+    }
+
+    async fn export_node(&self) -> Result<Vec<Metric>, ExportError> {
+        // every call that doesn't depend on another one is made at once, as far as the client allows;
+        // "check" only reports the pruning state without pruning anything, restricted RPC doesn't
+        // allow it though, so whether the node is pruned is left empty when it fails
+        let (info, pruning, custom) = join!(
+            self.get_info_and_synced(),
+            self.call_collector(
+                "pruning",
+                self.client.prune_blockchain(PruneBlockchainRequest { check: true }),
+            ),
+            join_all(self.custom_collectors.collectors.iter().map(|collector| {
+                self.call_collector(collector.name(), collector.collect(&self.client))
+            })),
+        );
+
+        // the node is down without its info, every other collector is optional;
+        // monerod is busy while it starts up or compacts its database, which isn't a failure
+        let (info, synced) = match info {
+            Err(ClientError::Busy) => {
+                info!("{} is busy - skipped exporting all but the basic metrics", self.name);
+                return Ok(self.node_busy_metrics());
+            },
+            info => info.map_err(ExportError::Client)?,
+        };
+        // assuming all other responses will have the same value for "untrusted"
+        if info.untrusted {
+            return Err(ExportError::Untrusted);
+        }
+
+        *self.tip.lock().unwrap() = Some((info.height, info.top_block_hash.clone()));
+
+        let p2p_probe = self.probe_p2p(&info.nettype).await;
+
+        let mut collectors = Vec::new();
+        // whether the node is pruned is unknown when the collector is disabled
+        let pruned = pruning
+            .and_then(|pruning| self.collect(&mut collectors, "pruning", pruning, |r| r.untrusted))
+            .map(|pruning| pruning.pruned.to_string())
+            .unwrap_or_default();
+
+        let mut custom_metrics = Vec::new();
+        for (collector, result) in self.custom_collectors.collectors.iter().zip(custom) {
+            let metrics = result.and_then(|result| {
+                self.collect(&mut collectors, collector.name(), result, |_| false)
+            });
+            custom_metrics.extend(metrics.into_iter().flatten());
+        }
+
+        // the lock isn't held across the RPC calls
+        {
+            let mut families = self.families.lock().unwrap();
+            families.clear();
+
+            families.set("node_info", &[&info.version, &info.nettype, &pruned], 1.0);
+            families.set_value("node_up", 1.0);
+            families.set_value("node_busy", 0.0);
+            if self.circuit_breaker.is_some() {
+                families.set_value("node_circuit_open", 0.0);
+            }
+            self.set_event_metrics(&mut families);
+            if let Some(p2p_probe) = &p2p_probe {
+                families.set_value("p2p_reachable", p2p_probe.connected.is_ok() as u8 as f64);
+                if let Ok(duration) = p2p_probe.connected {
+                    families.set_value("p2p_connect_duration_seconds", duration.as_secs_f64());
+                }
+                if self.p2p_handshake {
+                    let handshake = p2p_probe.handshake.as_ref().and_then(|h| h.as_ref().ok());
+                    families.set_value("p2p_handshake_succeeded", handshake.is_some() as u8 as f64);
+                    if let Some((handshake, duration)) = handshake {
+                        families.set_value("p2p_handshake_duration_seconds", duration.as_secs_f64());
+                        families.set_value("p2p_top_height", handshake.current_height as f64);
+                        families.set_value("p2p_top_version", handshake.top_version as f64);
+                    }
+                }
+            }
+            self.survey.lock().unwrap().set_metrics(&mut families);
+            self.set_onion_metrics(&mut families);
+            self.set_port_metrics(&mut families);
+            self.set_log_metrics(&mut families);
+
+            let mut set = |name: &str, value| families.set_value(name, value);
+
+            // Node metrics
+            set("node_database_size", info.database_size as f64);
+            set("node_free_space", info.free_space as f64);
+            set("node_grey_peerlist_size", info.grey_peerlist_size as f64);
+            set("node_incoming_connections_count", info.incoming_connections_count as f64);
+            set("node_offline", info.offline as u8 as f64);
+            set("node_outgoing_connections_count", info.outgoing_connections_count as f64);
+            set("node_rpc_connections_count", info.rpc_connections_count as f64);
+            set("node_synchronized", info.synchronized as u8 as f64);
+            set("node_tx_pool_size", info.tx_pool_size as f64);
+            set("node_white_peerlist_size", info.white_peerlist_size as f64);
+
+            // Network metrics
+            set("network_block_size_limit", info.block_size_limit as f64);
+            set("network_block_size_median", info.block_size_median as f64);
+            set("network_block_weight_limit", info.block_weight_limit as f64);
+            set("network_block_weight_median", info.block_weight_median as f64);
+            set("network_cumulative_difficulty", info.cumulative_difficulty as f64);
+            set("network_difficulty", info.difficulty as f64);
+            set("network_height", info.height as f64);
+            set("network_target", info.target as f64);
+            set("network_target_height", info.target_height as f64);
+            set("network_tx_count", info.tx_count as f64);
+
+            if !info.synchronized {
+                info!("node is not synchronized yet - skipped exporting tx pool and blocks metrics");
+                return Ok(self.with_collectors(families.metrics(), custom_metrics, &collectors));
+            }
+        }
 
-        // Network metrics - blocks
-        push_blocks_metric("monero_blocks_avg_txes", |m| m.avg_txes);
-        push_blocks_metric("monero_blocks_max_txes", |m| m.max_txes);
-        push_blocks_metric("monero_blocks_avg_reward", |m| m.avg_reward);
-        push_blocks_metric("monero_blocks_max_reward", |m| m.max_reward);
-        push_blocks_metric("monero_blocks_avg_size", |m| m.avg_size);
-        push_blocks_metric("monero_blocks_max_size", |m| m.max_size);
+        let tx_pool_stats = synced.tx_pool_stats.and_then(|tx_pool_stats| {
+            self.collect(&mut collectors, "txpool", tx_pool_stats, |_| false)
+        });
+        let pool_changes = synced.pool_changes.and_then(|pool_changes| {
+            self.collect(&mut collectors, "churn", pool_changes, |r| r.untrusted)
+        });
+        let block_headers = synced.block_headers.and_then(|block_headers| {
+            self.collect(&mut collectors, "blocks", block_headers, |_| false)
+        });
 
-        let mut s = String::new();
-        render_metrics(metrics.iter(), &mut s)
-            .map(|_| s)
-            .map_err(ExportError::Renderer)
+        let mut families = self.families.lock().unwrap();
+
+        {
+            let mut pool_churn = self.pool_churn.lock().unwrap();
+            if let Some(tx_pool_stats) = &tx_pool_stats {
+                pool_churn.observe_double_spends(tx_pool_stats.pool_stats.num_double_spends);
+            }
+            if let Some(pool_changes) = pool_changes {
+                pool_churn.apply(pool_changes);
+            }
+            pool_churn.set_metrics(&mut families);
+        }
+
+        if let Some(tx_pool_stats) = tx_pool_stats {
+            let pool_stats = tx_pool_stats.pool_stats;
+            let mut set = |name: &str, value| families.set_value(name, value);
+
+            // Node metrics - transaction pool
+            set("txpool_bytes_max", pool_stats.bytes_max as f64);
+            set("txpool_bytes_med", pool_stats.bytes_med as f64);
+            set("txpool_bytes_min", pool_stats.bytes_min as f64);
+            set("txpool_bytes_total", pool_stats.bytes_total as f64);
+            set("txpool_double_spends", pool_stats.num_double_spends as f64);
+            set("txpool_txs_failing", pool_stats.num_failing as f64);
+            set("txpool_txs_not_relayed", pool_stats.num_not_relayed as f64);
+            set("txpool_oldest_tx", pool_stats.oldest as f64);
+            set("txpool_oldest_tx_age_seconds", Exporter::get_age_seconds(pool_stats.oldest));
+            set("txpool_txs_above_10min", pool_stats.num_10m as f64);
+            set("txpool_txs_total", pool_stats.txs_total as f64);
+        }
+
+        let block_headers = match block_headers {
+            Some(block_headers) => block_headers.headers,
+            None => return Ok(self.with_collectors(families.metrics(), custom_metrics, &collectors)),
+        };
+
+        for count in self.block_spans.iter() {
+            let m = Exporter::get_blocks_metrics(&block_headers, *count);
+            let count = count.to_string();
+            families.set("blocks_observed", &[&count], m.observed);
+
+            // spans are either left out or zeroed but never exported as NaN
+            let m = match self.empty_spans {
+                _ if m.observed > 0.0 => m,
+                EmptySpanPolicy::Omit => continue,
+                EmptySpanPolicy::Zero => BlocksMetrics::default(),
+            };
+
+            // Network metrics - blocks
+            families.set("blocks_avg_txes", &[&count], m.avg_txes);
+            families.set("blocks_max_txes", &[&count], m.max_txes);
+            families.set("blocks_avg_reward", &[&count], m.avg_reward);
+            families.set("blocks_max_reward", &[&count], m.max_reward);
+            families.set("blocks_avg_size", &[&count], m.avg_size);
+            families.set("blocks_max_size", &[&count], m.max_size);
+            if m.difficulty_change.is_finite() {
+                families.set("blocks_difficulty_change", &[&count], m.difficulty_change);
+            }
+
+            // Network metrics - blocks quantiles
+            for (i, q) in QUANTILES.iter().enumerate() {
+                let q = q.to_string();
+                families.set("blocks_txes", &[&count, &q], m.txes_quantiles[i]);
+                families.set("blocks_reward", &[&count, &q], m.reward_quantiles[i]);
+                families.set("blocks_size", &[&count, &q], m.size_quantiles[i]);
+            }
+        }
+
+        Ok(self.with_collectors(families.metrics(), custom_metrics, &collectors))
+    }
+}
+
+#[derive(Clone, Debug)]
+struct RefreshStats {
+    refreshes: u64,
+    failures: u64,
+    consecutive_failures: u64,
+    last_duration: Duration,
+    durations: Histogram,
+    last_success: Option<SystemTime>,
+    // of the failure of the last refresh, none once one succeeds
+    last_error_kind: Option<&'static str>,
+    // the time, kind and message of the last error, which /status keeps after a success
+    last_error: Option<(SystemTime, &'static str, String)>,
+    last_refresh_at: Option<SystemTime>,
+    last_refresh_trace: Option<RefreshTrace>,
+    last_failure_trace: Option<RefreshTrace>,
+    counters_created: SystemTime,
+    last_refresh_end: Option<Instant>,
+}
+
+// trace id of a refresh span, exported as an exemplar so a failed refresh can be looked up
+#[derive(Clone, Debug)]
+struct RefreshTrace {
+    trace_id: String,
+    at: SystemTime,
+}
+
+impl RefreshTrace {
+    fn exemplar_labels(&self) -> Vec<(String, String)> {
+        vec![("trace_id".to_string(), self.trace_id.clone())]
+    }
+}
+
+impl Default for RefreshStats {
+    fn default() -> Self {
+        RefreshStats {
+            refreshes: 0,
+            failures: 0,
+            consecutive_failures: 0,
+            last_duration: Duration::default(),
+            durations: Histogram::new(&DEFAULT_BUCKETS),
+            last_success: None,
+            last_error_kind: None,
+            last_error: None,
+            last_refresh_at: None,
+            last_refresh_trace: None,
+            last_failure_trace: None,
+            counters_created: SystemTime::now(),
+            last_refresh_end: None,
+        }
+    }
+}
+
+// number of refresh intervals after which the last successful export is considered stale
+const READY_MAX_AGE_INTERVALS: u32 = 3;
+
+// time from the first target publishing a new block until the refresh
+const NEW_BLOCK_REFRESH_DELAY: Duration = Duration::from_secs(1);
+
+// failed refreshes in a row which are retried at the refresh interval, so that a node which
+// restarts or drops a connection now and then isn't backed off from
+const BACKOFF_AFTER_FAILURES: u64 = 3;
+
+// counters which are kept across restarts when a state file is configured
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct PersistedState {
+    refreshes: u64,
+    failures: u64,
+    // unix timestamp in seconds of when the counters started, missing in older state files
+    #[serde(default)]
+    created: Option<f64>,
+    // by the names of the targets, missing in older state files too
+    #[serde(default)]
+    targets: BTreeMap<String, TargetCounters>,
+}
+
+// the counters an exporter derives itself from what it observes of its target
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+struct TargetCounters {
+    blocks_published: u64,
+    txs_received: u64,
+    tx_bytes_received: u64,
+    txs_added: u64,
+    txs_mined: u64,
+    txs_dropped: u64,
+    double_spends: u64,
+}
+
+impl PersistedState {
+    fn load(path: &Path) -> io::Result<PersistedState> {
+        match fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data).map_err(io::Error::from),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(PersistedState::default()),
+            Err(e) => Err(e),
+        }
     }
+
+    fn save(&self, path: &Path) -> io::Result<()> {
+        let data = serde_json::to_vec(self).map_err(io::Error::from)?;
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, data)?;
+        fs::rename(&tmp_path, path)
+    }
+}
+
+// what scrapes get once the metrics are older than the configured maximum age
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StalePolicy {
+    // respond with 503
+    Fail,
+    // serve them marked as stale, the last ones are also kept when a refresh fails
+    Serve,
+}
+
+// when the metrics are got from the daemon
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScrapeMode {
+    // every refresh interval in the background, scrapes get the last render
+    Interval,
+    // on every scrape, within the time the scrape allows
+    Pull,
+    // in the background once a scrape finds the last render older than the refresh interval,
+    // that scrape still gets the last render
+    Revalidate,
 }
 
 #[derive(Debug)]
 pub struct Publisher {
-    exporter: Exporter,
+    // one per target, swapped when targets are discovered
+    exporters: ArcSwap<Vec<Arc<Exporter>>>,
+    // nodes whose height the targets are compared to, by name
+    references: Vec<(String, Client)>,
+    // metrics of no single target
+    shared_families: Mutex<NodeFamilies>,
     refresh_interval: Duration,
-    rendered_metrics: RwLock<Option<String>>,
+    // fraction of the refresh interval
+    refresh_jitter: f64,
+    max_refresh_backoff: Option<Duration>,
+    refresh_on_block: bool,
+    scrape_mode: ScrapeMode,
+    scrape_timeout: Duration,
+    serve_on_failure: bool,
+    max_metrics_age: Option<Duration>,
+    stale_metrics: StalePolicy,
+    sample_timestamps: bool,
+    const_labels: BTreeMap<String, String>,
+    rewrite_rules: Vec<RewriteRule>,
+    derived_metrics: Vec<(String, Expression)>,
+    watchdog_timeout: Option<Duration>,
+    state_path: Option<PathBuf>,
+    // restored counters of the targets which weren't there since the start, e.g. discovered later
+    stored_counters: Mutex<BTreeMap<String, TargetCounters>>,
+    build_info: Metric,
+    config_info: Metric,
+    started_at: Instant,
+    stats: RwLock<RefreshStats>,
+    // the metric families are shared, so exports must not overlap
+    refresh_lock: AsyncMutex<()>,
+    // number of finished refreshes, so that refreshes waiting for one can share its result
+    refresh_generation: AtomicU64,
+    // woken by scrapes in revalidate mode
+    revalidate: Notify,
+    // woken by the targets' ZMQ subscriptions
+    new_block: Arc<Notify>,
+    // swapped on every refresh, scrapes only take a reference to it
+    rendered_metrics: ArcSwapOption<RenderedMetrics>,
+}
+
+// an exposition compressed once per refresh in every supported encoding
+#[derive(Debug)]
+struct EncodedMetrics {
+    identity: Bytes,
+    gzip: Bytes,
+    deflate: Bytes,
+}
+
+impl EncodedMetrics {
+    fn new(rendered: Vec<u8>) -> io::Result<EncodedMetrics> {
+        Ok(EncodedMetrics {
+            gzip: compress(&rendered, Encoding::Gzip)?.into(),
+            deflate: compress(&rendered, Encoding::Deflate)?.into(),
+            identity: rendered.into(),
+        })
+    }
+
+    fn get(&self, encoding: Encoding) -> Bytes {
+        match encoding {
+            Encoding::Identity => self.identity.clone(),
+            Encoding::Gzip => self.gzip.clone(),
+            Encoding::Deflate => self.deflate.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RenderedMetrics {
+    text: EncodedMetrics,
+    openmetrics: EncodedMetrics,
+    protobuf: EncodedMetrics,
+    // the samples as rendered, for pushes
+    series: Vec<(String, Labels, f64)>,
+    rendered_at: Instant,
+}
+
+impl RenderedMetrics {
+    fn get(&self, format: Format, encoding: Encoding) -> Bytes {
+        match format {
+            Format::Text => self.text.get(encoding),
+            Format::OpenMetrics => self.openmetrics.get(encoding),
+            Format::Protobuf => self.protobuf.get(encoding),
+        }
+    }
+}
+
+pub struct ServedMetrics {
+    pub body: Bytes,
+    pub age: Duration,
+    // how much longer the metrics are fresh
+    pub max_age: Duration,
+    pub stale: bool,
 }
 
 impl Publisher {
-    pub fn new(exporter: Exporter, refresh_interval: Duration) -> Publisher {
+    pub fn new(
+        exporters: Vec<Exporter>,
+        references: Vec<(String, Client)>,
+        config: &Config,
+    ) -> Publisher {
+        let refresh_interval = config.refresh_interval;
+        let state_path = config.state_path.clone();
+
+        let build_info_labels = vec![
+            ("version".to_string(), env!("CARGO_PKG_VERSION").to_string()),
+            ("commit".to_string(), env!("MONEROD_EXPORTER_COMMIT").to_string()),
+            ("rustc".to_string(), env!("MONEROD_EXPORTER_RUSTC").to_string()),
+        ];
+        let build_info = Metric::new_info("monerod_exporter_build_info", build_info_labels);
+
+        let shared_families = NodeFamilies::new(MetricNaming::new(config));
+
+        let block_spans = match config.block_spans.as_slice() {
+            [] => "1".to_string(),
+            block_spans => block_spans.iter()
+                .map(u32::to_string)
+                .collect::<Vec<_>>()
+                .join(","),
+        };
+
+        // discovered targets are represented by the SRV name
+        let targets = exporters.iter()
+            .map(|exporter| exporter.client.sanitized_base_url())
+            .chain(config.discovery.iter().map(|discovery| discovery.srv_name.clone()))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let config_info_labels = vec![
+            ("refresh_interval".to_string(), format_duration(refresh_interval).to_string()),
+            ("block_spans".to_string(), block_spans),
+            ("target".to_string(), targets),
+        ];
+        let config_info = Metric::new_info("monerod_exporter_config_info", config_info_labels);
+
+        let watchdog_timeout =
+            if config.watchdog_factor == 0 {
+                None
+            } else {
+                Some(refresh_interval * config.watchdog_factor)
+            };
+
+        let persisted_state = match &state_path {
+            None => PersistedState::default(),
+            Some(path) => PersistedState::load(path).unwrap_or_else(|e| {
+                warn!("failed to load state from {}: {}", path.display(), e);
+                PersistedState::default()
+            }),
+        };
+        let counters_created = persisted_state.created
+            .filter(|created| created.is_finite() && *created >= 0.0)
+            .map(|created| UNIX_EPOCH + Duration::from_secs_f64(created))
+            .unwrap_or_else(SystemTime::now);
+        let stats = RefreshStats {
+            refreshes: persisted_state.refreshes,
+            failures: persisted_state.failures,
+            counters_created,
+            ..RefreshStats::default()
+        };
+        let mut stored_counters = persisted_state.targets;
+        for exporter in exporters.iter() {
+            if let Some(counters) = stored_counters.remove(exporter.name()) {
+                exporter.restore_counters(&counters);
+            }
+        }
+
         Publisher {
-            exporter,
+            exporters: ArcSwap::from_pointee(exporters.into_iter().map(Arc::new).collect()),
+            references,
+            shared_families: Mutex::new(shared_families),
             refresh_interval,
-            rendered_metrics: RwLock::new(None),
+            refresh_jitter: config.refresh_jitter as f64 / 100.0,
+            max_refresh_backoff: config.max_refresh_backoff,
+            refresh_on_block: config.refresh_on_block,
+            scrape_mode: config.scrape_mode,
+            scrape_timeout: config.scrape_timeout,
+            serve_on_failure: config.serve_on_failure,
+            max_metrics_age: config.max_metrics_age,
+            stale_metrics: config.stale_metrics,
+            sample_timestamps: config.sample_timestamps,
+            const_labels: config.labels.clone(),
+            rewrite_rules: config.rewrite.clone(),
+            derived_metrics: config.derived.clone(),
+            watchdog_timeout,
+            state_path,
+            stored_counters: Mutex::new(stored_counters),
+            build_info,
+            config_info,
+            started_at: Instant::now(),
+            stats: RwLock::new(stats),
+            refresh_lock: AsyncMutex::new(()),
+            refresh_generation: AtomicU64::new(0),
+            revalidate: Notify::new(),
+            new_block: Arc::new(Notify::new()),
+            rendered_metrics: ArcSwapOption::empty(),
+        }
+    }
+
+    fn exporter_metrics(&self) -> Vec<Metric> {
+        let stats = self.stats.read().unwrap().clone();
+
+        let mut durations = Metric::new_histogram(
+            "monerod_exporter_refresh_duration_seconds",
+            &stats.durations,
+        );
+        if let Some(trace) = &stats.last_refresh_trace {
+            durations = durations.with_exemplar(
+                trace.exemplar_labels(),
+                stats.last_duration.as_secs_f64(),
+                Some(trace.at),
+            );
+        }
+
+        let mut failures =
+            Metric::new_counter("monerod_exporter_refresh_failures_total", stats.failures as f64)
+                .with_created(stats.counters_created);
+        if let Some(trace) = &stats.last_failure_trace {
+            failures = failures.with_exemplar(trace.exemplar_labels(), 1.0, Some(trace.at));
+        }
+
+        let mut metrics = vec![
+            self.build_info.clone(),
+            self.config_info.clone(),
+            Metric::new_gauge(
+                "monerod_exporter_last_refresh_duration_seconds",
+                stats.last_duration.as_secs_f64(),
+            ),
+            durations,
+            Metric::new_counter("monerod_exporter_refreshes_total", stats.refreshes as f64)
+                .with_created(stats.counters_created),
+            failures,
+            Metric::new_gauge(
+                "monerod_exporter_last_success_timestamp_seconds",
+                stats.last_success
+                    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs_f64())
+                    .unwrap_or(0.0),
+            ),
+            Metric::new_gauge(
+                "monerod_exporter_consecutive_failures",
+                stats.consecutive_failures as f64,
+            ),
+        ];
+
+        metrics.extend(self.exporters.load().iter().map(|exporter| exporter.latency_metric()));
+
+        metrics.extend(process_metrics());
+
+        if let Some(kind) = stats.last_error_kind {
+            metrics.push(Metric::new_info(
+                "monerod_exporter_last_error_info",
+                vec![("kind".to_string(), kind.to_string())],
+            ));
+        }
+
+        metrics
+    }
+
+    fn save_state(&self) {
+        let path = match &self.state_path {
+            None => return,
+            Some(path) => path,
+        };
+
+        let state = {
+            let stats = self.stats.read().unwrap();
+            PersistedState {
+                refreshes: stats.refreshes,
+                failures: stats.failures,
+                created: stats.counters_created.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs_f64()),
+                targets: self.stored_counters.lock().unwrap().clone().into_iter()
+                    .chain(self.exporters.load().iter().map(|e| (e.name().to_string(), e.counters())))
+                    .collect(),
+            }
+        };
+
+        if let Err(e) = state.save(path) {
+            warn!("failed to save state to {}: {}", path.display(), e);
+        }
+    }
+
+    pub fn exporters(&self) -> Vec<Arc<Exporter>> {
+        self.exporters.load().as_ref().clone()
+    }
+
+    pub fn set_exporters(&self, exporters: Vec<Arc<Exporter>>) {
+        {
+            let mut stored_counters = self.stored_counters.lock().unwrap();
+            // the counters of the targets which are gone are kept in case they come back
+            for exporter in self.exporters.load().iter() {
+                if !exporters.iter().any(|e| e.name() == exporter.name()) {
+                    stored_counters.insert(exporter.name().to_string(), exporter.counters());
+                }
+            }
+            for exporter in exporters.iter() {
+                if let Some(counters) = stored_counters.remove(exporter.name()) {
+                    exporter.restore_counters(&counters);
+                }
+            }
+        }
+        for exporter in exporters.iter() {
+            exporter.start(&self.new_block);
+        }
+        self.exporters.store(Arc::new(exporters));
+    }
+
+    // ready once an export has succeeded and as long as the last success is recent enough,
+    // in pull mode there is nothing to wait for before the first scrape,
+    // in revalidate mode the metrics only get old when nothing scrapes them
+    pub fn is_ready(&self) -> bool {
+        if self.scrape_mode == ScrapeMode::Pull {
+            return true;
+        }
+        let last_success = self.stats.read().unwrap().last_success;
+        let max_age = self.refresh_interval * READY_MAX_AGE_INTERVALS;
+        match last_success.map(|t| t.elapsed()) {
+            Some(Ok(_)) if self.scrape_mode == ScrapeMode::Revalidate => true,
+            Some(Ok(age)) => age <= max_age,
+            // the clock went backwards since the last success
+            Some(Err(_)) => true,
+            None => false,
+        }
+    }
+
+    // false when no refresh has finished for longer than an export may take
+    pub fn is_refreshing(&self) -> bool {
+        if self.scrape_mode != ScrapeMode::Interval {
+            return true;
+        }
+        let last_refresh_end = self.stats.read().unwrap().last_refresh_end.unwrap_or(self.started_at);
+        let export_timeout = self.watchdog_timeout.unwrap_or(self.refresh_interval);
+        let max_stall = self.refresh_interval * 2 + export_timeout + self.backoff().unwrap_or_default();
+        last_refresh_end.elapsed() <= max_stall
+    }
+
+    // a summary for troubleshooting without access to the logs
+    pub fn status(&self) -> serde_json::Value {
+        let stats = self.stats.read().unwrap().clone();
+        let timestamp = |t: Option<SystemTime>| t.map(|t| format_rfc3339_seconds(t).to_string());
+
+        let last_error = stats.last_error.as_ref().map(|(at, kind, message)| json!({
+            "at": format_rfc3339_seconds(*at).to_string(),
+            "kind": kind,
+            "message": message,
+        }));
+
+        // every RPC method is collected on its own
+        let collectors = |exporter: &Exporter| exporter.client.call_states().into_iter()
+            .map(|(method, state)| (method, json!({
+                "last_call": format_rfc3339_seconds(state.at).to_string(),
+                "up": state.error.is_none(),
+                "error": state.error,
+            })))
+            .collect::<serde_json::Map<_, _>>();
+
+        let metrics_age = self.rendered_metrics.load().as_ref()
+            .map(|rendered| rendered.rendered_at.elapsed().as_secs_f64());
+
+        let mut status = json!({
+            "scrape_mode": self.scrape_mode,
+            "refresh_interval_seconds": self.refresh_interval.as_secs_f64(),
+            "backoff_seconds": self.backoff().map(|backoff| backoff.as_secs_f64()),
+            "ready": self.is_ready(),
+            "refreshes": stats.refreshes,
+            "failures": stats.failures,
+            "consecutive_failures": stats.consecutive_failures,
+            "last_refresh": timestamp(stats.last_refresh_at),
+            "last_refresh_duration_seconds": stats.last_duration.as_secs_f64(),
+            "last_success": timestamp(stats.last_success),
+            "last_error": last_error,
+            "metrics_age_seconds": metrics_age,
+        });
+
+        // several targets are listed by name
+        match self.exporters.load().as_slice() {
+            [exporter] => {
+                status["target"] = exporter.client.sanitized_base_url().into();
+                status["collectors"] = collectors(exporter).into();
+            },
+            exporters => {
+                status["targets"] = exporters.iter()
+                    .map(|exporter| (exporter.name.clone(), json!({
+                        "target": exporter.client.sanitized_base_url(),
+                        "collectors": collectors(exporter),
+                    })))
+                    .collect::<serde_json::Map<_, _>>()
+                    .into();
+            },
+        }
+        status
+    }
+
+    // without a maximum age the metrics are fresh until the next refresh and never stale
+    pub fn get_metrics(&self, format: Format, encoding: Encoding) -> Option<ServedMetrics> {
+        let rendered = self.rendered_metrics.load_full()?;
+
+        let age = rendered.rendered_at.elapsed();
+        let max_age = self.max_metrics_age.unwrap_or(self.refresh_interval);
+        let stale = self.max_metrics_age.is_some() && age > max_age;
+        if stale && self.stale_metrics == StalePolicy::Fail {
+            return None;
+        }
+
+        Some(ServedMetrics {
+            body: rendered.get(format, encoding),
+            age,
+            max_age: max_age.checked_sub(age).unwrap_or_default(),
+            stale,
+        })
+    }
+
+    // 128-bit id in the W3C trace context format, unique across refreshes and restarts
+    fn new_trace_id(&self, started_at: SystemTime) -> String {
+        let nanos = started_at.duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0);
+        let refreshes = self.stats.read().unwrap().refreshes;
+        format!("{:016x}{:016x}", nanos, refreshes)
+    }
+
+    // the metrics of a scrape, which are exported for it in pull mode,
+    // the timeout sent by Prometheus lowers the configured one
+    pub async fn scrape(
+        &self,
+        format: Format,
+        encoding: Encoding,
+        timeout: Option<Duration>,
+    ) -> Option<ServedMetrics> {
+        match self.scrape_mode {
+            ScrapeMode::Interval => return self.get_metrics(format, encoding),
+            ScrapeMode::Revalidate => {
+                if self.needs_revalidation() {
+                    self.revalidate.notify_one();
+                }
+                return self.get_metrics(format, encoding);
+            },
+            ScrapeMode::Pull => {},
+        }
+
+        let timeout = timeout.map_or(self.scrape_timeout, |timeout| timeout.min(self.scrape_timeout));
+        let rendered = self.refresh(Some(Instant::now() + timeout)).await?;
+        Some(ServedMetrics {
+            body: rendered.get(format, encoding),
+            age: rendered.rendered_at.elapsed(),
+            max_age: Duration::from_secs(0),
+            stale: false,
+        })
+    }
+
+    // the samples of a push, which are exported for it in pull mode like for a scrape
+    pub async fn push_series(&self) -> Option<Vec<(String, Labels, f64)>> {
+        let rendered = match self.scrape_mode {
+            ScrapeMode::Interval => self.rendered_metrics.load_full()?,
+            ScrapeMode::Revalidate => {
+                if self.needs_revalidation() {
+                    self.revalidate.notify_one();
+                }
+                self.rendered_metrics.load_full()?
+            },
+            ScrapeMode::Pull => self.refresh(Some(Instant::now() + self.scrape_timeout)).await?,
+        };
+
+        let max_age = self.max_metrics_age.unwrap_or(self.refresh_interval);
+        let stale = self.max_metrics_age.is_some() && rendered.rendered_at.elapsed() > max_age;
+        if stale && self.stale_metrics == StalePolicy::Fail {
+            return None;
+        }
+        Some(rendered.series.clone())
+    }
+
+    // time until the next refresh after too many consecutive failures, doubled with every
+    // further failure, none once the refresh interval is back
+    fn backoff(&self) -> Option<Duration> {
+        let max_refresh_backoff = self.max_refresh_backoff?;
+        let failures = self.stats.read().unwrap().consecutive_failures;
+        let failures = match failures.checked_sub(BACKOFF_AFTER_FAILURES) {
+            None | Some(0) => return None,
+            Some(failures) => failures,
+        };
+        let backoff = self.refresh_interval.checked_mul(1 << failures.min(16))
+            .map_or(max_refresh_backoff, |backoff| backoff.min(max_refresh_backoff));
+        Some(backoff).filter(|backoff| *backoff > self.refresh_interval)
+    }
+
+    // random delay of up to the configured fraction of the refresh interval
+    fn jitter(&self) -> Duration {
+        let mut random = [0; 8];
+        if self.refresh_jitter == 0.0 || getrandom::getrandom(&mut random).is_err() {
+            return Duration::from_secs(0);
         }
+        let fraction = u64::from_le_bytes(random) as f64 / u64::MAX as f64;
+        self.refresh_interval.mul_f64(fraction * self.refresh_jitter)
     }
 
-    pub fn get_metrics(&self) -> Option<String> {
-        self.rendered_metrics.read().unwrap().clone()
+    fn needs_revalidation(&self) -> bool {
+        match self.rendered_metrics.load().as_ref() {
+            Some(rendered) => rendered.rendered_at.elapsed() >= self.refresh_interval,
+            None => true,
+        }
+    }
+
+    // the targets publish the same block one after the other, so they're waited for
+    // and a single refresh follows
+    async fn wait_for_new_block(&self) {
+        self.new_block.notified().await;
+        sleep(NEW_BLOCK_REFRESH_DELAY).await;
+        self.new_block.notified().now_or_never();
     }
 
     pub async fn run(&self) -> ! {
-        let mut interval = interval(self.refresh_interval);
-        loop {
-            interval.tick().await;
+        for exporter in self.exporters.load().iter() {
+            exporter.start(&self.new_block);
+        }
+
+        match self.scrape_mode {
+            ScrapeMode::Interval => {
+                let mut interval = interval(self.refresh_interval);
+                loop {
+                    let new_block = select! {
+                        _ = interval.tick() => false,
+                        _ = self.wait_for_new_block(), if self.refresh_on_block => true,
+                    };
+                    match new_block {
+                        // the next refresh is a whole interval after this one
+                        true => interval = interval_at(
+                            TokioInstant::now() + self.refresh_interval,
+                            self.refresh_interval,
+                        ),
+                        // the schedule itself doesn't drift, only every refresh is delayed
+                        false => sleep(self.jitter()).await,
+                    }
+                    let backing_off = self.backoff().is_some();
+                    self.refresh(None).await;
 
-            let result = self.exporter.export().await;
+                    match self.backoff() {
+                        Some(backoff) => {
+                            let failures = self.stats.read().unwrap().consecutive_failures;
+                            warn!(
+                                "refresh failed {} times in a row, backing off for {}",
+                                failures,
+                                format_duration(backoff),
+                            );
+                            sleep(backoff).await;
+                            // the ticks missed while backing off are skipped
+                            interval = interval_at(TokioInstant::now(), self.refresh_interval);
+                        },
+                        None if backing_off => info!("refresh succeeded, no longer backing off"),
+                        None => {},
+                    }
+                }
+            },
+            ScrapeMode::Pull => loop {
+                future::pending::<()>().await;
+            },
+            ScrapeMode::Revalidate => {
+                // the first scrape already has something to get
+                self.refresh(None).await;
+                loop {
+                    select! {
+                        _ = self.revalidate.notified() => {
+                            // scrapes during the last refresh may have woken the loop up for nothing
+                            if self.needs_revalidation() {
+                                self.refresh(None).await;
+                            }
+                        },
+                        _ = self.wait_for_new_block(), if self.refresh_on_block => {
+                            self.refresh(None).await;
+                        },
+                    }
+                }
+            },
+        }
+    }
 
-            let result = match result {
-                Ok(r) => Some(r),
+    // with several targets the export only fails when every target does,
+    // the others are exported as down
+    async fn export(&self) -> Result<Vec<Metric>, ExportError> {
+        // targets discovered meanwhile are exported by the next refresh
+        let exporters = self.exporters.load_full();
+        let (results, references) = join!(
+            join_all(exporters.iter().map(|exporter| exporter.export())),
+            join_all(self.references.iter().map(|(name, client)| async move {
+                (name, client.get_info().await)
+            })),
+        );
+
+        if results.iter().all(Result::is_err) {
+            for (exporter, result) in exporters.iter().zip(results.iter()).skip(1) {
+                if let Err(e) = result {
+                    warn!("failed to export metrics of {}: {}", exporter.name, e);
+                }
+            }
+            return results.into_iter().next().unwrap_or_else(|| Ok(Vec::new()));
+        }
+
+        let mut metrics = Vec::new();
+        let mut up = Vec::new();
+        for (exporter, result) in exporters.iter().zip(results) {
+            match result {
+                Ok(exporter_metrics) => {
+                    metrics.extend(exporter_metrics);
+                    up.push(exporter.as_ref());
+                },
+                Err(e) => {
+                    warn!("failed to export metrics of {}: {}", exporter.name, e);
+                    metrics.extend(exporter.node_down_metrics());
+                },
+            }
+        }
+
+        // a reference node which fails is left out
+        let references = references.into_iter()
+            .filter_map(|(name, result)| match result {
+                Ok(info) => Some((name.clone(), info.height)),
                 Err(e) => {
-                    error!("{}", e);
+                    warn!("failed to get the height of reference node {}: {}", name, e);
                     None
                 },
-            };
+            })
+            .collect::<Vec<_>>();
+        for exporter in up.iter() {
+            metrics.extend(exporter.reference_metrics(&references));
+        }
+
+        let mut shared_families = self.shared_families.lock().unwrap();
+        shared_families.clear();
+        for (name, height) in references.iter() {
+            shared_families.set("reference_height", &[name], *height as f64);
+        }
+        if exporters.len() > 1 {
+            Publisher::set_cluster_metrics(&mut shared_families, &up);
+        }
+        metrics.extend(shared_families.metrics());
+
+        Ok(metrics)
+    }
+
+    // how far apart the targets which responded are, and which of them disagree with most
+    // of them on the block at the lowest of their heights
+    fn set_cluster_metrics(cluster: &mut NodeFamilies, exporters: &[&Exporter]) {
+        let heights = exporters.iter()
+            .filter_map(|exporter| Some((*exporter, exporter.tip.lock().unwrap().as_ref()?.0)))
+            .collect::<Vec<_>>();
 
-            {
-                let mut rendered_metrics = self.rendered_metrics.write().unwrap();
-                *rendered_metrics = result;
+        let lowest = heights.iter().map(|(_, height)| *height).min();
+        let highest = heights.iter().map(|(_, height)| *height).max();
+        let (lowest, highest) = match (lowest, highest) {
+            (Some(lowest), Some(highest)) => (lowest, highest),
+            _ => return,
+        };
+
+        cluster.set_value("cluster_height_spread", (highest - lowest) as f64);
+        for (exporter, height) in heights.iter() {
+            cluster.set("cluster_blocks_behind", &[&exporter.name], (highest - height) as f64);
+        }
+
+        // the height is the length of the chain, so the top block of the lowest target is below it;
+        // targets whose hash isn't known at that height are left out
+        let hashes = heights.iter()
+            .filter_map(|(exporter, _)| Some((*exporter, exporter.block_hash(lowest.checked_sub(1)?)?)))
+            .collect::<Vec<_>>();
+        let count = |hash: &String| hashes.iter().filter(|(_, other)| other == hash).count();
+        if let Some((_, majority)) = hashes.iter().max_by_key(|(_, hash)| count(hash)) {
+            let mut forked = 0;
+            for (exporter, hash) in hashes.iter() {
+                forked += (hash != majority) as u32;
+                cluster.set("cluster_forked", &[&exporter.name], (hash != majority) as u8 as f64);
             }
+            cluster.set_value("cluster_forked_nodes", forked as f64);
+        }
+    }
+
+    // a scrape's deadline replaces the watchdog, including the time waited for another export;
+    // overlapping refreshes are coalesced into the one in flight and get its result
+    async fn refresh(&self, deadline: Option<Instant>) -> Option<Arc<RenderedMetrics>> {
+        let generation = self.refresh_generation.load(Ordering::SeqCst);
+        let refresh_lock = self.refresh_lock.lock();
+        let _refresh = match deadline {
+            None => refresh_lock.await,
+            Some(deadline) => match timeout_at(deadline.into(), refresh_lock).await {
+                Ok(refresh) => refresh,
+                Err(_) => {
+                    warn!("another export didn't finish within the scrape timeout");
+                    return None;
+                },
+            },
+        };
+        if self.refresh_generation.load(Ordering::SeqCst) != generation {
+            return self.rendered_metrics.load_full();
         }
+
+        let started_at = Instant::now();
+        let refreshed_at = SystemTime::now();
+        let trace_id = self.new_trace_id(refreshed_at);
+        let span = info_span!("refresh", trace_id = %trace_id);
+        // exemplars are only attached when the span is recorded by the subscriber
+        let trace = if span.is_disabled() {
+            None
+        } else {
+            Some(RefreshTrace { trace_id, at: refreshed_at })
+        };
+
+        let export = self.export().instrument(span.clone());
+        let deadline = deadline.map(|deadline| (deadline, ExportError::ScrapeTimeout))
+            .or_else(|| self.watchdog_timeout.map(|t| (started_at + t, ExportError::Stalled)));
+        let result = match deadline {
+            None => export.await,
+            Some((deadline, e)) => timeout_at(deadline.into(), export).await.unwrap_or(Err(e)),
+        };
+
+        {
+            let mut stats = self.stats.write().unwrap();
+            stats.refreshes += 1;
+            stats.failures += result.is_err() as u64;
+            let duration = started_at.elapsed();
+            stats.last_duration = duration;
+            stats.durations.observe(duration.as_secs_f64());
+            stats.last_refresh_trace = trace.clone();
+            stats.last_refresh_at = Some(refreshed_at);
+            stats.last_refresh_end = Some(Instant::now());
+            match &result {
+                Ok(_) => {
+                    stats.consecutive_failures = 0;
+                    stats.last_success = Some(SystemTime::now());
+                    stats.last_error_kind = None;
+                },
+                Err(e) => {
+                    stats.consecutive_failures += 1;
+                    stats.last_error_kind = Some(e.kind());
+                    stats.last_error = Some((refreshed_at, e.kind(), e.redacted()));
+                    stats.last_failure_trace = trace;
+                },
+            }
+        }
+
+        self.save_state();
+
+        let result = match result {
+            Ok(metrics) => Some(metrics),
+            Err(e) => {
+                span.in_scope(|| error!("{}", e));
+                if self.serve_on_failure {
+                    let exporters = self.exporters.load();
+                    Some(exporters.iter().flat_map(|exporter| exporter.node_down_metrics()).collect())
+                } else {
+                    None
+                }
+            },
+        };
+
+        let result = result.and_then(|mut metrics| {
+            metrics.extend(self.exporter_metrics());
+
+            // derived from the metrics as exported, before the labels and rewrite rules
+            let derived = self.derived_metrics.iter()
+                .filter_map(|(name, expression)| expression.to_metric(name, &metrics))
+                .collect::<Vec<_>>();
+            metrics.extend(derived);
+
+            let samples = metrics.iter().map(Metric::samples).sum::<usize>();
+            metrics.push(Metric::new_gauge("monerod_exporter_samples", samples as f64));
+
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as i64)
+                .unwrap_or(0);
+
+            let mut registry = Registry::new();
+            registry.extend(metrics.into_iter()
+                .map(|metric| match get_exporter_help(metric.name()) {
+                    Some(help) if metric.help().is_none() => metric.with_help(help),
+                    _ => metric,
+                })
+                .map(|metric| metric.with_const_labels(&self.const_labels))
+                .map(|metric| self.rewrite_rules.iter().fold(metric, Metric::rewrite))
+                .map(|metric| {
+                    if self.sample_timestamps {
+                        metric.with_timestamp(timestamp)
+                    } else {
+                        metric
+                    }
+                }));
+
+            let mut text = String::new();
+            let mut openmetrics = String::new();
+            let rendered = registry.render(&mut text, Format::Text)
+                .and_then(|_| registry.render(&mut openmetrics, Format::OpenMetrics));
+            match rendered {
+                Ok(_) => {
+                    let encoded = EncodedMetrics::new(text.into_bytes())
+                        .and_then(|text| Ok((text, EncodedMetrics::new(openmetrics.into_bytes())?)))
+                        .and_then(|(text, openmetrics)| {
+                            Ok((text, openmetrics, EncodedMetrics::new(registry.encode())?))
+                        });
+                    match encoded {
+                        Ok((text, openmetrics, protobuf)) => Some(RenderedMetrics {
+                            text,
+                            openmetrics,
+                            protobuf,
+                            series: registry.series(),
+                            rendered_at: Instant::now(),
+                        }),
+                        Err(e) => {
+                            error!("compression error: {}", e);
+                            None
+                        },
+                    }
+                },
+                Err(e) => {
+                    error!("rendering error: {}", e);
+                    None
+                },
+            }
+        });
+
+        let result = result.map(Arc::new);
+        // with "serve" the last metrics are kept when a refresh fails, stale once they're old enough,
+        // while a scrape in pull mode only gets the metrics exported for it
+        let keep_last = self.stale_metrics == StalePolicy::Serve && self.scrape_mode != ScrapeMode::Pull;
+        if result.is_some() || !keep_last {
+            self.rendered_metrics.store(result.clone());
+        }
+        self.refresh_generation.fetch_add(1, Ordering::SeqCst);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::RetryPolicy;
+
+    // OpenMetrics names counter families without the _total suffix, so they mustn't clash with others
+    #[test]
+    fn renders_every_family_once() {
+        for normalize_names in [false, true].iter() {
+            let config = Config { normalize_names: *normalize_names, ..Config::default() };
+            let mut families = NodeFamilies::new(MetricNaming::new(&config));
+            for (name, _, label_names) in FAMILIES.iter().chain(COUNTERS.iter()) {
+                families.set(name, &vec!["value"; label_names.len()], 1.0);
+            }
+            let mut registry = Registry::new();
+            registry.extend(families.metrics());
+            let mut rendered = String::new();
+            registry.render(&mut rendered, Format::OpenMetrics).unwrap();
+
+            let mut names = HashSet::new();
+            for line in rendered.lines().filter(|line| line.starts_with("# TYPE ")) {
+                let name = line.split(' ').nth(2).unwrap();
+                assert!(names.insert(name), "{} is rendered more than once", name);
+            }
+            assert_eq!(names.len(), FAMILIES.len() + COUNTERS.len());
+        }
+    }
+
+    fn render(metrics: Vec<Metric>) -> String {
+        let mut registry = Registry::new();
+        registry.extend(metrics);
+        let mut rendered = String::new();
+        registry.render(&mut rendered, Format::Text).unwrap();
+        rendered
+    }
+
+    #[tokio::test]
+    async fn exports_open_circuits_as_down() {
+        // nothing listens on the port once it's closed
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let retry_policy = RetryPolicy { retries: 0, delay: Duration::from_secs(1), jitter: 0.0 };
+        let client = Client::new(
+            reqwest::Client::new(),
+            format!("http://127.0.0.1:{}", port),
+            None,
+            0,
+            Vec::new(),
+            retry_policy,
+        );
+        let exporter = Exporter::new(
+            client,
+            "node".to_string(),
+            false,
+            MetricNaming::new(&Config::default()),
+            Vec::new(),
+            EmptySpanPolicy::Omit,
+            Vec::new(),
+        ).with_circuit_breaker(CircuitBreaker { failures: 1, cooldown: Duration::from_secs(60) });
+
+        assert!(matches!(exporter.export().await, Err(ExportError::Client(_))));
+        let rendered = render(exporter.export().await.unwrap());
+        assert!(rendered.lines().any(|line| line == "monero_node_up 0"), "{}", rendered);
+        assert!(rendered.lines().any(|line| line == "monero_node_circuit_open 1"), "{}", rendered);
+    }
+
+    #[test]
+    fn backs_off_past_the_failure_threshold() {
+        let config = Config {
+            refresh_interval: Duration::from_secs(10),
+            max_refresh_backoff: Some(Duration::from_secs(60)),
+            ..Config::default()
+        };
+        let publisher = Publisher::new(Vec::new(), Vec::new(), &config);
+        let backoff = |failures| {
+            publisher.stats.write().unwrap().consecutive_failures = failures;
+            publisher.backoff().map(|backoff| backoff.as_secs())
+        };
+        assert_eq!(backoff(0), None);
+        assert_eq!(backoff(BACKOFF_AFTER_FAILURES), None);
+        assert_eq!(backoff(BACKOFF_AFTER_FAILURES + 1), Some(20));
+        assert_eq!(backoff(BACKOFF_AFTER_FAILURES + 2), Some(40));
+        assert_eq!(backoff(BACKOFF_AFTER_FAILURES + 3), Some(60));
+        assert_eq!(backoff(u64::MAX), Some(60));
+
+        let publisher = Publisher::new(Vec::new(), Vec::new(), &Config::default());
+        publisher.stats.write().unwrap().consecutive_failures = u64::MAX;
+        assert_eq!(publisher.backoff(), None);
     }
 }
@@ -0,0 +1,213 @@
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::{error, fmt, io, net::SocketAddr, time::{Duration, Instant}};
+use tokio::{net::TcpStream, time::timeout};
+
+use crate::{
+    config::RpcLoginConfig,
+    levin::{self, Handshake, LevinError},
+    socks::{SocksError, SocksProxy},
+};
+
+pub const P2P_PORT: u16 = 18080;
+
+// of connecting to a port checked for another exporter, long enough for nodes far away
+const PORT_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+// of a check by another exporter, which takes up to the timeout above itself
+const PORT_CHECKER_TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Debug)]
+pub enum ProbeError {
+    Io(io::Error),
+    Timeout,
+    Levin(LevinError),
+    Socks(SocksError),
+    Checker(reqwest::Error),
+}
+
+impl error::Error for ProbeError {}
+
+impl fmt::Display for ProbeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProbeError::Io(e) => write!(f, "I/O error: {}", e),
+            ProbeError::Timeout => f.write_str("connection timed out"),
+            ProbeError::Levin(e) => write!(f, "P2P handshake failed: {}", e),
+            ProbeError::Socks(e) => write!(f, "SOCKS proxy: {}", e),
+            ProbeError::Checker(e) => write!(f, "port checker failed: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for ProbeError {
+    fn from(e: io::Error) -> Self {
+        ProbeError::Io(e)
+    }
+}
+
+// host:port, or only the host for the default port
+pub fn parse_address(address: &str, default_port: u16) -> Option<(String, u16)> {
+    let url = Url::parse(&format!("tcp://{}", address)).ok()?;
+    if !matches!(url.path(), "" | "/") || !url.username().is_empty() || url.query().is_some() {
+        return None;
+    }
+    let host = url.host_str()?.trim_start_matches('[').trim_end_matches(']').to_string();
+    Some((host, url.port().unwrap_or(default_port)))
+}
+
+// a TCP connection to a port of the node, which tells whether it accepts any
+#[derive(Clone, Debug)]
+pub struct TcpProbe {
+    host: String,
+    port: u16,
+    timeout: Duration,
+}
+
+impl TcpProbe {
+    pub fn new(address: &str, default_port: u16, timeout: Duration) -> Option<TcpProbe> {
+        let (host, port) = parse_address(address, default_port)?;
+        Some(TcpProbe { host, port, timeout })
+    }
+
+    pub fn for_peer(address: SocketAddr, timeout: Duration) -> TcpProbe {
+        TcpProbe { host: address.ip().to_string(), port: address.port(), timeout }
+    }
+
+    pub fn address(&self) -> String {
+        match self.host.contains(':') {
+            true => format!("[{}]:{}", self.host, self.port),
+            false => format!("{}:{}", self.host, self.port),
+        }
+    }
+
+    // the connection, and how long it took to be made including resolving the host
+    pub async fn connect(&self) -> Result<(TcpStream, Duration), ProbeError> {
+        let started_at = Instant::now();
+        let stream = timeout(self.timeout, TcpStream::connect((self.host.as_str(), self.port))).await
+            .map_err(|_| ProbeError::Timeout)??;
+        Ok((stream, started_at.elapsed()))
+    }
+
+    // a P2P handshake over the connection, within the timeout too, and how long it took
+    pub async fn handshake(
+        &self,
+        stream: &mut TcpStream,
+        network_id: [u8; 16],
+    ) -> Result<(Handshake, Duration), ProbeError> {
+        let started_at = Instant::now();
+        let handshake = timeout(self.timeout, levin::handshake(stream, network_id)).await
+            .map_err(|_| ProbeError::Timeout)?
+            .map_err(ProbeError::Levin)?;
+        Ok((handshake, started_at.elapsed()))
+    }
+}
+
+// a connection to an onion service of the node through Tor, which only succeeds once the service
+// connected to the port behind it, so it tells whether the node can be reached from outside
+#[derive(Clone, Debug)]
+pub struct OnionProbe {
+    proxy: SocksProxy,
+    host: String,
+    port: u16,
+    timeout: Duration,
+}
+
+impl OnionProbe {
+    pub fn new(address: &str, default_port: u16, proxy: &Url, timeout: Duration) -> Option<OnionProbe> {
+        let (host, port) = parse_address(address, default_port)?;
+        Some(OnionProbe { proxy: SocksProxy::from_url(proxy)?, host, port, timeout })
+    }
+
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    // how long it took to connect, which includes building the circuits unless Tor has them already
+    pub async fn connect(&self) -> Result<Duration, ProbeError> {
+        let started_at = Instant::now();
+        timeout(self.timeout, self.proxy.isolated().connect(&self.host, self.port)).await
+            .map_err(|_| ProbeError::Timeout)?
+            .map_err(ProbeError::Socks)?;
+        Ok(started_at.elapsed())
+    }
+}
+
+// whether a port could be connected to by the exporter, as another one is told by /check
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PortCheck {
+    pub reachable: bool,
+    // none if reachable
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+// a port checked for another exporter, none for an address without a port
+pub async fn check_port(address: &str) -> Option<PortCheck> {
+    let probe = TcpProbe::new(address, 0, PORT_CHECK_TIMEOUT).filter(|probe| probe.port != 0)?;
+    Some(match probe.connect().await {
+        Ok(_) => PortCheck { reachable: true, error: None },
+        Err(e) => PortCheck { reachable: false, error: Some(e.to_string()) },
+    })
+}
+
+// another exporter elsewhere, or any service responding alike, which checks whether the public
+// ports of the node can be connected to from the internet, which the node itself can't tell
+#[derive(Clone, Debug)]
+pub struct PortChecker {
+    client: reqwest::Client,
+    // of the /check endpoint
+    url: Url,
+    login: Option<RpcLoginConfig>,
+}
+
+impl PortChecker {
+    // the login of the URL is sent as basic auth
+    pub fn new(base_url: &str) -> Option<PortChecker> {
+        let client = reqwest::Client::builder().timeout(PORT_CHECKER_TIMEOUT).build().ok()?;
+        let mut url = Url::parse(base_url).ok()?;
+        let path = format!("{}/check", url.path().trim_end_matches('/'));
+        url.set_path(&path);
+        let login = match (url.username(), url.password()) {
+            ("", None) => None,
+            (username, password) => Some(RpcLoginConfig {
+                username: username.to_string(),
+                password: password.unwrap_or_default().to_string(),
+            }),
+        };
+        url.set_username("").ok()?;
+        url.set_password(None).ok()?;
+        Some(PortChecker { client, url, login })
+    }
+
+    pub async fn check(&self, address: &str) -> Result<PortCheck, ProbeError> {
+        let mut request = self.client.get(self.url.clone()).query(&[("address", address)]);
+        if let Some(login) = &self.login {
+            request = request.basic_auth(&login.username, Some(&login.password));
+        }
+        let response = request.send().await
+            .and_then(|response| response.error_for_status())
+            .map_err(ProbeError::Checker)?;
+        response.json().await.map_err(ProbeError::Checker)
+    }
+}
+
+// a random sample of up to count of the items
+pub fn sample<T>(mut items: Vec<T>, count: usize) -> Vec<T> {
+    let count = count.min(items.len());
+    let mut random = vec![0; count * 8];
+    if getrandom::getrandom(&mut random).is_err() {
+        items.truncate(count);
+        return items;
+    }
+    // the first count steps of a Fisher-Yates shuffle
+    for (i, random) in random.chunks(8).enumerate() {
+        let random = u64::from_le_bytes([
+            random[0], random[1], random[2], random[3], random[4], random[5], random[6], random[7],
+        ]);
+        let j = i + (random % (items.len() - i) as u64) as usize;
+        items.swap(i, j);
+    }
+    items.truncate(count);
+    items
+}
@@ -0,0 +1,77 @@
+use std::fs;
+
+use crate::prometheus::Metric;
+
+#[cfg(target_os = "linux")]
+fn read_stat_fields() -> Option<Vec<String>> {
+    let stat = fs::read_to_string("/proc/self/stat").ok()?;
+    // the command name may contain spaces, fields are counted after its closing parenthesis
+    let (_, fields) = stat.rsplit_once(')')?;
+    Some(fields.split_whitespace().map(String::from).collect())
+}
+
+#[cfg(target_os = "linux")]
+fn read_boot_time() -> Option<u64> {
+    fs::read_to_string("/proc/stat").ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("btime "))
+        .and_then(|btime| btime.trim().parse().ok())
+}
+
+#[cfg(target_os = "linux")]
+fn read_max_fds() -> Option<u64> {
+    fs::read_to_string("/proc/self/limits").ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("Max open files"))
+        .and_then(|limits| limits.split_whitespace().next())
+        .and_then(|soft_limit| soft_limit.parse().ok())
+}
+
+#[cfg(target_os = "linux")]
+pub fn process_metrics() -> Vec<Metric> {
+    let mut metrics = Vec::with_capacity(6);
+
+    let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as f64;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as f64;
+
+    // field N of /proc/self/stat is at index N - 3
+    let stat_field = |fields: &[String], n: usize| -> Option<f64> {
+        fields.get(n - 3).and_then(|f| f.parse::<f64>().ok())
+    };
+
+    if let Some(fields) = read_stat_fields() {
+        if let (Some(utime), Some(stime)) = (stat_field(&fields, 14), stat_field(&fields, 15)) {
+            metrics.push(Metric::new_counter(
+                "process_cpu_seconds_total",
+                (utime + stime) / ticks_per_second,
+            ));
+        }
+        if let (Some(start_time), Some(boot_time)) = (stat_field(&fields, 22), read_boot_time()) {
+            metrics.push(Metric::new_gauge(
+                "process_start_time_seconds",
+                boot_time as f64 + start_time / ticks_per_second,
+            ));
+        }
+        if let Some(vsize) = stat_field(&fields, 23) {
+            metrics.push(Metric::new_gauge("process_virtual_memory_bytes", vsize));
+        }
+        if let Some(rss) = stat_field(&fields, 24) {
+            metrics.push(Metric::new_gauge("process_resident_memory_bytes", rss * page_size));
+        }
+    }
+
+    if let Ok(fds) = fs::read_dir("/proc/self/fd") {
+        metrics.push(Metric::new_gauge("process_open_fds", fds.count() as f64));
+    }
+
+    if let Some(max_fds) = read_max_fds() {
+        metrics.push(Metric::new_gauge("process_max_fds", max_fds as f64));
+    }
+
+    metrics
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn process_metrics() -> Vec<Metric> {
+    Vec::new()
+}
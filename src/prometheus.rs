@@ -1,89 +1,960 @@
-use std::fmt::{self, Write};
+use serde::Deserialize;
+use std::{collections::BTreeMap, fmt::{self, Write}, time::{SystemTime, UNIX_EPOCH}};
 
-#[derive(Debug)]
+use crate::protobuf::Message;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    // Prometheus text exposition format 0.0.4
+    Text,
+    // OpenMetrics 1.0.0, the only text format supporting exemplars
+    OpenMetrics,
+    // length-delimited MetricFamily messages, the only format supporting native histograms,
+    // which is encoded by the registry rather than rendered
+    Protobuf,
+}
+
+impl Format {
+    // picks the format the scraper prefers by the q values of the Accept header,
+    // the first one of those preferred alike, and text if it accepts none
+    pub fn negotiate(accept: Option<&str>) -> Format {
+        let mut negotiated = (Format::Text, 0.0);
+        for media_range in accept.unwrap_or_default().split(',') {
+            let mut params = media_range.split(';').map(str::trim);
+            let media_type = params.next().unwrap_or_default();
+            let mut q = 1.0;
+            let mut delimited = false;
+            for (name, value) in params.filter_map(|param| param.split_once('=')) {
+                match name.trim() {
+                    "q" => q = value.trim().parse::<f64>().unwrap_or(0.0),
+                    "encoding" => delimited = value.trim() == "delimited",
+                    _ => {},
+                }
+            }
+            let format = match media_type {
+                "text/plain" => Format::Text,
+                "application/openmetrics-text" => Format::OpenMetrics,
+                "application/vnd.google.protobuf" if delimited => Format::Protobuf,
+                _ => continue,
+            };
+            if q > negotiated.1 {
+                negotiated = (format, q);
+            }
+        }
+        negotiated.0
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Text => "text/plain; version=0.0.4; charset=utf-8",
+            Format::OpenMetrics => "application/openmetrics-text; version=1.0.0; charset=utf-8",
+            Format::Protobuf => {
+                "application/vnd.google.protobuf; proto=io.prometheus.client.MetricFamily; encoding=delimited"
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 enum MetricType {
+    Counter,
     Gauge,
+    Histogram,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 struct MetricLabel {
     name: String,
     value: String,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
+struct Exemplar {
+    labels: Vec<MetricLabel>,
+    value: f64,
+    // seconds since unix epoch
+    timestamp: Option<f64>,
+}
+
+#[derive(Clone, Debug)]
 struct MetricValue {
-    label: Option<MetricLabel>,
+    suffix: &'static str,
+    labels: Vec<MetricLabel>,
     value: f64,
+    exemplar: Option<Exemplar>,
+    // the native buckets of a histogram, on its _count sample
+    native: Option<NativeBuckets>,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Metric {
     t: MetricType,
     name: String,
+    help: Option<String>,
+    timestamp: Option<i64>,
+    // seconds since unix epoch, rendered as _created series in OpenMetrics
+    created: Option<f64>,
     values: Vec<MetricValue>,
 }
 
-// TODO: validation
+// user-defined rewrite of the exported metrics, e.g. to keep metric names of another exporter
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct RewriteRule {
+    // name of the metric to rewrite, every metric if not set
+    pub metric: Option<String>,
+    pub rename: Option<String>,
+    pub drop_labels: Vec<String>,
+    // label whose values are replaced according to label_values
+    pub label: Option<String>,
+    pub label_values: BTreeMap<String, String>,
+}
+
+// names and values of the labels of a sample
+pub type Labels = Vec<(String, String)>;
+
+pub const DEFAULT_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+// native buckets grow by a factor of 2^(2^-schema), about 1.09 for 3
+const NATIVE_SCHEMA: i32 = 3;
+const MIN_NATIVE_SCHEMA: i32 = -4;
+// the resolution is halved as long as there are more buckets
+const MAX_NATIVE_BUCKETS: usize = 160;
+// values closer to 0 are counted in the zero bucket, 2^-128 like the Go client
+const NATIVE_ZERO_THRESHOLD: f64 = 2.938735877055719e-39;
+
+// the exponential buckets of a native histogram, which only the protobuf format exposes
+#[derive(Clone, Debug)]
+struct NativeBuckets {
+    schema: i32,
+    zero_count: u64,
+    // counts by index, bucket i holding the values in (2^((i - 1) * 2^-schema), 2^(i * 2^-schema)]
+    // and the negative buckets their opposites
+    positive: BTreeMap<i32, u64>,
+    negative: BTreeMap<i32, u64>,
+}
+
+impl NativeBuckets {
+    fn new() -> NativeBuckets {
+        NativeBuckets {
+            schema: NATIVE_SCHEMA,
+            zero_count: 0,
+            positive: BTreeMap::new(),
+            negative: BTreeMap::new(),
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if value.is_nan() {
+            return;
+        }
+        if value.abs() <= NATIVE_ZERO_THRESHOLD {
+            self.zero_count += 1;
+            return;
+        }
+        let index = (value.abs().log2() * 2f64.powi(self.schema)).ceil() as i32;
+        let buckets = if value > 0.0 { &mut self.positive } else { &mut self.negative };
+        *buckets.entry(index).or_default() += 1;
+
+        while self.positive.len() + self.negative.len() > MAX_NATIVE_BUCKETS && self.schema > MIN_NATIVE_SCHEMA {
+            self.schema -= 1;
+            self.positive = merge_bucket_pairs(&self.positive);
+            self.negative = merge_bucket_pairs(&self.negative);
+        }
+    }
+}
+
+// buckets 2i - 1 and 2i of a schema are bucket i of the next lower one
+fn merge_bucket_pairs(buckets: &BTreeMap<i32, u64>) -> BTreeMap<i32, u64> {
+    let mut merged = BTreeMap::new();
+    for (index, count) in buckets {
+        *merged.entry((index + 1).div_euclid(2)).or_default() += count;
+    }
+    merged
+}
+
+// a histogram with both the classic buckets of its bounds, which every format exposes,
+// and native ones
+#[derive(Clone, Debug)]
+pub struct Histogram {
+    bounds: Vec<f64>,
+    // per-bucket (non-cumulative) counts, the last one is the +Inf bucket
+    counts: Vec<u64>,
+    sum: f64,
+    native: NativeBuckets,
+}
+
+impl Histogram {
+    pub fn new(bounds: &[f64]) -> Histogram {
+        let mut bounds = bounds.iter()
+            .cloned()
+            .filter(|b| b.is_finite())
+            .collect::<Vec<_>>();
+        bounds.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        bounds.dedup();
+
+        Histogram {
+            counts: vec![0; bounds.len() + 1],
+            bounds,
+            sum: 0.0,
+            native: NativeBuckets::new(),
+        }
+    }
+
+    pub fn observe(&mut self, value: f64) {
+        let bucket = self.bounds.iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(self.bounds.len());
+        self.counts[bucket] += 1;
+        self.sum += value;
+        self.native.observe(value);
+    }
+
+    fn values(&self, labels: Vec<MetricLabel>) -> Vec<MetricValue> {
+        let mut values = Vec::with_capacity(self.counts.len() + 2);
+        let mut cumulative_count = 0;
+
+        for (i, count) in self.counts.iter().enumerate() {
+            cumulative_count += count;
+            let le = match self.bounds.get(i) {
+                Some(bound) => bound.to_string(),
+                None => "+Inf".into(),
+            };
+            let mut labels = labels.clone();
+            labels.push(MetricLabel { name: "le".into(), value: le });
+            values.push(MetricValue {
+                suffix: "_bucket",
+                labels,
+                value: cumulative_count as f64,
+                exemplar: None,
+                native: None,
+            });
+        }
+
+        values.push(MetricValue {
+            suffix: "_sum",
+            labels: labels.clone(),
+            value: self.sum,
+            exemplar: None,
+            native: None,
+        });
+        values.push(MetricValue {
+            suffix: "_count",
+            labels,
+            value: cumulative_count as f64,
+            exemplar: None,
+            native: Some(self.native.clone()),
+        });
+        values
+    }
+}
+
+// replaces characters not matching [a-zA-Z_:][a-zA-Z0-9_:]* (colons are only valid in metric names)
+fn sanitize_name(name: String, allow_colons: bool) -> String {
+    let is_valid = |i: usize, c: char| {
+        c.is_ascii_alphabetic() || c == '_' || (allow_colons && c == ':') || (i > 0 && c.is_ascii_digit())
+    };
+
+    if !name.is_empty() && name.chars().enumerate().all(|(i, c)| is_valid(i, c)) {
+        return name;
+    }
+
+    let mut sanitized = String::with_capacity(name.len() + 1);
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+        sanitized.push('_');
+    }
+    sanitized.extend(name.chars().map(|c| if is_valid(1, c) { c } else { '_' }));
+    sanitized
+}
+
+// label names starting with "__" are reserved for internal use by Prometheus
+fn sanitize_label_name(name: String) -> String {
+    let name = sanitize_name(name, false);
+    match name.strip_prefix("__") {
+        None => name,
+        Some(stripped) => sanitize_name(stripped.trim_start_matches('_').to_string(), false),
+    }
+}
+
 impl Metric {
     pub fn new_gauge<S: Into<String>>(name: S, value: f64) -> Metric {
-        let value = MetricValue { label: None, value };
+        let value = MetricValue { suffix: "", labels: Vec::new(), value, exemplar: None, native: None };
         Metric {
             t: MetricType::Gauge,
-            name: name.into(),
+            name: sanitize_name(name.into(), true),
+            help: None,
+            timestamp: None,
+            created: None,
             values: vec![value],
         }
     }
 
-    pub fn new_gauge_with_label_values<S, V>(name: S, label_name: S, values: V) -> Metric
+    pub fn new_counter<S: Into<String>>(name: S, value: f64) -> Metric {
+        let value = MetricValue { suffix: "", labels: Vec::new(), value, exemplar: None, native: None };
+        Metric {
+            t: MetricType::Counter,
+            name: sanitize_name(name.into(), true),
+            help: None,
+            timestamp: None,
+            created: None,
+            values: vec![value],
+        }
+    }
+
+    pub fn new_gauge_with_labels<S, L, V>(name: S, values: V) -> Metric
     where
         S: Into<String>,
-        V: IntoIterator<Item = (String, f64)>,
+        L: IntoIterator<Item = (String, String)>,
+        V: IntoIterator<Item = (L, f64)>,
     {
-        let name = name.into();
-        let label_name = label_name.into();
-
         let values = values.into_iter()
-            .map(|(label_value, value)| {
-                let label = Some (MetricLabel { name: label_name.clone(), value: label_value });
-                MetricValue { label, value }
+            .map(|(labels, value)| {
+                let labels = labels.into_iter()
+                    .map(|(name, value)| MetricLabel { name: sanitize_label_name(name), value })
+                    .collect();
+                MetricValue { suffix: "", labels, value, exemplar: None, native: None }
             })
             .collect();
 
         Metric {
             t: MetricType::Gauge,
-            name,
+            name: sanitize_name(name.into(), true),
+            help: None,
+            timestamp: None,
+            created: None,
             values,
         }
     }
 
-    pub fn render<W: Write>(&self, sink: &mut W) -> fmt::Result {
-        sink.write_fmt(format_args!("# HELP {}\n", self.name))?;
+    // info metrics carry their data in labels of a single sample with the value 1
+    pub fn new_info<S, L>(name: S, labels: L) -> Metric
+    where
+        S: Into<String>,
+        L: IntoIterator<Item = (String, String)>,
+    {
+        Metric::new_gauge_with_labels(name, vec![(labels, 1.0)])
+    }
+
+    pub fn new_histogram<S: Into<String>>(name: S, histogram: &Histogram) -> Metric {
+        Metric {
+            t: MetricType::Histogram,
+            name: sanitize_name(name.into(), true),
+            help: None,
+            timestamp: None,
+            created: None,
+            values: histogram.values(Vec::new()),
+        }
+    }
+
+    pub fn new_histogram_with_label_values<'a, S, V>(name: S, label_name: S, values: V) -> Metric
+    where
+        S: Into<String>,
+        V: IntoIterator<Item = (String, &'a Histogram)>,
+    {
+        let label_name = sanitize_label_name(label_name.into());
+
+        let values = values.into_iter()
+            .flat_map(|(label_value, histogram)| {
+                let label = MetricLabel { name: label_name.clone(), value: label_value };
+                histogram.values(vec![label])
+            })
+            .collect();
+
+        Metric {
+            t: MetricType::Histogram,
+            name: sanitize_name(name.into(), true),
+            help: None,
+            timestamp: None,
+            created: None,
+            values,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn help(&self) -> Option<&str> {
+        self.help.as_deref()
+    }
+
+    pub fn with_help<S: Into<String>>(self, help: S) -> Metric {
+        Metric {
+            help: Some(help.into()),
+            ..self
+        }
+    }
+
+    pub fn with_const_labels<'a, L>(mut self, labels: L) -> Metric
+    where
+        L: IntoIterator<Item = (&'a String, &'a String)> + Clone,
+    {
+        for value in self.values.iter_mut() {
+            let const_labels = labels.clone().into_iter()
+                .filter(|(name, _)| !value.labels.iter().any(|l| &l.name == *name))
+                .map(|(name, value)| MetricLabel {
+                    name: sanitize_label_name(name.clone()),
+                    value: value.clone(),
+                })
+                .collect::<Vec<_>>();
+            value.labels.extend(const_labels);
+        }
+        self
+    }
+
+    // timestamp in milliseconds since unix epoch attached to every sample
+    pub fn with_timestamp(self, timestamp: i64) -> Metric {
+        Metric {
+            timestamp: Some(timestamp),
+            ..self
+        }
+    }
+
+    // exemplars are only allowed on counters and histogram buckets, a histogram gets it
+    // on the first bucket containing the exemplar value
+    pub fn with_exemplar<L>(mut self, labels: L, value: f64, timestamp: Option<SystemTime>) -> Metric
+    where
+        L: IntoIterator<Item = (String, String)>,
+    {
+        let exemplar = Exemplar {
+            labels: labels.into_iter()
+                .map(|(name, value)| MetricLabel { name: sanitize_label_name(name), value })
+                .collect(),
+            value,
+            timestamp: timestamp
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs_f64()),
+        };
+
+        match self.t {
+            MetricType::Counter => {
+                for sample in self.values.iter_mut() {
+                    sample.exemplar = Some(exemplar.clone());
+                }
+            },
+            MetricType::Histogram => {
+                // buckets of every label set are consecutive and end with the +Inf one
+                let mut attached = false;
+                for sample in self.values.iter_mut().filter(|v| v.suffix == "_bucket") {
+                    let le = sample.labels.iter()
+                        .find(|l| l.name == "le")
+                        .map(|l| l.value.as_str())
+                        .unwrap_or("+Inf");
+                    let is_inf = le == "+Inf";
+                    let contains = is_inf || le.parse::<f64>().map(|le| value <= le).unwrap_or(false);
+                    if contains && !attached {
+                        sample.exemplar = Some(exemplar.clone());
+                        attached = true;
+                    }
+                    if is_inf {
+                        attached = false;
+                    }
+                }
+            },
+            MetricType::Gauge => {},
+        }
+        self
+    }
+
+    // time since which a counter or histogram has been counting
+    pub fn with_created(self, created: SystemTime) -> Metric {
+        match self.t {
+            MetricType::Counter | MetricType::Histogram => Metric {
+                created: created.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs_f64()),
+                ..self
+            },
+            MetricType::Gauge => self,
+        }
+    }
+
+    // dropping labels may leave samples with the same label set, it's up to the rule to avoid that
+    pub fn rewrite(mut self, rule: &RewriteRule) -> Metric {
+        match &rule.metric {
+            Some(metric) if *metric != self.name => return self,
+            _ => {},
+        }
+
+        if let Some(name) = &rule.rename {
+            self.name = sanitize_name(name.clone(), true);
+        }
+
+        for value in self.values.iter_mut() {
+            value.labels.retain(|label| !rule.drop_labels.contains(&label.name));
+
+            let labels = value.labels.iter_mut()
+                .filter(|label| Some(&label.name) == rule.label.as_ref());
+            for label in labels {
+                if let Some(mapped) = rule.label_values.get(&label.value) {
+                    label.value = mapped.clone();
+                }
+            }
+        }
+
+        self
+    }
+
+    // samples of a family with a different type can't be represented in it and are dropped
+    fn merge(&mut self, other: Metric) {
+        if self.t != other.t {
+            return;
+        }
+        if self.help.is_none() {
+            self.help = other.help;
+        }
+        self.values.extend(other.values);
+    }
+
+    pub fn samples(&self) -> usize {
+        self.values.len()
+    }
+
+    // name, labels and value of every sample, histograms have one per bucket, sum and count
+    pub fn series(&self) -> Vec<(String, Labels, f64)> {
+        self.values.iter()
+            .map(|value| {
+                let labels = value.labels.iter()
+                    .map(|label| (label.name.clone(), label.value.clone()))
+                    .collect();
+                (format!("{}{}", self.name, value.suffix), labels, value.value)
+            })
+            .collect()
+    }
+
+    pub fn render<W: Write>(&self, sink: &mut W, format: Format) -> fmt::Result {
+        // OpenMetrics names counter families without the _total suffix of their samples
+        let (family_name, sample_suffix) = match (format, &self.t) {
+            (Format::OpenMetrics, MetricType::Counter) => {
+                (self.name.strip_suffix("_total").unwrap_or(&self.name), "_total")
+            },
+            _ => (self.name.as_str(), ""),
+        };
+
+        sink.write_fmt(format_args!("# HELP {}", family_name))?;
+        if let Some(help) = &self.help {
+            sink.write_char(' ')?;
+            write_escaped(sink, help, format == Format::OpenMetrics)?;
+        }
+        sink.write_char('\n')?;
 
         let type_str = match self.t {
-            MetricType::Gauge => "gauge"
+            MetricType::Counter => "counter",
+            MetricType::Gauge => "gauge",
+            MetricType::Histogram => "histogram",
         };
-        sink.write_fmt(format_args!("# TYPE {} {}\n", self.name, type_str))?;
+        sink.write_fmt(format_args!("# TYPE {} {}\n", family_name, type_str))?;
 
         for value in self.values.iter() {
-            sink.write_str(&self.name)?;
-            if let Some(label) = &value.label {
-                sink.write_fmt(format_args!("{{{}=\"{}\"}}", label.name, label.value))?;
+            sink.write_str(family_name)?;
+            sink.write_str(sample_suffix)?;
+            sink.write_str(value.suffix)?;
+            write_labels(sink, &value.labels)?;
+            sink.write_fmt(format_args!(" {}", value.value))?;
+            // text format timestamps are in milliseconds, OpenMetrics ones in seconds
+            match (self.timestamp, format) {
+                (None, _) => {},
+                (Some(timestamp), Format::OpenMetrics) => {
+                    sink.write_fmt(format_args!(" {}", timestamp as f64 / 1000.0))?
+                },
+                (Some(timestamp), _) => sink.write_fmt(format_args!(" {}", timestamp))?,
+            }
+            match &value.exemplar {
+                Some(exemplar) if format == Format::OpenMetrics => {
+                    sink.write_str(" # ")?;
+                    if exemplar.labels.is_empty() {
+                        sink.write_str("{}")?;
+                    }
+                    write_labels(sink, &exemplar.labels)?;
+                    sink.write_fmt(format_args!(" {}", exemplar.value))?;
+                    if let Some(timestamp) = exemplar.timestamp {
+                        sink.write_fmt(format_args!(" {}", timestamp))?;
+                    }
+                },
+                _ => {},
+            }
+            sink.write_char('\n')?;
+
+            // every label set of a counter or a histogram gets its own _created series
+            let has_created = match self.t {
+                MetricType::Counter => true,
+                MetricType::Histogram => value.suffix == "_count",
+                MetricType::Gauge => false,
+            };
+            match self.created {
+                Some(created) if format == Format::OpenMetrics && has_created => {
+                    sink.write_fmt(format_args!("{}_created", family_name))?;
+                    write_labels(sink, &value.labels)?;
+                    sink.write_fmt(format_args!(" {}\n", created))?;
+                },
+                _ => {},
             }
-            sink.write_fmt(format_args!(" {}\n", value.value))?;
         }
 
         Ok(())
     }
+
+    // a MetricFamily message, whose histograms have a Metric per label set
+    // with both their classic and native buckets
+    fn encode(&self) -> Message {
+        let mut family = Message::new();
+        family.string(1, &self.name);
+        if let Some(help) = &self.help {
+            family.string(2, help);
+        }
+        family.uint(3, match self.t {
+            MetricType::Counter => 0,
+            MetricType::Gauge => 1,
+            MetricType::Histogram => 4,
+        });
+
+        // the buckets of every label set of a histogram are followed by its _sum and _count
+        let mut histogram = Message::new();
+        for value in self.values.iter() {
+            let mut metric = Message::new();
+            match (&self.t, value.suffix) {
+                (MetricType::Counter, _) => {
+                    let mut counter = Message::new();
+                    counter.double(1, value.value);
+                    if let Some(exemplar) = &value.exemplar {
+                        counter.message(2, &encode_exemplar(exemplar));
+                    }
+                    if let Some(created) = self.created {
+                        counter.message(3, &encode_timestamp(created));
+                    }
+                    metric.message(3, &counter);
+                },
+                (MetricType::Gauge, _) => {
+                    metric.message(2, Message::new().double(1, value.value));
+                },
+                (MetricType::Histogram, "_bucket") => {
+                    // the +Inf bucket is the count
+                    let upper_bound = value.labels.iter()
+                        .find(|label| label.name == "le")
+                        .and_then(|label| label.value.parse::<f64>().ok())
+                        .filter(|upper_bound| upper_bound.is_finite());
+                    if let Some(upper_bound) = upper_bound {
+                        let mut bucket = Message::new();
+                        bucket.uint(1, value.value as u64).double(2, upper_bound);
+                        if let Some(exemplar) = &value.exemplar {
+                            bucket.message(3, &encode_exemplar(exemplar));
+                        }
+                        histogram.message(3, &bucket);
+                    }
+                    continue;
+                },
+                (MetricType::Histogram, "_sum") => {
+                    histogram.double(2, value.value);
+                    continue;
+                },
+                (MetricType::Histogram, _) => {
+                    histogram.uint(1, value.value as u64);
+                    if let Some(native) = &value.native {
+                        histogram.sint(5, native.schema as i64)
+                            .double(6, NATIVE_ZERO_THRESHOLD)
+                            .uint(7, native.zero_count);
+                        encode_native_buckets(&mut histogram, &native.negative, 9, 10);
+                        encode_native_buckets(&mut histogram, &native.positive, 12, 13);
+                    }
+                    if let Some(created) = self.created {
+                        histogram.message(15, &encode_timestamp(created));
+                    }
+                    metric.message(7, &histogram);
+                    histogram = Message::new();
+                },
+            }
+            for label in value.labels.iter() {
+                metric.message(1, &encode_label(label));
+            }
+            if let Some(timestamp) = self.timestamp {
+                metric.int(6, timestamp);
+            }
+            family.message(4, &metric);
+        }
+        family
+    }
+}
+
+fn encode_label(label: &MetricLabel) -> Message {
+    let mut pair = Message::new();
+    pair.string(1, &label.name).string(2, &label.value);
+    pair
+}
+
+// seconds since unix epoch
+fn encode_timestamp(timestamp: f64) -> Message {
+    let mut message = Message::new();
+    message.int(1, timestamp.floor() as i64).int(2, (timestamp.fract() * 1e9) as i64);
+    message
+}
+
+fn encode_exemplar(exemplar: &Exemplar) -> Message {
+    let mut message = Message::new();
+    for label in exemplar.labels.iter() {
+        message.message(1, &encode_label(label));
+    }
+    message.double(2, exemplar.value);
+    if let Some(timestamp) = exemplar.timestamp {
+        message.message(3, &encode_timestamp(timestamp));
+    }
+    message
+}
+
+// spans of consecutive buckets, each offset from the end of the previous one,
+// and the counts as deltas from the previous bucket
+fn encode_native_buckets(histogram: &mut Message, buckets: &BTreeMap<i32, u64>, span_field: u32, delta_field: u32) {
+    let mut spans: Vec<(i32, u32)> = Vec::new();
+    let mut next_index = 0;
+    for index in buckets.keys() {
+        match spans.last_mut() {
+            Some((_, length)) if *index == next_index => *length += 1,
+            Some(_) => spans.push((index - next_index, 1)),
+            None => spans.push((*index, 1)),
+        }
+        next_index = index + 1;
+    }
+    for (offset, length) in spans {
+        histogram.message(span_field, Message::new().sint(1, offset as i64).uint(2, length as u64));
+    }
+    let mut previous = 0;
+    for count in buckets.values() {
+        histogram.sint(delta_field, *count as i64 - previous);
+        previous = *count as i64;
+    }
+}
+
+fn write_labels<W: Write>(sink: &mut W, labels: &[MetricLabel]) -> fmt::Result {
+    for (i, label) in labels.iter().enumerate() {
+        let prefix = if i == 0 { '{' } else { ',' };
+        sink.write_fmt(format_args!("{}{}=\"", prefix, label.name))?;
+        write_escaped(sink, &label.value, true)?;
+        sink.write_char('"')?;
+    }
+    if !labels.is_empty() {
+        sink.write_char('}')?;
+    }
+    Ok(())
+}
+
+// escapes backslashes and line feeds, and also double quotes in label values and OpenMetrics help
+fn write_escaped<W: Write>(sink: &mut W, s: &str, escape_quotes: bool) -> fmt::Result {
+    for c in s.chars() {
+        match c {
+            '\\' => sink.write_str("\\\\")?,
+            '\n' => sink.write_str("\\n")?,
+            '"' if escape_quotes => sink.write_str("\\\"")?,
+            c => sink.write_char(c)?,
+        }
+    }
+    Ok(())
+}
+
+// metric family declared once with its name, type, help and label names, whose samples
+// are set on every refresh
+#[derive(Clone, Debug)]
+pub struct Family {
+    metric: Metric,
+    label_names: Vec<String>,
+}
+
+impl Family {
+    fn new<S: Into<String>>(t: MetricType, name: S, label_names: &[&str]) -> Family {
+        Family {
+            metric: Metric {
+                t,
+                name: sanitize_name(name.into(), true),
+                help: None,
+                timestamp: None,
+                created: None,
+                values: Vec::new(),
+            },
+            label_names: label_names.iter().map(|name| sanitize_label_name(name.to_string())).collect(),
+        }
+    }
+
+    pub fn gauge<S: Into<String>>(name: S, label_names: &[&str]) -> Family {
+        Family::new(MetricType::Gauge, name, label_names)
+    }
+
+    pub fn counter<S: Into<String>>(name: S, label_names: &[&str]) -> Family {
+        Family::new(MetricType::Counter, name, label_names)
+    }
+
+    pub fn with_help<S: Into<String>>(self, help: S) -> Family {
+        Family {
+            metric: self.metric.with_help(help),
+            ..self
+        }
+    }
+
+    // label values are given in the order of the declared label names, the sample with
+    // the same label values is replaced
+    pub fn set<V: AsRef<str>>(&mut self, label_values: &[V], value: f64) {
+        debug_assert_eq!(label_values.len(), self.label_names.len(), "labels of {}", self.metric.name);
+        let label_names = &self.label_names;
+        let same_labels = |sample: &&mut MetricValue| {
+            sample.labels.iter()
+                .zip(label_values.iter())
+                .all(|(label, value)| label.value == value.as_ref())
+        };
+
+        match self.metric.values.iter_mut().find(same_labels) {
+            Some(sample) => sample.value = value,
+            None => {
+                let labels = label_names.iter()
+                    .zip(label_values.iter())
+                    .map(|(name, value)| MetricLabel {
+                        name: name.clone(),
+                        value: value.as_ref().into(),
+                    })
+                    .collect();
+                self.metric.values.push(MetricValue { suffix: "", labels, value, exemplar: None, native: None });
+            },
+        }
+    }
+
+    // drops the samples but keeps the allocation for the next refresh
+    pub fn clear(&mut self) {
+        self.metric.values.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.metric.values.is_empty()
+    }
+
+    pub fn to_metric(&self) -> Metric {
+        self.metric.clone()
+    }
+}
+
+// metric families keyed and rendered by name, samples of the same family are merged
+#[derive(Debug, Default)]
+pub struct Registry {
+    families: BTreeMap<String, Metric>,
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry::default()
+    }
+
+    pub fn register(&mut self, metric: Metric) {
+        match self.families.get_mut(&metric.name) {
+            None => {
+                self.families.insert(metric.name.clone(), metric);
+            },
+            Some(family) => family.merge(metric),
+        }
+    }
+
+    pub fn render<W: Write>(&self, sink: &mut W, format: Format) -> fmt::Result {
+        render_metrics(self.families.values(), sink, format)
+    }
+
+    pub fn series(&self) -> Vec<(String, Labels, f64)> {
+        self.families.values().flat_map(Metric::series).collect()
+    }
+
+    // the protobuf format, which can't be rendered as text
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        for metric in self.families.values() {
+            metric.encode().push_delimited(&mut data);
+        }
+        data
+    }
 }
 
-pub fn render_metrics<'a, M, W: Write>(metrics: M, sink: &mut W) -> fmt::Result
+impl Extend<Metric> for Registry {
+    fn extend<I: IntoIterator<Item = Metric>>(&mut self, metrics: I) {
+        for metric in metrics {
+            self.register(metric);
+        }
+    }
+}
+
+pub fn render_metrics<'a, M, W: Write>(metrics: M, sink: &mut W, format: Format) -> fmt::Result
 where
     M: IntoIterator<Item = &'a Metric>,
 {
     for metric in metrics {
-        metric.render(sink)?;
+        metric.render(sink, format)?;
+    }
+
+    if format == Format::OpenMetrics {
+        sink.write_str("# EOF\n")?;
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(metric: &Metric, format: Format) -> String {
+        let mut rendered = String::new();
+        metric.render(&mut rendered, format).unwrap();
+        rendered
+    }
+
+    fn labeled(value: &str) -> Metric {
+        Metric::new_info("test_info", vec![("value".to_string(), value.to_string())])
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        let cases = [
+            ("back\\slash", "back\\\\slash"),
+            ("double \"quotes\"", "double \\\"quotes\\\""),
+            ("line\nfeed", "line\\nfeed"),
+            ("", ""),
+            ("nön-ÄSCII ✓", "nön-ÄSCII ✓"),
+        ];
+        for (value, escaped) in cases.iter() {
+            let sample = format!("test_info{{value=\"{}\"}} 1\n", escaped);
+            assert!(render(&labeled(value), Format::Text).ends_with(&sample), "{:?}", value);
+            assert!(render(&labeled(value), Format::OpenMetrics).ends_with(&sample), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn escapes_help() {
+        let metric = Metric::new_gauge("test", 1.0).with_help("a \"quoted\" back\\slash\nand ü");
+        assert!(render(&metric, Format::Text)
+            .starts_with("# HELP test a \"quoted\" back\\\\slash\\nand ü\n"));
+        assert!(render(&metric, Format::OpenMetrics)
+            .starts_with("# HELP test a \\\"quoted\\\" back\\\\slash\\nand ü\n"));
+    }
+
+    #[test]
+    fn encodes_native_bucket_spans() {
+        let mut histogram = Message::new();
+        let buckets = [(-1, 2), (0, 3), (3, 1)].iter().cloned().collect::<BTreeMap<i32, u64>>();
+        encode_native_buckets(&mut histogram, &buckets, 12, 13);
+        let mut encoded = Vec::new();
+        histogram.push_delimited(&mut encoded);
+        assert_eq!(encoded, [
+            0x12,
+            // two buckets from -1
+            0x62, 0x04, 0x08, 0x01, 0x10, 0x02,
+            // one bucket two after them
+            0x62, 0x04, 0x08, 0x04, 0x10, 0x01,
+            // the deltas 2, 1 and -2
+            0x68, 0x04, 0x68, 0x02, 0x68, 0x03,
+        ]);
+
+        let mut empty = Message::new();
+        encode_native_buckets(&mut empty, &BTreeMap::new(), 12, 13);
+        let mut encoded = Vec::new();
+        empty.push_delimited(&mut encoded);
+        assert_eq!(encoded, [0x00]);
+    }
+
+    #[test]
+    fn encodes_delimited_families() {
+        let mut registry = Registry::new();
+        registry.extend(vec![Metric::new_gauge("test", 1.0)]);
+        // the name, the type and a Metric with a gauge of 1.0
+        let mut expected = vec![0x15, 0x0a, 0x04, b't', b'e', b's', b't', 0x18, 0x01];
+        expected.extend_from_slice(&[0x22, 0x0b, 0x12, 0x09, 0x09, 0, 0, 0, 0, 0, 0, 0xf0, 0x3f]);
+        assert_eq!(registry.encode(), expected);
+    }
+}
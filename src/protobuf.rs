@@ -0,0 +1,126 @@
+// the wire format of protocol buffers, only as far as encoding messages goes
+const WIRE_VARINT: u32 = 0;
+const WIRE_FIXED64: u32 = 1;
+const WIRE_LEN: u32 = 2;
+
+fn push_varint(data: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        data.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    data.push(value as u8);
+}
+
+// a message, whose fields are appended in order
+#[derive(Clone, Debug, Default)]
+pub struct Message {
+    data: Vec<u8>,
+}
+
+impl Message {
+    pub fn new() -> Message {
+        Message::default()
+    }
+
+    fn key(&mut self, field: u32, wire_type: u32) {
+        push_varint(&mut self.data, (field << 3 | wire_type) as u64);
+    }
+
+    pub fn uint(&mut self, field: u32, value: u64) -> &mut Message {
+        self.key(field, WIRE_VARINT);
+        push_varint(&mut self.data, value);
+        self
+    }
+
+    // int32 and int64, negative values take 10 bytes
+    pub fn int(&mut self, field: u32, value: i64) -> &mut Message {
+        self.uint(field, value as u64)
+    }
+
+    // sint32 and sint64, zigzag-encoded
+    pub fn sint(&mut self, field: u32, value: i64) -> &mut Message {
+        self.uint(field, ((value << 1) ^ (value >> 63)) as u64)
+    }
+
+    pub fn double(&mut self, field: u32, value: f64) -> &mut Message {
+        self.key(field, WIRE_FIXED64);
+        self.data.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub fn bytes(&mut self, field: u32, value: &[u8]) -> &mut Message {
+        self.key(field, WIRE_LEN);
+        push_varint(&mut self.data, value.len() as u64);
+        self.data.extend_from_slice(value);
+        self
+    }
+
+    pub fn string(&mut self, field: u32, value: &str) -> &mut Message {
+        self.bytes(field, value.as_bytes())
+    }
+
+    pub fn message(&mut self, field: u32, message: &Message) -> &mut Message {
+        self.bytes(field, &message.data)
+    }
+
+    // prefixed with its length, as messages are streamed one after the other
+    pub fn push_delimited(&self, data: &mut Vec<u8>) {
+        push_varint(data, self.data.len() as u64);
+        data.extend_from_slice(&self.data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoded(encode: impl FnOnce(&mut Message) -> &mut Message) -> Vec<u8> {
+        let mut message = Message::new();
+        encode(&mut message);
+        message.data
+    }
+
+    // the examples of the encoding guide of protocol buffers
+    #[test]
+    fn encodes_fields() {
+        assert_eq!(encoded(|m| m.uint(1, 150)), [0x08, 0x96, 0x01]);
+        assert_eq!(encoded(|m| m.uint(1, 0)), [0x08, 0x00]);
+        assert_eq!(encoded(|m| m.string(2, "testing")), b"\x12\x07testing");
+        assert_eq!(encoded(|m| m.double(1, 1.0)), [0x09, 0, 0, 0, 0, 0, 0, 0xf0, 0x3f]);
+        assert_eq!(
+            encoded(|m| m.int(1, -1)),
+            [0x08, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01],
+        );
+        // field numbers from 16 on take two bytes of key
+        assert_eq!(encoded(|m| m.uint(16, 1)), [0x80, 0x01, 0x01]);
+    }
+
+    #[test]
+    fn zigzag_encodes_signed_fields() {
+        let zigzag = [
+            (0, 0), (-1, 1), (1, 2), (-2, 3), (2147483647, 4294967294), (-2147483648, 4294967295),
+        ];
+        for (value, encoded_value) in zigzag.iter() {
+            assert_eq!(encoded(|m| m.sint(1, *value)), encoded(|m| m.uint(1, *encoded_value)));
+        }
+        assert_eq!(encoded(|m| m.sint(1, i64::MIN)), encoded(|m| m.uint(1, u64::MAX)));
+    }
+
+    #[test]
+    fn delimits_nested_messages() {
+        let mut inner = Message::new();
+        inner.uint(1, 150);
+        assert_eq!(encoded(|m| m.message(3, &inner)), [0x1a, 0x03, 0x08, 0x96, 0x01]);
+
+        let mut data = Vec::new();
+        inner.push_delimited(&mut data);
+        Message::new().push_delimited(&mut data);
+        let mut long = Message::new();
+        long.bytes(1, &[0; 200]);
+        long.push_delimited(&mut data);
+        assert_eq!(data[..5], [0x03, 0x08, 0x96, 0x01, 0x00]);
+        // 203 bytes, the key and the length of 200 included
+        assert_eq!(data[5..8], [0xcb, 0x01, 0x0a]);
+        assert_eq!(data.len(), 5 + 2 + 203);
+    }
+}
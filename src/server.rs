@@ -0,0 +1,373 @@
+use serde::Deserialize;
+use std::{
+    convert::Infallible,
+    error, fs,
+    io::{self, BufReader},
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, lookup_host},
+    sync::mpsc,
+    time::{sleep, timeout},
+};
+use tokio_rustls::{
+    TlsAcceptor,
+    rustls::{
+        ALL_CIPHERSUITES, AllowAnyAuthenticatedClient, NoClientAuth, ProtocolVersion, RootCertStore,
+        ServerConfig as TlsServerConfig, SupportedCipherSuite, internal::pemfile,
+    },
+};
+use tokio_stream::{StreamExt, wrappers::ReceiverStream};
+use tracing::{debug, error, info, warn};
+use warp::{
+    Filter, Future, Reply,
+    filters::BoxedFilter,
+    http::{Method, StatusCode},
+    hyper::{
+        Body, Request, Response, Server,
+        server::accept,
+        service::{Service, make_service_fn, service_fn},
+    },
+};
+
+use crate::{config::{ServerConfig, UNIX_SOCKET_PREFIX}, systemd};
+
+pub type Shutdown = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+pub type Routes = BoxedFilter<(Box<dyn Reply>,)>;
+
+// a client that doesn't finish the handshake in time is disconnected
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// accept errors such as running out of file descriptors usually go away after a while
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+// accepted connections waiting for the server to pick them up
+const ACCEPT_BACKLOG: usize = 64;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub enum TlsVersion {
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+// cipher suites are named as in the IANA registry, e.g. TLS13_AES_256_GCM_SHA384
+pub fn cipher_suite(name: &str) -> Option<&'static SupportedCipherSuite> {
+    ALL_CIPHERSUITES.iter().copied().find(|suite| format!("{:?}", suite.suite) == name)
+}
+
+pub fn is_cipher_suite_usable(suite: &SupportedCipherSuite, min_version: TlsVersion) -> bool {
+    match min_version {
+        TlsVersion::Tls12 => true,
+        TlsVersion::Tls13 => suite.usable_for_version(ProtocolVersion::TLSv1_3),
+    }
+}
+
+trait Io: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Io for T {}
+
+// a connection of any kind along with the address of its peer, if it has one
+struct Connection {
+    io: Box<dyn Io>,
+    remote_addr: Option<SocketAddr>,
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    async fn accept(&self) -> io::Result<(Box<dyn Io>, Option<SocketAddr>)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, remote_addr) = listener.accept().await?;
+                Ok((Box::new(stream), Some(remote_addr)))
+            },
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Box::new(stream), None))
+            },
+        }
+    }
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &str) -> Result<Listener, Box<dyn error::Error>> {
+    // a socket left behind by a previous run would make the bind fail
+    if fs::metadata(path).map(|m| m.file_type().is_socket()).unwrap_or(false) {
+        fs::remove_file(path)?;
+    }
+    Ok(Listener::Unix(UnixListener::bind(path)?))
+}
+
+#[cfg(not(unix))]
+fn bind_unix(_path: &str) -> Result<Listener, Box<dyn error::Error>> {
+    Err("Unix sockets are not supported on this platform".into())
+}
+
+#[cfg(unix)]
+fn activated_listener() -> Result<Option<Listener>, Box<dyn error::Error>> {
+    let listener = match systemd::listen_socket()? {
+        None => return Ok(None),
+        Some(systemd::ListenSocket::Tcp(listener)) => {
+            listener.set_nonblocking(true)?;
+            Listener::Tcp(TcpListener::from_std(listener)?)
+        },
+        Some(systemd::ListenSocket::Unix(listener)) => {
+            listener.set_nonblocking(true)?;
+            Listener::Unix(UnixListener::from_std(listener)?)
+        },
+    };
+    Ok(Some(listener))
+}
+
+#[cfg(not(unix))]
+fn activated_listener() -> Result<Option<Listener>, Box<dyn error::Error>> {
+    Ok(None)
+}
+
+// the returned flag tells whether the socket came from systemd
+pub async fn bind(config: &ServerConfig) -> Result<(Listener, bool), Box<dyn error::Error>> {
+    if let Some(listener) = activated_listener()? {
+        info!("using the socket passed by systemd");
+        return Ok((listener, true));
+    }
+
+    let host = &config.host;
+    if let Some(path) = host.strip_prefix(UNIX_SOCKET_PREFIX) {
+        return Ok((bind_unix(path)?, false));
+    }
+
+    let socket_addr = lookup_host(host).await?
+        .next().ok_or("hostname lookup failed")?;
+    Ok((Listener::Tcp(TcpListener::bind(socket_addr).await?), false))
+}
+
+// None unless TLS is configured
+pub fn tls_config(config: &ServerConfig) -> Result<Option<TlsServerConfig>, Box<dyn error::Error>> {
+    let (key_path, cert_path) = match (&config.tls_key_path, &config.tls_cert_path) {
+        (None, None) => return Ok(None),
+        (Some(key_path), Some(cert_path)) => (key_path, cert_path),
+        _ => return Err("TLS requires both the key and the certificate".into()),
+    };
+
+    let certs = pemfile::certs(&mut BufReader::new(fs::File::open(cert_path)?))
+        .map_err(|_| "failed to parse the TLS certificate")?;
+
+    // PKCS#8 keys are preferred over RSA ones like in warp
+    let key_data = fs::read(key_path)?;
+    let key = pemfile::pkcs8_private_keys(&mut key_data.as_slice())
+        .ok()
+        .and_then(|mut keys| if keys.is_empty() { None } else { Some(keys.remove(0)) })
+        .or_else(|| pemfile::rsa_private_keys(&mut key_data.as_slice())
+            .ok()
+            .and_then(|mut keys| if keys.is_empty() { None } else { Some(keys.remove(0)) }))
+        .ok_or("failed to parse the TLS key")?;
+
+    let client_auth = match &config.tls_client_ca_path {
+        None => NoClientAuth::new(),
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            match roots.add_pem_file(&mut BufReader::new(fs::File::open(path)?)) {
+                Ok((valid, _)) if valid > 0 => AllowAnyAuthenticatedClient::new(roots),
+                _ => return Err("failed to parse the TLS client CA".into()),
+            }
+        },
+    };
+
+    let mut tls_config = TlsServerConfig::new(client_auth);
+    tls_config.set_single_cert(certs, key)?;
+    tls_config.set_protocols(&["h2".into(), "http/1.1".into()]);
+    tls_config.versions = match config.tls_min_version {
+        TlsVersion::Tls12 => vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2],
+        TlsVersion::Tls13 => vec![ProtocolVersion::TLSv1_3],
+    };
+    // the names were validated along with the rest of the config
+    if !config.tls_ciphers.is_empty() {
+        tls_config.ciphersuites = config.tls_ciphers.iter()
+            .filter_map(|name| cipher_suite(name))
+            .collect();
+    }
+    Ok(Some(tls_config))
+}
+
+// the routes find the remote address in the request extensions, since warp can't know about it
+#[derive(Clone, Copy, Debug)]
+struct RemoteAddr(Option<SocketAddr>);
+
+pub fn remote_addr() -> impl Filter<Extract = (Option<SocketAddr>,), Error = Infallible> + Copy {
+    warp::ext::optional::<RemoteAddr>().map(|addr: Option<RemoteAddr>| addr.and_then(|addr| addr.0))
+}
+
+struct AccessLog {
+    remote_addr: Option<SocketAddr>,
+    method: Method,
+    path: String,
+    user_agent: Option<String>,
+    started_at: Instant,
+}
+
+impl AccessLog {
+    fn new(request: &Request<Body>, remote_addr: Option<SocketAddr>) -> AccessLog {
+        AccessLog {
+            remote_addr,
+            method: request.method().clone(),
+            path: request.uri().path().to_string(),
+            user_agent: request.headers().get("user-agent")
+                .and_then(|user_agent| user_agent.to_str().ok())
+                .map(String::from),
+            started_at: Instant::now(),
+        }
+    }
+
+    fn log(self, status: StatusCode) {
+        // there is no remote address on Unix sockets
+        let remote_addr = self.remote_addr
+            .map(|addr| addr.to_string())
+            .unwrap_or_else(|| "-".into());
+        info!(
+            target: "monerod_exporter::access",
+            remote_addr = %remote_addr,
+            method = %self.method,
+            path = self.path.as_str(),
+            status = status.as_u16(),
+            duration = ?self.started_at.elapsed(),
+            user_agent = self.user_agent.as_deref().unwrap_or("-"),
+            "request",
+        );
+    }
+}
+
+async fn accept(listener: Listener, tls: Option<TlsAcceptor>, connections: mpsc::Sender<Connection>) {
+    loop {
+        let (io, remote_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("failed to accept a connection: {}", e);
+                sleep(ACCEPT_ERROR_DELAY).await;
+                continue;
+            },
+        };
+
+        let tls = match &tls {
+            None => {
+                // the server is gone
+                if connections.send(Connection { io, remote_addr }).await.is_err() {
+                    return;
+                }
+                continue;
+            },
+            Some(tls) => tls.clone(),
+        };
+
+        // handshakes run on their own, so that a slow client doesn't hold up the others
+        let connections = connections.clone();
+        tokio::spawn(async move {
+            match timeout(TLS_HANDSHAKE_TIMEOUT, tls.accept(io)).await {
+                Ok(Ok(io)) => {
+                    let _ = connections.send(Connection { io: Box::new(io), remote_addr }).await;
+                },
+                Ok(Err(e)) => debug!("TLS handshake with {:?} failed: {}", remote_addr, e),
+                Err(_) => debug!("TLS handshake with {:?} timed out", remote_addr),
+            }
+        });
+    }
+}
+
+// on shutdown the server stops accepting connections and waits for the in-flight requests
+pub async fn serve(
+    listener: Listener,
+    routes: Routes,
+    tls: Option<TlsServerConfig>,
+    config: &ServerConfig,
+    shutdown: Shutdown,
+) {
+    let access_log = config.access_log;
+    let request_timeout = config.request_timeout;
+
+    let (sender, receiver) = mpsc::channel(ACCEPT_BACKLOG);
+    tokio::spawn(accept(listener, tls.map(|tls| TlsAcceptor::from(Arc::new(tls))), sender));
+    let incoming = ReceiverStream::new(receiver).map(Ok::<_, Infallible>);
+
+    let service = warp::service(routes);
+    let make_service = make_service_fn(move |connection: &Connection| {
+        let remote_addr = connection.remote_addr;
+        let service = service.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |mut request: Request<Body>| {
+                let log = if access_log { Some(AccessLog::new(&request, remote_addr)) } else { None };
+                request.extensions_mut().insert(RemoteAddr(remote_addr));
+                let response = service.clone().call(request);
+                async move {
+                    let response = match request_timeout {
+                        None => response.await?,
+                        Some(request_timeout) => match timeout(request_timeout, response).await {
+                            Ok(response) => response?,
+                            Err(_) => {
+                                let mut response = Response::new(Body::from("request timed out"));
+                                *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                                response
+                            },
+                        },
+                    };
+                    if let Some(log) = log {
+                        log.log(response.status());
+                    }
+                    Ok::<_, Infallible>(response)
+                }
+            }))
+        }
+    });
+
+    let mut server = Server::builder(accept::from_stream(incoming))
+        .http1_max_buf_size(config.max_header_size);
+    // a client has as long to send the request headers as the exporter has to respond
+    if let Some(request_timeout) = request_timeout {
+        server = server.http1_header_read_timeout(request_timeout);
+    }
+    let server = server
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown);
+    if let Err(e) = server.await {
+        error!("server error: {}", e);
+    }
+}
@@ -0,0 +1,196 @@
+use reqwest::Url;
+use std::{
+    error,
+    fmt,
+    io,
+    net::IpAddr,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, lookup_host},
+};
+
+use crate::{config::RpcLoginConfig, hex};
+
+pub const SOCKS_PORT: u16 = 1080;
+
+const VERSION: u8 = 5;
+const LOGIN_VERSION: u8 = 1;
+
+const METHOD_NONE: u8 = 0;
+const METHOD_LOGIN: u8 = 2;
+
+const COMMAND_CONNECT: u8 = 1;
+
+const ADDRESS_IPV4: u8 = 1;
+const ADDRESS_DOMAIN: u8 = 3;
+const ADDRESS_IPV6: u8 = 4;
+
+#[derive(Debug)]
+pub enum SocksError {
+    Io(io::Error),
+    InvalidVersion,
+    NoAcceptableMethod,
+    LoginRejected,
+    Reply(u8),
+    UnresolvedHost,
+    InvalidResponse,
+}
+
+impl error::Error for SocksError {}
+
+impl fmt::Display for SocksError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SocksError::Io(e) => write!(f, "I/O error: {}", e),
+            SocksError::InvalidVersion => f.write_str("proxy doesn't speak SOCKS5"),
+            SocksError::NoAcceptableMethod => {
+                f.write_str("proxy requires another authentication method")
+            },
+            SocksError::LoginRejected => f.write_str("proxy rejected the login"),
+            // see RFC 1928
+            SocksError::Reply(reply) => f.write_str(match reply {
+                1 => "general SOCKS server failure",
+                2 => "connection not allowed by ruleset",
+                3 => "network unreachable",
+                4 => "host unreachable",
+                5 => "connection refused",
+                6 => "TTL expired",
+                7 => "command not supported",
+                8 => "address type not supported",
+                _ => "unknown SOCKS error",
+            }),
+            SocksError::UnresolvedHost => f.write_str("failed to resolve the target"),
+            SocksError::InvalidResponse => f.write_str("invalid response"),
+        }
+    }
+}
+
+impl From<io::Error> for SocksError {
+    fn from(e: io::Error) -> Self {
+        SocksError::Io(e)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SocksProxy {
+    host: String,
+    port: u16,
+    // Tor isolates the streams of different logins from each other
+    login: Option<RpcLoginConfig>,
+    // socks5h leaves resolving the target to the proxy, which onion addresses need
+    remote_dns: bool,
+}
+
+impl SocksProxy {
+    // None unless the URL is of a SOCKS5 proxy, e.g. socks5h://127.0.0.1:9050
+    pub fn from_url(url: &Url) -> Option<SocksProxy> {
+        let remote_dns = match url.scheme() {
+            "socks5" => false,
+            "socks5h" => true,
+            _ => return None,
+        };
+        // the lengths are sent as single bytes
+        let login = match (url.username(), url.password()) {
+            ("", None) => None,
+            (username, Some(password)) if username.len() <= 255 && password.len() <= 255 => {
+                Some(RpcLoginConfig { username: username.to_string(), password: password.to_string() })
+            },
+            _ => return None,
+        };
+
+        Some(SocksProxy {
+            host: url.host_str()?.trim_start_matches('[').trim_end_matches(']').to_string(),
+            port: url.port().unwrap_or(SOCKS_PORT),
+            login,
+            remote_dns,
+        })
+    }
+
+    // the proxy with a random login unless it has one, so that Tor connects over a circuit of its own
+    // instead of one that's already open to the target
+    pub fn isolated(&self) -> SocksProxy {
+        let mut proxy = self.clone();
+        let mut username = [0; 16];
+        if proxy.login.is_none() && getrandom::getrandom(&mut username).is_ok() {
+            let username = hex::encode(&username);
+            proxy.login = Some(RpcLoginConfig { password: username.clone(), username });
+        }
+        proxy
+    }
+
+    // a connection to the target through the proxy, see RFC 1928 and RFC 1929
+    pub async fn connect(&self, host: &str, port: u16) -> Result<TcpStream, SocksError> {
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+
+        let method = if self.login.is_some() { METHOD_LOGIN } else { METHOD_NONE };
+        stream.write_all(&[VERSION, 1, method]).await?;
+        let mut reply = [0; 2];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != VERSION {
+            return Err(SocksError::InvalidVersion);
+        }
+        if reply[1] != method {
+            return Err(SocksError::NoAcceptableMethod);
+        }
+
+        if let Some(login) = &self.login {
+            let mut request = vec![LOGIN_VERSION, login.username.len() as u8];
+            request.extend_from_slice(login.username.as_bytes());
+            request.push(login.password.len() as u8);
+            request.extend_from_slice(login.password.as_bytes());
+            stream.write_all(&request).await?;
+            stream.read_exact(&mut reply).await?;
+            if reply[1] != 0 {
+                return Err(SocksError::LoginRejected);
+            }
+        }
+
+        let mut request = vec![VERSION, COMMAND_CONNECT, 0];
+        let ip = match host.parse::<IpAddr>() {
+            Ok(ip) => Some(ip),
+            Err(_) if self.remote_dns => None,
+            Err(_) => {
+                let address = lookup_host((host, port)).await?.next().ok_or(SocksError::UnresolvedHost)?;
+                Some(address.ip())
+            },
+        };
+        match ip {
+            Some(IpAddr::V4(ip)) => {
+                request.push(ADDRESS_IPV4);
+                request.extend_from_slice(&ip.octets());
+            },
+            Some(IpAddr::V6(ip)) => {
+                request.push(ADDRESS_IPV6);
+                request.extend_from_slice(&ip.octets());
+            },
+            None if host.len() > 255 => return Err(SocksError::UnresolvedHost),
+            None => {
+                request.extend_from_slice(&[ADDRESS_DOMAIN, host.len() as u8]);
+                request.extend_from_slice(host.as_bytes());
+            },
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request).await?;
+
+        let mut reply = [0; 4];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != VERSION {
+            return Err(SocksError::InvalidVersion);
+        }
+        if reply[1] != 0 {
+            return Err(SocksError::Reply(reply[1]));
+        }
+        // the address the proxy bound to is of no use
+        let address_length = match reply[3] {
+            ADDRESS_IPV4 => 4,
+            ADDRESS_IPV6 => 16,
+            ADDRESS_DOMAIN => stream.read_u8().await? as usize,
+            _ => return Err(SocksError::InvalidResponse),
+        };
+        let mut address = vec![0; address_length + 2];
+        stream.read_exact(&mut address).await?;
+
+        Ok(stream)
+    }
+}
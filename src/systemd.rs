@@ -0,0 +1,156 @@
+use serde::Deserialize;
+use std::{env, future, sync::Arc, time::Duration};
+use tokio::time::interval;
+use tracing::warn;
+
+use crate::metrics::Publisher;
+
+// when READY=1 is sent to systemd
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReadyOn {
+    // once the server is listening
+    Bind,
+    // once metrics were exported for the first time
+    Export,
+}
+
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+#[cfg(unix)]
+pub struct Notifier {
+    socket: std::os::unix::net::UnixDatagram,
+    path: std::path::PathBuf,
+}
+
+#[cfg(unix)]
+impl Notifier {
+    // None unless started by systemd with Type=notify
+    pub fn from_env() -> Option<Notifier> {
+        let path = env::var_os("NOTIFY_SOCKET")?;
+        if path.to_str().map(|p| p.starts_with('@')).unwrap_or(false) {
+            warn!("abstract NOTIFY_SOCKET addresses are not supported");
+            return None;
+        }
+
+        match std::os::unix::net::UnixDatagram::unbound() {
+            Ok(socket) => Some(Notifier { socket, path: path.into() }),
+            Err(e) => {
+                warn!("failed to create systemd notification socket: {}", e);
+                None
+            },
+        }
+    }
+
+    pub fn notify(&self, state: &str) {
+        if let Err(e) = self.socket.send_to(state.as_bytes(), &self.path) {
+            warn!("failed to notify systemd: {}", e);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub struct Notifier;
+
+#[cfg(not(unix))]
+impl Notifier {
+    pub fn from_env() -> Option<Notifier> {
+        None
+    }
+
+    pub fn notify(&self, _state: &str) {}
+}
+
+// the first file descriptor passed by systemd, see sd_listen_fds(3)
+#[cfg(unix)]
+const LISTEN_FDS_START: std::os::unix::io::RawFd = 3;
+
+#[cfg(unix)]
+pub enum ListenSocket {
+    Tcp(std::net::TcpListener),
+    Unix(std::os::unix::net::UnixListener),
+}
+
+// the socket passed by systemd socket activation, if any
+#[cfg(unix)]
+pub fn listen_socket() -> std::io::Result<Option<ListenSocket>> {
+    use std::{io, mem, os::unix::io::FromRawFd};
+
+    let pid = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok());
+    let fds = env::var("LISTEN_FDS").ok().and_then(|fds| fds.parse::<u32>().ok());
+    // must not be inherited by child processes
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    let fds = match (pid, fds) {
+        (Some(pid), Some(fds)) if pid == std::process::id() && fds > 0 => fds,
+        _ => return Ok(None),
+    };
+    if fds > 1 {
+        warn!("systemd passed {} sockets, only the first one is used", fds);
+    }
+
+    let fd = LISTEN_FDS_START;
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of_val(&addr) as libc::socklen_t;
+    if unsafe { libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let socket = match addr.ss_family as libc::c_int {
+        libc::AF_INET | libc::AF_INET6 => {
+            ListenSocket::Tcp(unsafe { std::net::TcpListener::from_raw_fd(fd) })
+        },
+        libc::AF_UNIX => {
+            ListenSocket::Unix(unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) })
+        },
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "unsupported socket family")),
+    };
+    Ok(Some(socket))
+}
+
+// half of WatchdogSec, as recommended by sd_watchdog_enabled(3)
+fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+
+    env::var("WATCHDOG_USEC").ok()
+        .and_then(|usec| usec.parse::<u64>().ok())
+        .filter(|usec| *usec > 0)
+        .map(|usec| Duration::from_micros(usec / 2))
+}
+
+// reports readiness and keeps pinging the watchdog for as long as the refresh loop makes progress
+pub async fn run(publisher: Arc<Publisher>, ready_on: ReadyOn) {
+    let notifier = match Notifier::from_env() {
+        None => return future::pending().await,
+        Some(notifier) => notifier,
+    };
+
+    if ready_on == ReadyOn::Export {
+        let mut poll = interval(READY_POLL_INTERVAL);
+        while !publisher.is_ready() {
+            poll.tick().await;
+        }
+    }
+    notifier.notify("READY=1");
+
+    let watchdog_interval = match watchdog_interval() {
+        None => return future::pending().await,
+        Some(watchdog_interval) => watchdog_interval,
+    };
+
+    let mut watchdog = interval(watchdog_interval);
+    loop {
+        watchdog.tick().await;
+        if publisher.is_refreshing() {
+            notifier.notify("WATCHDOG=1");
+        } else {
+            warn!("refresh loop is stuck - skipped systemd watchdog ping");
+        }
+    }
+}
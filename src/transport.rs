@@ -0,0 +1,222 @@
+use bytes::Bytes;
+use reqwest::{StatusCode, header::HeaderMap};
+use std::{error, time::Duration};
+#[cfg(unix)]
+use std::{
+    future::Future,
+    io,
+    path::PathBuf,
+    pin::Pin,
+    task::{Context, Poll},
+};
+#[cfg(unix)]
+use hyper::{
+    Body, Uri,
+    body::HttpBody,
+    client::connect::{Connected, Connection},
+    header::{CONTENT_LENGTH, HOST},
+    service::Service,
+};
+#[cfg(unix)]
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::UnixStream,
+    time::{Instant, timeout_at},
+};
+
+use crate::client::ClientError;
+
+// the host of the requests sent over a Unix socket, which is what the RPC is proxied from
+#[cfg(unix)]
+const UNIX_SOCKET_HOST: &str = "localhost";
+
+// how the requests get to the daemon
+#[derive(Clone, Debug)]
+pub enum Transport {
+    Http(reqwest::Client),
+    // the RPC proxied to a Unix socket, which reqwest can't connect to
+    #[cfg(unix)]
+    Unix {
+        client: hyper::Client<UnixConnector>,
+        timeout: Duration,
+    },
+}
+
+impl From<reqwest::Client> for Transport {
+    fn from(client: reqwest::Client) -> Self {
+        Transport::Http(client)
+    }
+}
+
+impl Transport {
+    #[cfg(unix)]
+    pub fn unix(path: &str, timeout: Duration) -> Result<Transport, Box<dyn error::Error>> {
+        let connector = UnixConnector { path: PathBuf::from(path) };
+        Ok(Transport::Unix {
+            client: hyper::Client::builder().build(connector),
+            timeout,
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn unix(_path: &str, _timeout: Duration) -> Result<Transport, Box<dyn error::Error>> {
+        Err("Unix sockets are not supported on this platform".into())
+    }
+
+    // the URL a path is requested at, base URLs of Unix sockets only name the socket
+    pub fn url(&self, base_url: &str, path: &str) -> String {
+        match self {
+            Transport::Http(_) => format!("{}{}", base_url, path),
+            #[cfg(unix)]
+            Transport::Unix { .. } => format!("http://{}{}", UNIX_SOCKET_HOST, path),
+        }
+    }
+
+    pub async fn post(
+        &self,
+        url: &str,
+        body: Vec<u8>,
+        headers: HeaderMap,
+        timeout: Option<Duration>,
+    ) -> Result<Response, ClientError> {
+        match self {
+            Transport::Http(client) => {
+                let mut request = client.post(url).headers(headers).body(body);
+                if let Some(timeout) = timeout {
+                    request = request.timeout(timeout);
+                }
+                request.send().await.map(Response::Http).map_err(ClientError::HttpClient)
+            },
+            #[cfg(unix)]
+            Transport::Unix { client, timeout: default_timeout } => {
+                // like reqwest's, the timeout covers reading the body
+                let deadline = Instant::now() + timeout.unwrap_or(*default_timeout);
+                let mut request = hyper::Request::post(url)
+                    .header(HOST, UNIX_SOCKET_HOST)
+                    .body(Body::from(body))
+                    .map_err(|_| ClientError::InvalidRequest)?;
+                request.headers_mut().extend(headers);
+
+                match timeout_at(deadline, client.request(request)).await {
+                    Ok(Ok(response)) => Ok(Response::Unix { response, deadline }),
+                    Ok(Err(e)) => Err(ClientError::UnixSocket(e)),
+                    Err(_) => Err(ClientError::Timeout),
+                }
+            },
+        }
+    }
+}
+
+// a response of either transport
+pub enum Response {
+    Http(reqwest::Response),
+    #[cfg(unix)]
+    Unix {
+        response: hyper::Response<Body>,
+        deadline: Instant,
+    },
+}
+
+impl Response {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Response::Http(response) => response.status(),
+            #[cfg(unix)]
+            Response::Unix { response, .. } => response.status(),
+        }
+    }
+
+    pub fn headers(&self) -> &HeaderMap {
+        match self {
+            Response::Http(response) => response.headers(),
+            #[cfg(unix)]
+            Response::Unix { response, .. } => response.headers(),
+        }
+    }
+
+    pub fn content_length(&self) -> Option<u64> {
+        match self {
+            Response::Http(response) => response.content_length(),
+            #[cfg(unix)]
+            Response::Unix { response, .. } => response.headers().get(CONTENT_LENGTH)
+                .and_then(|length| length.to_str().ok()?.parse().ok()),
+        }
+    }
+
+    // the next chunk of the body, none once it has been read
+    pub async fn chunk(&mut self) -> Result<Option<Bytes>, ClientError> {
+        match self {
+            Response::Http(response) => response.chunk().await.map_err(ClientError::HttpClient),
+            #[cfg(unix)]
+            Response::Unix { response, deadline } => {
+                match timeout_at(*deadline, response.body_mut().data()).await {
+                    Ok(chunk) => chunk.transpose().map_err(ClientError::UnixSocket),
+                    Err(_) => Err(ClientError::Timeout),
+                }
+            },
+        }
+    }
+}
+
+#[cfg(unix)]
+#[derive(Clone, Debug)]
+pub struct UnixConnector {
+    path: PathBuf,
+}
+
+// every connection is to the socket, whatever the URL
+#[cfg(unix)]
+impl Service<Uri> for UnixConnector {
+    type Response = UnixConnection;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<UnixConnection>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _uri: Uri) -> Self::Future {
+        let path = self.path.clone();
+        Box::pin(async move { UnixStream::connect(path).await.map(UnixConnection) })
+    }
+}
+
+#[cfg(unix)]
+pub struct UnixConnection(UnixStream);
+
+#[cfg(unix)]
+impl Connection for UnixConnection {
+    fn connected(&self) -> Connected {
+        Connected::new()
+    }
+}
+
+#[cfg(unix)]
+impl AsyncRead for UnixConnection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+#[cfg(unix)]
+impl AsyncWrite for UnixConnection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,333 @@
+use reqwest::Url;
+use serde::Deserialize;
+use std::{convert::TryInto, error, fmt, io, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::{sleep, timeout},
+};
+use tracing::{info, warn};
+
+pub const ZMQ_PORT: u16 = 18083;
+
+// ZMTP 3.0, whose subscriptions are messages rather than the commands of 3.1
+const SIGNATURE_START: u8 = 0xff;
+const SIGNATURE_END: u8 = 0x7f;
+const VERSION: [u8; 2] = [3, 0];
+const MECHANISM_NULL: &[u8] = b"NULL";
+const GREETING_LEN: usize = 64;
+
+const FLAG_MORE: u8 = 0x01;
+const FLAG_LONG: u8 = 0x02;
+const FLAG_COMMAND: u8 = 0x04;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// a dropped connection can't be told apart from a quiet daemon, so it's made anew
+// when nothing was published for this long
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+// far larger than the minimal JSON events of monerod
+const MAX_MESSAGE_SIZE: u64 = 16 * 1024 * 1024;
+
+pub const TOPIC_CHAIN_MAIN: &str = "json-minimal-chain_main";
+pub const TOPIC_TXPOOL_ADD: &str = "json-minimal-txpool_add";
+
+#[derive(Debug)]
+pub enum ZmqError {
+    Io(io::Error),
+    Timeout,
+    InvalidUrl,
+    InvalidGreeting,
+    UnsupportedMechanism,
+    // the reason the publisher sent in its ERROR command
+    Rejected(String),
+    InvalidSocketType,
+    InvalidFrame,
+    MessageTooLarge,
+}
+
+impl error::Error for ZmqError {}
+
+impl fmt::Display for ZmqError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ZmqError::Io(e) => write!(f, "I/O error: {}", e),
+            ZmqError::Timeout => f.write_str("connection timed out"),
+            ZmqError::InvalidUrl => f.write_str("invalid URL"),
+            ZmqError::InvalidGreeting => f.write_str("invalid greeting, the peer doesn't speak ZMTP 3"),
+            ZmqError::UnsupportedMechanism => f.write_str("security mechanism other than NULL"),
+            ZmqError::Rejected(reason) => write!(f, "connection rejected: {}", reason),
+            ZmqError::InvalidSocketType => f.write_str("peer isn't a publisher"),
+            ZmqError::InvalidFrame => f.write_str("invalid frame"),
+            ZmqError::MessageTooLarge => f.write_str("message too large"),
+        }
+    }
+}
+
+impl From<io::Error> for ZmqError {
+    fn from(e: io::Error) -> Self {
+        ZmqError::Io(e)
+    }
+}
+
+// tcp://host:port, as given to monerod with --zmq-pub
+pub fn parse_url(url: &str) -> Option<(String, u16)> {
+    let url = Url::parse(url).ok()?;
+    if url.scheme() != "tcp" || !matches!(url.path(), "" | "/") {
+        return None;
+    }
+    let host = url.host_str()?.trim_start_matches('[').trim_end_matches(']').to_string();
+    Some((host, url.port().unwrap_or(ZMQ_PORT)))
+}
+
+struct Frame {
+    command: bool,
+    more: bool,
+    body: Vec<u8>,
+}
+
+// a SUB socket connected to a single publisher
+struct Subscriber {
+    stream: TcpStream,
+}
+
+impl Subscriber {
+    async fn connect(url: &str, topics: &[&str]) -> Result<Subscriber, ZmqError> {
+        let (host, port) = parse_url(url).ok_or(ZmqError::InvalidUrl)?;
+        let stream = timeout(CONNECT_TIMEOUT, TcpStream::connect((host.as_str(), port))).await
+            .map_err(|_| ZmqError::Timeout)??;
+        let mut subscriber = Subscriber { stream };
+        timeout(CONNECT_TIMEOUT, subscriber.handshake()).await.map_err(|_| ZmqError::Timeout)??;
+        for topic in topics {
+            // a message starting with 1 subscribes to the messages starting with the rest
+            let mut subscription = vec![1];
+            subscription.extend_from_slice(topic.as_bytes());
+            subscriber.send(false, &subscription).await?;
+        }
+        Ok(subscriber)
+    }
+
+    async fn handshake(&mut self) -> Result<(), ZmqError> {
+        let mut greeting = [0; GREETING_LEN];
+        greeting[0] = SIGNATURE_START;
+        greeting[9] = SIGNATURE_END;
+        greeting[10..12].copy_from_slice(&VERSION);
+        greeting[12..12 + MECHANISM_NULL.len()].copy_from_slice(MECHANISM_NULL);
+        self.stream.write_all(&greeting).await?;
+
+        let mut greeting = [0; GREETING_LEN];
+        self.stream.read_exact(&mut greeting).await?;
+        if greeting[0] != SIGNATURE_START || greeting[9] != SIGNATURE_END || greeting[10] < VERSION[0] {
+            return Err(ZmqError::InvalidGreeting);
+        }
+        let (mechanism, padding) = greeting[12..32].split_at(MECHANISM_NULL.len());
+        if mechanism != MECHANISM_NULL || padding.iter().any(|b| *b != 0) {
+            return Err(ZmqError::UnsupportedMechanism);
+        }
+
+        let mut ready = Vec::new();
+        push_short_string(&mut ready, "READY");
+        push_property(&mut ready, "Socket-Type", b"SUB");
+        self.send(true, &ready).await?;
+
+        let frame = self.recv_frame().await?;
+        if !frame.command {
+            return Err(ZmqError::InvalidFrame);
+        }
+        let (name, data) = split_command(&frame.body).ok_or(ZmqError::InvalidFrame)?;
+        match name {
+            "READY" => {},
+            "ERROR" => {
+                let reason = data.split_first()
+                    .and_then(|(len, reason)| reason.get(..*len as usize))
+                    .map(|reason| String::from_utf8_lossy(reason).into_owned())
+                    .unwrap_or_default();
+                return Err(ZmqError::Rejected(reason));
+            },
+            _ => return Err(ZmqError::InvalidFrame),
+        }
+        match property(data, "Socket-Type") {
+            Some(b"PUB") | Some(b"XPUB") => Ok(()),
+            _ => Err(ZmqError::InvalidSocketType),
+        }
+    }
+
+    async fn send(&mut self, command: bool, body: &[u8]) -> Result<(), ZmqError> {
+        let mut frame = Vec::with_capacity(body.len() + 9);
+        let flags = if command { FLAG_COMMAND } else { 0 };
+        match body.len() {
+            len if len <= u8::MAX as usize => frame.extend_from_slice(&[flags, len as u8]),
+            len => {
+                frame.push(flags | FLAG_LONG);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            },
+        }
+        frame.extend_from_slice(body);
+        self.stream.write_all(&frame).await?;
+        Ok(())
+    }
+
+    async fn recv_frame(&mut self) -> Result<Frame, ZmqError> {
+        let flags = self.stream.read_u8().await?;
+        let len = match flags & FLAG_LONG {
+            0 => self.stream.read_u8().await? as u64,
+            _ => self.stream.read_u64().await?,
+        };
+        if len > MAX_MESSAGE_SIZE {
+            return Err(ZmqError::MessageTooLarge);
+        }
+        let mut body = vec![0; len as usize];
+        self.stream.read_exact(&mut body).await?;
+        Ok(Frame {
+            command: flags & FLAG_COMMAND != 0,
+            more: flags & FLAG_MORE != 0,
+            body,
+        })
+    }
+
+    // the frames of the next message, joined; the commands in between are skipped
+    async fn recv(&mut self) -> Result<Vec<u8>, ZmqError> {
+        let mut message = Vec::new();
+        loop {
+            let frame = self.recv_frame().await?;
+            if frame.command {
+                continue;
+            }
+            if message.len() as u64 + frame.body.len() as u64 > MAX_MESSAGE_SIZE {
+                return Err(ZmqError::MessageTooLarge);
+            }
+            message.extend_from_slice(&frame.body);
+            if !frame.more {
+                return Ok(message);
+            }
+        }
+    }
+}
+
+fn push_short_string(data: &mut Vec<u8>, string: &str) {
+    data.push(string.len() as u8);
+    data.extend_from_slice(string.as_bytes());
+}
+
+fn push_property(data: &mut Vec<u8>, name: &str, value: &[u8]) {
+    push_short_string(data, name);
+    data.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    data.extend_from_slice(value);
+}
+
+fn split_command(body: &[u8]) -> Option<(&str, &[u8])> {
+    let (len, rest) = body.split_first()?;
+    let name = rest.get(..*len as usize)?;
+    Some((std::str::from_utf8(name).ok()?, &rest[*len as usize..]))
+}
+
+// the value of a property of the metadata of a command, names are case-insensitive
+fn property<'a>(mut metadata: &'a [u8], name: &str) -> Option<&'a [u8]> {
+    while let Some((len, rest)) = metadata.split_first() {
+        let property_name = rest.get(..*len as usize)?;
+        let rest = &rest[*len as usize..];
+        let value_len = u32::from_be_bytes(rest.get(..4)?.try_into().ok()?) as usize;
+        let value = rest.get(4..4 + value_len)?;
+        if property_name.eq_ignore_ascii_case(name.as_bytes()) {
+            return Some(value);
+        }
+        metadata = &rest[4 + value_len..];
+    }
+    None
+}
+
+// blocks added to the main chain, several after a reorganization
+#[derive(Clone, Debug, Deserialize)]
+pub struct ChainMain {
+    pub first_height: u64,
+    pub first_prev_id: String,
+    pub ids: Vec<String>,
+}
+
+impl ChainMain {
+    // the length of the chain, like the height of get_info
+    pub fn height(&self) -> u64 {
+        self.first_height + self.ids.len() as u64
+    }
+}
+
+// a transaction added to the pool, relayed or submitted to the node
+#[derive(Clone, Debug, Deserialize)]
+pub struct TxpoolAdd {
+    pub id: String,
+    pub blob_size: u64,
+    pub weight: u64,
+    pub fee: u64,
+}
+
+#[derive(Clone, Debug)]
+pub enum Event {
+    Connected,
+    // followed by a reconnect, the events published meanwhile are missed
+    Disconnected,
+    ChainMain(ChainMain),
+    TxpoolAdd(Vec<TxpoolAdd>),
+}
+
+// monerod publishes every event as a single frame of its topic, a colon and the JSON
+fn parse_event(message: &[u8]) -> Option<Event> {
+    let separator = message.iter().position(|b| *b == b':')?;
+    let (topic, data) = (&message[..separator], &message[separator + 1..]);
+    match topic {
+        topic if topic == TOPIC_CHAIN_MAIN.as_bytes() => {
+            serde_json::from_slice(data).ok().map(Event::ChainMain)
+        },
+        topic if topic == TOPIC_TXPOOL_ADD.as_bytes() => {
+            serde_json::from_slice(data).ok().map(Event::TxpoolAdd)
+        },
+        _ => None,
+    }
+}
+
+// subscribes to the topics for as long as the handler returns true, reconnecting on failures
+pub async fn run<H: FnMut(Event) -> bool>(url: String, topics: &[&str], mut handle: H) {
+    let mut failing = false;
+    loop {
+        let result = match Subscriber::connect(&url, topics).await {
+            Ok(mut subscriber) => {
+                failing = false;
+                info!("subscribed to {}", url);
+                if !handle(Event::Connected) {
+                    return;
+                }
+                loop {
+                    let message = match timeout(IDLE_TIMEOUT, subscriber.recv()).await {
+                        Ok(Ok(message)) => message,
+                        Ok(Err(e)) => break Err(e),
+                        Err(_) => break Ok(()),
+                    };
+                    match parse_event(&message) {
+                        Some(event) => {
+                            if !handle(event) {
+                                return;
+                            }
+                        },
+                        None => warn!("unexpected message published by {}", url),
+                    }
+                }
+            },
+            Err(e) => Err(e),
+        };
+
+        if !handle(Event::Disconnected) {
+            return;
+        }
+        match result {
+            // only the first of consecutive failures is logged
+            Err(e) if !failing => {
+                warn!("subscription to {} failed: {}", url, e);
+                failing = true;
+            },
+            _ => {},
+        }
+        sleep(RECONNECT_DELAY).await;
+    }
+}